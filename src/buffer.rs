@@ -9,8 +9,8 @@ pub struct Buffer<'a> {
     offset: usize,
 }
 
-impl Buffer<'_> {
-    pub fn new_from_slice(d: &[u8]) -> Buffer {
+impl<'a> Buffer<'a> {
+    pub fn new_from_slice(d: &'a [u8]) -> Buffer<'a> {
         Buffer {
             data: d,
             offset: 0,
@@ -40,20 +40,28 @@ impl Buffer<'_> {
     }
 
     pub fn left(&mut self) -> usize {
-        if self.offset < self.len {
-            self.len - self.offset
-        } else {
-            0
-        }
+        self.len.saturating_sub(self.offset)
     }
 
-    pub fn get_bytes(&mut self, bytes: usize) -> Result<Vec<u8>> {
+    // Returns the next 'bytes' bytes, borrowed from the underlying data
+    pub fn get_slice(&mut self, bytes: usize) -> Result<&'a [u8]> {
         self.check_size(bytes)?;
 
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.data[self.offset..self.offset + bytes]);
+        let data: &'a [u8] = self.data;
+        let s = &data[self.offset..self.offset + bytes];
         self.offset += bytes;
-        Ok(v)
+        Ok(s)
+    }
+
+    pub fn get_bytes(&mut self, bytes: usize) -> Result<Vec<u8>> {
+        Ok(self.get_slice(bytes)?.to_vec())
+    }
+
+    pub fn get_64(&mut self) -> Result<u64> {
+        let hi = self.get_32()? as u64;
+        let lo = self.get_32()? as u64;
+
+        Ok(hi << 32 | lo)
     }
 
     pub fn get_32(&mut self) -> Result<u32> {
@@ -65,7 +73,7 @@ impl Buffer<'_> {
     }
 
     pub fn get_24(&mut self) -> Result<u32> {
-        self.check_size(4)?;
+        self.check_size(3)?;
         let b = &self.data[self.offset..];
         self.offset += 3;
 
@@ -89,16 +97,138 @@ impl Buffer<'_> {
     }
 
     pub fn get_ipv6addr(&mut self) -> Result<Ipv6Addr> {
-        let x = self.get_bytes(16)?;
-        let mut w = [0u16; 8];
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(self.get_slice(16)?);
+
+        Ok(Ipv6Addr::from(octets))
+    }
+}
+
+/// A destination for encoded data.  Fields are appended in order, but a field
+/// which has already been written may be overwritten.  This lets an encoder
+/// reserve space for a length, write the data it describes, and then backfill
+/// the length once it is known.
+pub trait Sink {
+    fn offset(&self) -> usize;
+    fn put_bytes(&mut self, b: &[u8]) -> Result<()>;
+    fn set_16_at(&mut self, offset: usize, v: u16) -> Result<()>;
+
+    fn put_8(&mut self, v: u8) -> Result<()> {
+        self.put_bytes(&[v])
+    }
+
+    fn put_16(&mut self, v: u16) -> Result<()> {
+        self.put_bytes(&v.to_be_bytes())
+    }
+
+    fn put_24(&mut self, v: u32) -> Result<()> {
+        self.put_bytes(&v.to_be_bytes()[1..])
+    }
 
-        for i in 0..8 {
-            w[i] = (x[2 * i] as u16) << 8 | (x[2 * i + 1] as u16);
+    fn put_32(&mut self, v: u32) -> Result<()> {
+        self.put_bytes(&v.to_be_bytes())
+    }
+
+    fn put_64(&mut self, v: u64) -> Result<()> {
+        self.put_bytes(&v.to_be_bytes())
+    }
+
+    fn put_ipv6addr(&mut self, addr: &Ipv6Addr) -> Result<()> {
+        self.put_bytes(&addr.octets())
+    }
+
+    // Reserves space for a 16-bit length, returning the offset at which it
+    // should be backfilled
+    fn reserve_16(&mut self) -> Result<usize> {
+        let offset = self.offset();
+        self.put_16(0)?;
+        Ok(offset)
+    }
+
+    // Backfills a 16-bit length reserved with reserve_16(), covering
+    // everything written since.
+    fn backfill_16(&mut self, offset: usize) -> Result<()> {
+        let len = self.offset() - offset - 2;
+        if len > u16::MAX as usize {
+            return Err(Error::BadOption("option too large".to_string()));
         }
+        self.set_16_at(offset, len as u16)
+    }
+}
 
-        Ok(Ipv6Addr::new(
-            w[0], w[1], w[2], w[3], w[4], w[5], w[6], w[7],
-        ))
+impl Sink for Vec<u8> {
+    fn offset(&self) -> usize {
+        self.len()
+    }
+
+    fn put_bytes(&mut self, b: &[u8]) -> Result<()> {
+        self.extend_from_slice(b);
+        Ok(())
+    }
+
+    fn set_16_at(&mut self, offset: usize, v: u16) -> Result<()> {
+        if offset + 2 > self.len() {
+            return Err(Error::TooShort);
+        }
+        self[offset..offset + 2].copy_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// A Sink which writes into a fixed-size, caller-supplied buffer.  Running
+/// out of space results in Error::TooShort.
+pub struct BufferMut<'a> {
+    data: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> BufferMut<'a> {
+    pub fn new_from_slice(d: &'a mut [u8]) -> BufferMut<'a> {
+        BufferMut { data: d, offset: 0 }
+    }
+}
+
+impl Sink for BufferMut<'_> {
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn put_bytes(&mut self, b: &[u8]) -> Result<()> {
+        if self.data.len() - self.offset < b.len() {
+            return Err(Error::TooShort);
+        }
+        self.data[self.offset..self.offset + b.len()].copy_from_slice(b);
+        self.offset += b.len();
+        Ok(())
+    }
+
+    fn set_16_at(&mut self, offset: usize, v: u16) -> Result<()> {
+        if offset + 2 > self.offset {
+            return Err(Error::TooShort);
+        }
+        self.data[offset..offset + 2].copy_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// A Sink which discards the data, and just counts how much there is
+#[derive(Default)]
+pub struct Counter {
+    len: usize,
+}
+
+impl Sink for Counter {
+    fn offset(&self) -> usize {
+        self.len
+    }
+
+    fn put_bytes(&mut self, b: &[u8]) -> Result<()> {
+        self.len += b.len();
+        Ok(())
+    }
+
+    fn set_16_at(&mut self, _offset: usize, _v: u16) -> Result<()> {
+        Ok(())
     }
 }
 
@@ -130,6 +260,30 @@ fn test_word() {
     assert_eq!(tbuf.get_32().unwrap(), 0x11223344);
 }
 
+#[test]
+fn test_24() {
+    // A 24-bit field that ends the buffer must not need a fourth byte
+    let raw: [u8; 4] = [0x11, 0x22, 0x33, 0x44];
+    let mut tbuf = Buffer::new_from_slice(&raw);
+
+    assert_eq!(tbuf.get_8().unwrap(), 0x11);
+    assert_eq!(tbuf.get_24().unwrap(), 0x223344);
+    assert_eq!(tbuf.get_24(), Err(crate::Error::TooShort));
+
+    // ... which is the case for a message with no options
+    let msg = crate::ClientMsg::decode(&raw).unwrap();
+    assert_eq!(msg.tx_id, 0x223344);
+    assert!(msg.options.is_empty());
+}
+
+#[test]
+fn test_long() {
+    let raw: [u8; 8] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+    let mut tbuf = Buffer::new_from_slice(&raw);
+
+    assert_eq!(tbuf.get_64().unwrap(), 0x1122334455667788);
+}
+
 #[test]
 fn test_overflow() {
     let raw: [u8; 4] = [0x11, 0x22, 0x33, 0x44];
@@ -138,3 +292,25 @@ fn test_overflow() {
     assert_eq!(tbuf.get_32().unwrap(), 0x11223344);
     assert_eq!(tbuf.get_32(), Err(crate::Error::TooShort));
 }
+
+#[test]
+fn test_sink() {
+    let mut raw = [0u8; 8];
+    let mut tbuf = BufferMut::new_from_slice(&mut raw);
+
+    tbuf.put_8(0x11).unwrap();
+    let len = tbuf.reserve_16().unwrap();
+    tbuf.put_24(0x223344).unwrap();
+    tbuf.backfill_16(len).unwrap();
+    assert_eq!(tbuf.offset(), 6);
+    assert_eq!(tbuf.put_24(0x556677), Err(crate::Error::TooShort));
+    tbuf.put_16(0x5566).unwrap();
+    assert_eq!(raw, [0x11, 0x00, 0x03, 0x22, 0x33, 0x44, 0x55, 0x66]);
+
+    let mut count = Counter::default();
+    count.put_8(0x11).unwrap();
+    let len = count.reserve_16().unwrap();
+    count.put_24(0x223344).unwrap();
+    count.backfill_16(len).unwrap();
+    assert_eq!(count.offset(), 6);
+}
@@ -0,0 +1,1124 @@
+// Copyright 2021 Oxide Computer Company
+
+//! A sans-IO DHCPv6 client, implementing stateful address assignment as
+//! described in rfc8415, section 18.
+//!
+//! The client doesn't own a socket or a clock.  The caller feeds it received
+//! messages and timer expirations, and in return is handed the messages to
+//! send and the time at which the client next needs to be woken up.
+
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::options::*;
+use crate::retransmit::Retransmitter;
+use crate::selection::{ServerSelection, Verdict};
+use crate::*;
+
+/// Options the client always asks the server for
+const DEFAULT_ORO: [u16; 2] = [OPTION_DNS_SERVERS, OPTION_DOMAIN_LIST];
+
+/// Static configuration for a DHCPv6 client
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The client's DUID, sent in the Client Identifier option
+    pub duid: Duid,
+    /// The IAID of the single IA_NA the client requests
+    pub iaid: u32,
+    /// Any options to request in addition to the DNS servers and domain list
+    pub oro: Vec<u16>,
+    /// Whether to ask servers to skip the Advertise/Request exchange, as
+    /// described in rfc8415, section 18.2.1
+    pub rapid_commit: bool,
+}
+
+impl ClientConfig {
+    pub fn new(duid: Duid, iaid: u32) -> Self {
+        ClientConfig {
+            duid,
+            iaid,
+            oro: Vec::new(),
+            rapid_commit: false,
+        }
+    }
+}
+
+/// The states of the client, as described in rfc8415, section 18
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClientState {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+/// A single address assigned to the client, with its lifetimes in seconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaseAddr {
+    pub addr: Ipv6Addr,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+}
+
+/// The addresses and configuration the client has obtained from a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lease {
+    pub server_id: Duid,
+    pub iaid: u32,
+    pub t1: u32,
+    pub t2: u32,
+    pub addrs: Vec<LeaseAddr>,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub domain_list: Vec<String>,
+    /// Where the server accepts unicast messages, if it sent a Server
+    /// Unicast option
+    pub unicast: Option<Ipv6Addr>,
+    /// When the Reply granting the lease was received.  All of the times
+    /// above are relative to this.
+    pub acquired: Instant,
+}
+
+impl Lease {
+    /// Returns the time at which the client should try to renew the lease,
+    /// or None if it never needs to
+    pub fn renew_at(&self) -> Option<Instant> {
+        lifetime_end(&self.acquired, self.t1)
+    }
+
+    /// Returns the time at which the client should try to extend the lease
+    /// with any available server, or None if it never needs to
+    pub fn rebind_at(&self) -> Option<Instant> {
+        lifetime_end(&self.acquired, self.t2)
+    }
+
+    /// Returns the time at which the last of the addresses becomes invalid,
+    /// or None if one of them never does
+    pub fn expires_at(&self) -> Option<Instant> {
+        let valid = self.addrs.iter().map(|a| a.valid_lifetime).max();
+        lifetime_end(&self.acquired, valid.unwrap_or(0))
+    }
+}
+
+/// Something the client must do after processing a Reply, beyond adopting
+/// the binding it carried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplyAction {
+    /// The Reply doesn't complete the exchange, so carry on retransmitting
+    Retransmit,
+    /// The server has no binding for the IA, so send a Request for it
+    Request,
+    /// The addresses aren't appropriate for the link, so start over with a
+    /// Solicit
+    Solicit,
+    /// The server won't accept unicast messages, so send the message again
+    /// to All_DHCP_Relay_Agents_and_Servers
+    UseMulticast,
+}
+
+/// The result of applying a Reply to the client's binding
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplyOutcome {
+    /// The binding once the Reply has been applied, or None if the client
+    /// holds no addresses
+    pub lease: Option<Lease>,
+    /// What the client must do next.  If this is None, the exchange is
+    /// complete and the client is bound.
+    pub action: Option<ReplyAction>,
+}
+
+// Returns the code of the Status Code option in a list, if there is one
+pub(crate) fn status_code(options: &[Dhcpv6Option]) -> Option<StatusCode> {
+    match find_one_option(options, OPTION_STATUS_CODE) {
+        Some(Dhcpv6Option::StatusCode(s)) => Some(s.code),
+        _ => None,
+    }
+}
+
+// Returns the addresses in a lease, with their lifetimes reduced by the time
+// since it was acquired.  Addresses that are no longer valid are dropped.
+fn aged_addrs(lease: &Lease, now: Instant) -> Vec<LeaseAddr> {
+    let elapsed = now.saturating_duration_since(lease.acquired).as_secs();
+    let elapsed = elapsed.min(u32::MAX as u64) as u32;
+    let age = |lifetime: u32| match lifetime {
+        INFINITY => INFINITY,
+        l => l.saturating_sub(elapsed),
+    };
+    lease
+        .addrs
+        .iter()
+        .map(|a| LeaseAddr {
+            addr: a.addr,
+            preferred_lifetime: age(a.preferred_lifetime),
+            valid_lifetime: age(a.valid_lifetime),
+        })
+        .filter(|a| a.valid_lifetime > 0)
+        .collect()
+}
+
+/// Applies a Reply to the client's current binding for an IA_NA, following
+/// the rules in rfc8415, section 18.2.10.  `sent` is the type of message the
+/// Reply answers.
+///
+/// Only the IA_NA with the given IAID is looked at, since a `Client` holds a
+/// single IA_NA.  A client with several must call this once for each of
+/// them.
+///
+/// Addresses in the Reply are added to the binding or have their lifetimes
+/// updated, and any with a valid lifetime of 0 are removed.  Addresses the
+/// Reply doesn't mention are left alone.  If the server leaves T1 or T2 as 0,
+/// the client chooses that one from the shortest lifetimes, keeping any
+/// value the server did supply.
+pub fn process_reply(
+    now: Instant,
+    current: Option<&Lease>,
+    sent: MsgType,
+    iaid: u32,
+    reply: &ClientMsg,
+) -> ReplyOutcome {
+    let outcome = |lease: Option<Lease>, action| ReplyOutcome {
+        lease,
+        action: Some(action),
+    };
+    let unchanged = |action| outcome(current.cloned(), action);
+    // Without any addresses from the server, a Request has failed, but a
+    // Renew or Rebind can carry on with the binding the client already has.
+    let failed = || match sent {
+        MsgType::Request => outcome(None, ReplyAction::Solicit),
+        _ => unchanged(ReplyAction::Retransmit),
+    };
+
+    let server_id = match reply.find_one_option(OPTION_SERVERID) {
+        Some(Dhcpv6Option::ServerId(id)) => id.clone(),
+        _ => return unchanged(ReplyAction::Retransmit),
+    };
+    match status_code(&reply.options) {
+        None | Some(StatusCode::Success) => {}
+        Some(StatusCode::UseMulticast) => {
+            let mut lease = current.cloned();
+            if let Some(lease) = lease.as_mut() {
+                lease.unicast = None;
+            }
+            return outcome(lease, ReplyAction::UseMulticast);
+        }
+        Some(StatusCode::NotOnLink) => return outcome(None, ReplyAction::Solicit),
+        Some(_) => return unchanged(ReplyAction::Retransmit),
+    }
+
+    // An IA with inconsistent timers is treated as though it were missing
+    let ia = match find_ia_na(reply, iaid) {
+        Some(Dhcpv6Option::IaNa(ia)) if ia.is_valid() => ia,
+        _ => return failed(),
+    };
+    match status_code(&ia.options) {
+        None | Some(StatusCode::Success) => {}
+        Some(StatusCode::NoBinding) if matches!(sent, MsgType::Renew | MsgType::Rebind) => {
+            return unchanged(ReplyAction::Request)
+        }
+        Some(StatusCode::NotOnLink) => return outcome(None, ReplyAction::Solicit),
+        Some(_) => return failed(),
+    }
+
+    let mut addrs = current.map(|l| aged_addrs(l, now)).unwrap_or_default();
+    for a in ia.addrs() {
+        addrs.retain(|x| x.addr != a.addr);
+        if a.valid_lifetime > 0 {
+            addrs.push(LeaseAddr {
+                addr: a.addr,
+                preferred_lifetime: a.preferred_lifetime,
+                valid_lifetime: a.valid_lifetime,
+            });
+        }
+    }
+    if addrs.is_empty() {
+        return match sent {
+            // Keep waiting for other servers to answer
+            MsgType::Solicit => outcome(None, ReplyAction::Retransmit),
+            _ => outcome(None, ReplyAction::Solicit),
+        };
+    }
+
+    // If the server leaves a timer up to us, use the value recommended by
+    // rfc8415, section 21.4.
+    let defaults = default_timers(
+        addrs.iter().map(|a| a.preferred_lifetime).min().unwrap(),
+        addrs.iter().map(|a| a.valid_lifetime).min().unwrap(),
+    );
+    let (t1, t2) = fill_timers(ia.t1, ia.t2, defaults);
+
+    let mut lease = Lease {
+        server_id,
+        iaid,
+        t1,
+        t2,
+        addrs,
+        dns_servers: Vec::new(),
+        domain_list: Vec::new(),
+        unicast: None,
+        acquired: now,
+    };
+    for opt in &reply.options {
+        match opt {
+            Dhcpv6Option::DnsServers(x) => lease.dns_servers = x.clone(),
+            Dhcpv6Option::DomainList(x) => lease.domain_list = x.clone(),
+            Dhcpv6Option::Unicast(x) => lease.unicast = Some(*x),
+            _ => {}
+        }
+    }
+    ReplyOutcome {
+        lease: Some(lease),
+        action: None,
+    }
+}
+
+/// A message to be sent by the client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transmit {
+    pub msg: ClientMsg,
+    pub dest: Ipv6Addr,
+}
+
+/// The result of feeding an event to the client
+#[derive(Debug, Default, PartialEq)]
+pub struct Output {
+    /// A message to be sent, if any
+    pub transmit: Option<Transmit>,
+    /// When handle_timeout() should next be called, if ever
+    pub deadline: Option<Instant>,
+}
+
+/// A sans-IO DHCPv6 client, which acquires and maintains the addresses in a
+/// single IA_NA.
+pub struct Client<R: Rng = StdRng> {
+    config: ClientConfig,
+    rng: R,
+    state: ClientState,
+    tx_id: u32,
+    retransmit: Option<Retransmitter>,
+    deadline: Option<Instant>,
+    selection: Option<ServerSelection>,
+    // Set once the first RT of the Solicit has passed without an acceptable
+    // Advertise.  From then on, the first one to arrive is acted on.
+    first_rt_elapsed: bool,
+    selected: Option<ClientMsg>,
+    lease: Option<Lease>,
+}
+
+impl Client<StdRng> {
+    pub fn new(config: ClientConfig) -> Self {
+        Client::with_rng(config, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> Client<R> {
+    /// Returns a client which draws its delays, retransmission timeouts and
+    /// transaction IDs from the supplied RNG.
+    pub fn with_rng(config: ClientConfig, rng: R) -> Self {
+        Client {
+            config,
+            rng,
+            state: ClientState::Init,
+            tx_id: 0,
+            retransmit: None,
+            deadline: None,
+            selection: None,
+            first_rt_elapsed: false,
+            selected: None,
+            lease: None,
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    /// Returns the lease the client currently holds, if any
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Returns when handle_timeout() should next be called, if ever
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Starts the process of acquiring a lease.  The first Solicit is delayed
+    /// by a random amount, as required by rfc8415, section 18.2.1.
+    pub fn start(&mut self, now: Instant) -> Output {
+        self.restart(now)
+    }
+
+    // Discards any state and goes back to soliciting servers
+    fn restart(&mut self, now: Instant) -> Output {
+        self.state = ClientState::Init;
+        self.retransmit = None;
+        self.selection = None;
+        self.first_rt_elapsed = false;
+        self.selected = None;
+        self.lease = None;
+
+        let max_delay = params::SOL_MAX_DELAY as u64 * 1000;
+        let delay = self.rng.gen_range(0..=max_delay);
+        self.deadline = Some(now + Duration::from_millis(delay));
+        self.output(None)
+    }
+
+    fn output(&self, transmit: Option<ClientMsg>) -> Output {
+        // A Renew goes straight to the server if it has said it accepts
+        // unicast.  Everything else is multicast.
+        let dest = match (self.state, self.lease.as_ref().and_then(|l| l.unicast)) {
+            (ClientState::Renewing, Some(unicast)) => unicast,
+            _ => params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS,
+        };
+        Output {
+            transmit: transmit.map(|msg| Transmit { msg, dest }),
+            deadline: self.deadline,
+        }
+    }
+
+    // Begins a new exchange, returning the first message of the exchange
+    fn begin(&mut self, now: Instant, msg_type: MsgType) -> Option<ClientMsg> {
+        let rng = StdRng::seed_from_u64(self.rng.gen());
+        let mut retransmit = Retransmitter::with_rng(msg_type, rng).unwrap();
+        match (msg_type, &self.lease) {
+            // Without an end, the exchange carries on until it succeeds
+            (MsgType::Renew, Some(lease)) => {
+                if let Some(t2) = lease.rebind_at() {
+                    retransmit.set_mrd(t2.saturating_duration_since(now));
+                }
+            }
+            (MsgType::Rebind, Some(lease)) => {
+                if let Some(expiry) = lease.expires_at() {
+                    retransmit.set_mrd(expiry.saturating_duration_since(now));
+                }
+            }
+            _ => {}
+        }
+
+        self.tx_id = self.rng.gen::<u32>() & 0xffffff;
+        self.deadline = Some(retransmit.start(&now));
+        self.retransmit = Some(retransmit);
+        Some(self.build(now, msg_type))
+    }
+
+    // Builds the current exchange's message
+    fn build(&self, now: Instant, msg_type: MsgType) -> ClientMsg {
+        let mut msg = ClientMsg::new(msg_type, Some(self.tx_id));
+        msg.options
+            .push(Dhcpv6Option::ClientId(self.config.duid.clone()));
+
+        let mut ia_na = IaNaOption::new(self.config.iaid);
+        match msg_type {
+            MsgType::Request if self.selected.is_some() => {
+                let advertise = self.selected.as_ref().unwrap();
+                if let Some(opt) = advertise.find_one_option(OPTION_SERVERID) {
+                    msg.options.push(opt.clone());
+                }
+                if let Some(Dhcpv6Option::IaNa(offered)) = find_ia_na(advertise, self.config.iaid) {
+                    ia_na.options = offered
+                        .options
+                        .iter()
+                        .filter(|o| u16::from(*o) == OPTION_IAADDR)
+                        .cloned()
+                        .collect();
+                }
+            }
+            // Without an Advertise, the Request asks the server that granted
+            // the lease to recreate a binding it has lost.
+            MsgType::Request | MsgType::Renew | MsgType::Rebind => {
+                let lease = self.lease.as_ref().unwrap();
+                if msg_type != MsgType::Rebind {
+                    msg.options
+                        .push(Dhcpv6Option::ServerId(lease.server_id.clone()));
+                }
+                ia_na.options = lease
+                    .addrs
+                    .iter()
+                    .map(|a| Dhcpv6Option::IaAddr(IaAddrOption::new(a.addr)))
+                    .collect();
+            }
+            _ => {}
+        }
+        msg.options.push(Dhcpv6Option::IaNa(ia_na));
+        if msg_type == MsgType::Solicit && self.config.rapid_commit {
+            msg.options.push(Dhcpv6Option::RapidCommit);
+        }
+
+        let mut oro = DEFAULT_ORO.to_vec();
+        oro.extend(self.config.oro.iter().filter(|c| !DEFAULT_ORO.contains(c)));
+        msg.options.push(Dhcpv6Option::Oro(oro));
+
+        // The elapsed time is measured in hundredths of a second
+        let started = self.retransmit.as_ref().and_then(|r| r.started());
+        let elapsed = now.saturating_duration_since(started.unwrap_or(now));
+        let elapsed = (elapsed.as_millis() / 10).min(u16::MAX as u128) as u16;
+        msg.options.push(Dhcpv6Option::ElapsedTime(elapsed));
+        msg
+    }
+
+    // Sends a Request to the best of the servers that have advertised
+    fn select(&mut self, now: Instant) -> Output {
+        let selection = self.selection.take().unwrap();
+        self.selected = selection.choose().map(|best| best.offer.msg);
+        self.state = ClientState::Requesting;
+        let msg = self.begin(now, MsgType::Request);
+        self.output(msg)
+    }
+
+    /// Handles the expiration of the deadline most recently returned by the
+    /// client.
+    pub fn handle_timeout(&mut self, now: Instant) -> Output {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return self.output(None),
+        }
+
+        match self.state {
+            ClientState::Init => {
+                self.state = ClientState::Selecting;
+                let msg = self.begin(now, MsgType::Solicit);
+                self.selection = msg.as_ref().map(ServerSelection::new);
+                return self.output(msg);
+            }
+            ClientState::Selecting => {
+                // The first RT has elapsed, so take the best offer we have
+                if !self.selection.as_ref().unwrap().is_empty() {
+                    return self.select(now);
+                }
+                self.first_rt_elapsed = true;
+            }
+            ClientState::Bound => {
+                self.state = ClientState::Renewing;
+                let msg = self.begin(now, MsgType::Renew);
+                return self.output(msg);
+            }
+            _ => {}
+        }
+
+        let retransmit = self.retransmit.as_mut().unwrap();
+        match retransmit.next(&now) {
+            Some(next) => {
+                self.deadline = Some(next);
+                let msg = self.build(now, self.exchange());
+                self.output(Some(msg))
+            }
+            None => match self.state {
+                ClientState::Renewing => {
+                    self.state = ClientState::Rebinding;
+                    let msg = self.begin(now, MsgType::Rebind);
+                    self.output(msg)
+                }
+                // Either the server we selected isn't responding, or our
+                // lease has expired.  Either way, start from scratch.
+                _ => self.restart(now),
+            },
+        }
+    }
+
+    /// Handles a message received from a server.  Messages which aren't
+    /// part of the current exchange are ignored.
+    pub fn handle_msg(&mut self, now: Instant, msg: &ClientMsg) -> Output {
+        if !self.is_response(msg) {
+            return self.output(None);
+        }
+
+        match (self.state, msg.msg_type) {
+            (ClientState::Selecting, MsgType::Advertise) => {
+                let selection = self.selection.as_mut().unwrap();
+                match selection.add(msg) {
+                    Verdict::SelectNow => self.select(now),
+                    Verdict::Candidate if self.first_rt_elapsed => self.select(now),
+                    _ => self.output(None),
+                }
+            }
+            // A server that commits straight away ends the search, but any
+            // other Reply is no answer to a Solicit.
+            (ClientState::Selecting, MsgType::Reply) => {
+                if self.config.rapid_commit && msg.has_option(OPTION_RAPID_COMMIT) {
+                    self.handle_reply(now, msg)
+                } else {
+                    self.output(None)
+                }
+            }
+            (ClientState::Requesting, MsgType::Reply)
+            | (ClientState::Renewing, MsgType::Reply)
+            | (ClientState::Rebinding, MsgType::Reply) => self.handle_reply(now, msg),
+            _ => self.output(None),
+        }
+    }
+
+    fn handle_reply(&mut self, now: Instant, msg: &ClientMsg) -> Output {
+        let sent = self.exchange();
+        let outcome = process_reply(now, self.lease.as_ref(), sent, self.config.iaid, msg);
+        self.lease = outcome.lease;
+
+        match outcome.action {
+            None => {
+                self.deadline = self.lease.as_ref().and_then(|l| l.renew_at());
+                self.retransmit = None;
+                self.selection = None;
+                self.selected = None;
+                self.state = ClientState::Bound;
+                self.output(None)
+            }
+            Some(ReplyAction::Retransmit) => self.output(None),
+            Some(ReplyAction::Request) => {
+                self.selected = None;
+                self.state = ClientState::Requesting;
+                let msg = self.begin(now, MsgType::Request);
+                self.output(msg)
+            }
+            Some(ReplyAction::Solicit) => self.restart(now),
+            // The unicast address has been dropped from the lease, so the
+            // message now goes to the multicast address.
+            Some(ReplyAction::UseMulticast) => {
+                let msg = self.build(now, sent);
+                self.output(Some(msg))
+            }
+        }
+    }
+
+    // Returns the type of message the current exchange is sending
+    fn exchange(&self) -> MsgType {
+        match self.state {
+            ClientState::Init | ClientState::Selecting => MsgType::Solicit,
+            ClientState::Requesting => MsgType::Request,
+            ClientState::Bound | ClientState::Renewing => MsgType::Renew,
+            ClientState::Rebinding => MsgType::Rebind,
+        }
+    }
+
+    // Returns true if the message is a server's response to our current
+    // exchange.
+    fn is_response(&self, msg: &ClientMsg) -> bool {
+        if self.retransmit.is_none() || msg.tx_id != self.tx_id {
+            return false;
+        }
+        if !msg.has_option(OPTION_SERVERID) {
+            return false;
+        }
+        matches!(
+            msg.find_one_option(OPTION_CLIENTID),
+            Some(Dhcpv6Option::ClientId(id)) if *id == self.config.duid
+        )
+    }
+}
+
+fn find_ia_na(msg: &ClientMsg, iaid: u32) -> Option<&Dhcpv6Option> {
+    msg.options
+        .iter()
+        .find(|o| matches!(o, Dhcpv6Option::IaNa(ia) if ia.iaid == iaid))
+}
+
+#[cfg(test)]
+use crate::test::test_duid;
+
+// Builds a server's response to a client message, offering a single address
+#[cfg(test)]
+fn test_response(
+    msg: &ClientMsg,
+    msg_type: MsgType,
+    server: u8,
+    addr: &str,
+    t1: u32,
+    t2: u32,
+) -> ClientMsg {
+    let mut response = ClientMsg::new(msg_type, Some(msg.tx_id));
+    response
+        .options
+        .push(msg.find_one_option(OPTION_CLIENTID).unwrap().clone());
+    response
+        .options
+        .push(Dhcpv6Option::ServerId(test_duid(server.into())));
+    let mut ia_addr = IaAddrOption::new(addr.parse().unwrap());
+    ia_addr.preferred_lifetime = 3000;
+    ia_addr.valid_lifetime = 4000;
+    let mut ia_na = IaNaOption::new(1);
+    ia_na.t1 = t1;
+    ia_na.t2 = t2;
+    ia_na.options.push(Dhcpv6Option::IaAddr(ia_addr));
+    response.options.push(Dhcpv6Option::IaNa(ia_na));
+    response
+}
+
+#[test]
+fn test_client_lifecycle() {
+    let mut client = Client::new(ClientConfig::new(test_duid(1), 1));
+    let t0 = Instant::now();
+
+    // The first Solicit is only sent once the initial delay has elapsed
+    let out = client.start(t0);
+    assert!(out.transmit.is_none());
+    let deadline = out.deadline.unwrap();
+    assert!(deadline <= t0 + Duration::from_secs(params::SOL_MAX_DELAY as u64));
+    let out = client.handle_timeout(deadline);
+    let solicit = out.transmit.unwrap().msg;
+    assert_eq!(solicit.msg_type, MsgType::Solicit);
+    assert_eq!(client.state(), ClientState::Selecting);
+    assert!(solicit.has_option(OPTION_IA_NA));
+
+    // An Advertise for some other transaction is ignored
+    let mut stale = test_response(&solicit, MsgType::Advertise, 9, "fd00::9", 0, 0);
+    stale.tx_id ^= 1;
+    assert!(client.handle_msg(deadline, &stale).transmit.is_none());
+
+    // Collect Advertises until the first RT expires, then request from the
+    // most preferred server.
+    let adv1 = test_response(&solicit, MsgType::Advertise, 2, "fd00::2", 0, 0);
+    let mut adv2 = test_response(&solicit, MsgType::Advertise, 3, "fd00::3", 0, 0);
+    adv2.options.push(Dhcpv6Option::Preference(10));
+    assert!(client.handle_msg(deadline, &adv1).transmit.is_none());
+    assert!(client.handle_msg(deadline, &adv2).transmit.is_none());
+    let rt = out.deadline.unwrap();
+    let out = client.handle_timeout(rt);
+    let request = out.transmit.unwrap().msg;
+    assert_eq!(request.msg_type, MsgType::Request);
+    assert_eq!(client.state(), ClientState::Requesting);
+    assert_eq!(
+        request.find_one_option(OPTION_SERVERID),
+        Some(&Dhcpv6Option::ServerId(test_duid(3)))
+    );
+
+    // The Reply binds the address, with T1/T2 chosen by the client
+    let reply = test_response(&request, MsgType::Reply, 3, "fd00::3", 0, 0);
+    let out = client.handle_msg(rt, &reply);
+    assert!(out.transmit.is_none());
+    assert_eq!(client.state(), ClientState::Bound);
+    let lease = client.lease().unwrap().clone();
+    assert_eq!(lease.addrs[0].addr, "fd00::3".parse::<Ipv6Addr>().unwrap());
+    assert_eq!((lease.t1, lease.t2), (1500, 2400));
+    assert_eq!(out.deadline, Some(rt + Duration::from_secs(1500)));
+
+    // At T1 the client renews with the server that granted the lease
+    let t1 = out.deadline.unwrap();
+    let out = client.handle_timeout(t1);
+    let renew = out.transmit.unwrap().msg;
+    assert_eq!(renew.msg_type, MsgType::Renew);
+    assert_eq!(client.state(), ClientState::Renewing);
+    assert_eq!(
+        renew.find_one_option(OPTION_SERVERID),
+        Some(&Dhcpv6Option::ServerId(test_duid(3)))
+    );
+    let reply = test_response(&renew, MsgType::Reply, 3, "fd00::3", 1000, 1600);
+    let out = client.handle_msg(t1, &reply);
+    assert_eq!(client.state(), ClientState::Bound);
+    assert_eq!(out.deadline, Some(t1 + Duration::from_secs(1000)));
+
+    // If the server stops responding, the client keeps renewing until T2,
+    // then rebinds with any server until the lease runs out.
+    let mut now = client
+        .handle_timeout(out.deadline.unwrap())
+        .deadline
+        .unwrap();
+    let t2 = t1 + Duration::from_secs(1600);
+    while client.state() == ClientState::Renewing {
+        let out = client.handle_timeout(now);
+        let msg = out.transmit.unwrap().msg;
+        assert!(now <= t2);
+        if client.state() == ClientState::Rebinding {
+            assert_eq!(msg.msg_type, MsgType::Rebind);
+            assert!(!msg.has_option(OPTION_SERVERID));
+        }
+        now = out.deadline.unwrap();
+    }
+    assert_eq!(client.state(), ClientState::Rebinding);
+    let expiry = t1 + Duration::from_secs(4000);
+    while client.state() == ClientState::Rebinding {
+        assert!(now <= expiry);
+        now = client.handle_timeout(now).deadline.unwrap();
+    }
+    assert_eq!(client.state(), ClientState::Init);
+    assert!(client.lease().is_none());
+}
+
+#[test]
+fn test_client_request_timeout() {
+    let mut client = Client::new(ClientConfig::new(test_duid(1), 1));
+    let t0 = Instant::now();
+
+    let deadline = client.start(t0).deadline.unwrap();
+    let out = client.handle_timeout(deadline);
+    let solicit = out.transmit.unwrap().msg;
+
+    // A preference of 255 means the client should request immediately
+    let mut adv = test_response(&solicit, MsgType::Advertise, 2, "fd00::2", 0, 0);
+    adv.options.push(Dhcpv6Option::Preference(255));
+    let out = client.handle_msg(deadline, &adv);
+    assert_eq!(out.transmit.unwrap().msg.msg_type, MsgType::Request);
+
+    // After REQ_MAX_RC attempts, the client gives up on the server
+    let mut now = out.deadline.unwrap();
+    let mut requests = 1;
+    while client.state() == ClientState::Requesting {
+        let out = client.handle_timeout(now);
+        if out.transmit.is_some() {
+            requests += 1;
+        }
+        now = out.deadline.unwrap();
+    }
+    assert_eq!(requests, params::REQ_MAX_RC);
+    assert_eq!(client.state(), ClientState::Init);
+}
+
+#[test]
+fn test_client_late_advertise() {
+    let mut client = Client::new(ClientConfig::new(test_duid(1), 1));
+    let t0 = Instant::now();
+
+    let deadline = client.start(t0).deadline.unwrap();
+    let out = client.handle_timeout(deadline);
+    let solicit = out.transmit.unwrap().msg;
+
+    // With no Advertise by the end of the first RT, the Solicit is resent
+    let rt = out.deadline.unwrap();
+    let out = client.handle_timeout(rt);
+    assert_eq!(out.transmit.unwrap().msg.msg_type, MsgType::Solicit);
+    assert_eq!(client.state(), ClientState::Selecting);
+
+    // An Advertise offering nothing is ignored, but the next acceptable one
+    // is acted on without waiting for others.
+    let mut empty = test_response(&solicit, MsgType::Advertise, 2, "fd00::2", 0, 0);
+    empty.options.pop();
+    assert!(client.handle_msg(rt, &empty).transmit.is_none());
+    let adv = test_response(&solicit, MsgType::Advertise, 3, "fd00::3", 0, 0);
+    let out = client.handle_msg(rt, &adv);
+    let request = out.transmit.unwrap().msg;
+    assert_eq!(request.msg_type, MsgType::Request);
+    assert_eq!(
+        request.find_one_option(OPTION_SERVERID),
+        Some(&Dhcpv6Option::ServerId(test_duid(3)))
+    );
+}
+
+#[test]
+fn test_client_rapid_commit() {
+    let mut config = ClientConfig::new(test_duid(1), 1);
+    config.rapid_commit = true;
+    let mut client = Client::new(config);
+    let t0 = Instant::now();
+
+    let deadline = client.start(t0).deadline.unwrap();
+    let solicit = client.handle_timeout(deadline).transmit.unwrap().msg;
+    assert!(solicit.has_option(OPTION_RAPID_COMMIT));
+
+    // A Reply without Rapid Commit is no answer to a Solicit
+    let reply = test_response(&solicit, MsgType::Reply, 2, "fd00::2", 0, 0);
+    client.handle_msg(deadline, &reply);
+    assert_eq!(client.state(), ClientState::Selecting);
+
+    // With it, the client is bound at once
+    let mut reply = test_response(&solicit, MsgType::Reply, 2, "fd00::2", 0, 0);
+    reply.options.push(Dhcpv6Option::RapidCommit);
+    let out = client.handle_msg(deadline, &reply);
+    assert!(out.transmit.is_none());
+    assert_eq!(client.state(), ClientState::Bound);
+    let lease = client.lease().unwrap();
+    assert_eq!(lease.server_id, test_duid(2));
+    assert_eq!(lease.addrs[0].addr, "fd00::2".parse::<Ipv6Addr>().unwrap());
+
+    // A client that didn't ask for it falls back to the Advertise flow
+    let mut client = Client::new(ClientConfig::new(test_duid(1), 1));
+    let deadline = client.start(t0).deadline.unwrap();
+    let solicit = client.handle_timeout(deadline).transmit.unwrap().msg;
+    assert!(!solicit.has_option(OPTION_RAPID_COMMIT));
+    let mut reply = test_response(&solicit, MsgType::Reply, 2, "fd00::2", 0, 0);
+    reply.options.push(Dhcpv6Option::RapidCommit);
+    client.handle_msg(deadline, &reply);
+    assert_eq!(client.state(), ClientState::Selecting);
+}
+
+#[test]
+fn test_client_rapid_commit_mixed() {
+    use crate::pool::{AddressPool, Strategy};
+    use crate::server::{Server, ServerConfig};
+    use std::time::SystemTime;
+
+    // One server commits straight away, while the other only advertises
+    let mut fast =
+        AddressPool::new("fd00:1::".parse().unwrap(), 120, Strategy::Sequential).unwrap();
+    fast.set_rapid_commit(Some(true));
+    let mut fast = Server::new(ServerConfig::new(test_duid(2)), fast);
+    let slow = AddressPool::new("fd00:2::".parse().unwrap(), 120, Strategy::Sequential).unwrap();
+    let mut slow = Server::new(ServerConfig::new(test_duid(3)), slow);
+
+    let mut config = ClientConfig::new(test_duid(1), 1);
+    config.rapid_commit = true;
+    let mut client = Client::new(config);
+    let now = client.start(Instant::now()).deadline.unwrap();
+    let solicit = client.handle_timeout(now).transmit.unwrap().msg;
+
+    let advertise = slow.handle_client_msg(SystemTime::now(), &solicit).unwrap();
+    assert_eq!(advertise.msg_type, MsgType::Advertise);
+    assert!(!advertise.has_option(OPTION_RAPID_COMMIT));
+    let reply = fast.handle_client_msg(SystemTime::now(), &solicit).unwrap();
+    assert_eq!(reply.msg_type, MsgType::Reply);
+    assert!(reply.has_option(OPTION_RAPID_COMMIT));
+
+    // The Advertise arrives first, but the committed Reply still wins
+    assert!(client.handle_msg(now, &advertise).transmit.is_none());
+    assert!(client.handle_msg(now, &reply).transmit.is_none());
+    assert_eq!(client.state(), ClientState::Bound);
+    assert_eq!(client.lease().unwrap().server_id, test_duid(2));
+    assert_eq!(
+        client.lease().unwrap().addrs[0].addr,
+        "fd00:1::1".parse::<Ipv6Addr>().unwrap()
+    );
+    assert!(fast.binding(&test_duid(1), 1).unwrap().committed);
+
+    // Had the Reply been lost, the Advertise would have been requested
+    // once the first RT expired.
+    let mut client = Client::new(client.config.clone());
+    let now = client.start(Instant::now()).deadline.unwrap();
+    let out = client.handle_timeout(now);
+    let solicit = out.transmit.unwrap().msg;
+    let advertise = slow.handle_client_msg(SystemTime::now(), &solicit).unwrap();
+    client.handle_msg(now, &advertise);
+    let out = client.handle_timeout(out.deadline.unwrap());
+    let request = out.transmit.unwrap().msg;
+    assert_eq!(request.msg_type, MsgType::Request);
+    assert_eq!(
+        request.find_one_option(OPTION_SERVERID),
+        Some(&Dhcpv6Option::ServerId(test_duid(3)))
+    );
+}
+
+#[cfg(test)]
+fn test_status(code: StatusCode) -> Dhcpv6Option {
+    Dhcpv6Option::StatusCode(StatusCodeOption {
+        code,
+        msg: Vec::new(),
+    })
+}
+
+#[test]
+fn test_process_reply() {
+    let now = Instant::now();
+    let mut renew = ClientMsg::new(MsgType::Renew, Some(1));
+    renew.options.push(Dhcpv6Option::ClientId(test_duid(1)));
+
+    // A fresh binding, with T1/T2 chosen by the client and the server's
+    // unicast address recorded
+    let mut reply = test_response(&renew, MsgType::Reply, 2, "fd00::2", 0, 0);
+    reply
+        .options
+        .push(Dhcpv6Option::Unicast("fd00::547".parse().unwrap()));
+    let outcome = process_reply(now, None, MsgType::Request, 1, &reply);
+    assert_eq!(outcome.action, None);
+    let lease = outcome.lease.unwrap();
+    assert_eq!((lease.t1, lease.t2), (1500, 2400));
+    assert_eq!(lease.unicast, Some("fd00::547".parse().unwrap()));
+
+    // A new address is added alongside the old one, whose lifetimes have
+    // aged, and an address with a valid lifetime of 0 is removed.
+    let later = now + Duration::from_secs(1000);
+    let mut reply = test_response(&renew, MsgType::Reply, 2, "fd00::3", 600, 900);
+    let outcome = process_reply(later, Some(&lease), MsgType::Renew, 1, &reply);
+    let renewed = outcome.lease.unwrap();
+    assert_eq!((renewed.t1, renewed.t2), (600, 900));
+    assert_eq!(renewed.addrs.len(), 2);
+    assert_eq!(
+        renewed.addrs[0].addr,
+        "fd00::2".parse::<Ipv6Addr>().unwrap()
+    );
+    assert_eq!(renewed.addrs[0].valid_lifetime, 3000);
+    assert_eq!(renewed.unicast, None);
+    if let Some(Dhcpv6Option::IaNa(ia)) = reply.options.get_mut(2) {
+        let mut gone = IaAddrOption::new("fd00::2".parse().unwrap());
+        gone.preferred_lifetime = 0;
+        gone.valid_lifetime = 0;
+        ia.options.push(Dhcpv6Option::IaAddr(gone));
+    }
+    let outcome = process_reply(later, Some(&lease), MsgType::Renew, 1, &reply);
+    let addrs = outcome.lease.unwrap().addrs;
+    assert_eq!(addrs.len(), 1);
+    assert_eq!(addrs[0].addr, "fd00::3".parse::<Ipv6Addr>().unwrap());
+
+    // NoBinding for the IA in a Renew or Rebind calls for a Request
+    let mut reply = test_response(&renew, MsgType::Reply, 2, "fd00::2", 0, 0);
+    let mut ia = IaNaOption::new(1);
+    ia.options.push(test_status(StatusCode::NoBinding));
+    reply.options[2] = Dhcpv6Option::IaNa(ia);
+    let outcome = process_reply(later, Some(&lease), MsgType::Rebind, 1, &reply);
+    assert_eq!(outcome.lease.as_ref(), Some(&lease));
+    assert_eq!(outcome.action, Some(ReplyAction::Request));
+
+    // NotOnLink means starting over
+    let mut reply = test_response(&renew, MsgType::Reply, 2, "fd00::2", 0, 0);
+    let mut ia = IaNaOption::new(1);
+    ia.options.push(test_status(StatusCode::NotOnLink));
+    reply.options[2] = Dhcpv6Option::IaNa(ia);
+    let outcome = process_reply(later, Some(&lease), MsgType::Request, 1, &reply);
+    assert_eq!(outcome.lease, None);
+    assert_eq!(outcome.action, Some(ReplyAction::Solicit));
+
+    // UseMulticast drops the unicast address, but keeps the binding
+    let mut reply = ClientMsg::new(MsgType::Reply, Some(1));
+    reply.options.push(Dhcpv6Option::ServerId(test_duid(2)));
+    reply.options.push(test_status(StatusCode::UseMulticast));
+    let outcome = process_reply(later, Some(&lease), MsgType::Renew, 1, &reply);
+    assert_eq!(outcome.action, Some(ReplyAction::UseMulticast));
+    let kept = outcome.lease.unwrap();
+    assert_eq!(kept.unicast, None);
+    assert_eq!(kept.addrs, lease.addrs);
+
+    // A Renew answered without the IA carries on, while a Request fails
+    let mut reply = ClientMsg::new(MsgType::Reply, Some(1));
+    reply.options.push(Dhcpv6Option::ServerId(test_duid(2)));
+    let outcome = process_reply(later, Some(&lease), MsgType::Renew, 1, &reply);
+    assert_eq!(outcome.lease.as_ref(), Some(&lease));
+    assert_eq!(outcome.action, Some(ReplyAction::Retransmit));
+    let outcome = process_reply(later, None, MsgType::Request, 1, &reply);
+    assert_eq!(outcome.action, Some(ReplyAction::Solicit));
+}
+
+#[test]
+fn test_client_reply_recovery() {
+    let mut client = Client::new(ClientConfig::new(test_duid(1), 1));
+    let t0 = Instant::now();
+    let deadline = client.start(t0).deadline.unwrap();
+    let solicit = client.handle_timeout(deadline).transmit.unwrap().msg;
+    let mut adv = test_response(&solicit, MsgType::Advertise, 2, "fd00::2", 0, 0);
+    adv.options.push(Dhcpv6Option::Preference(255));
+    let request = client.handle_msg(deadline, &adv).transmit.unwrap().msg;
+    let mut reply = test_response(&request, MsgType::Reply, 2, "fd00::2", 0, 0);
+    reply
+        .options
+        .push(Dhcpv6Option::Unicast("fd00::547".parse().unwrap()));
+    let t1 = client.handle_msg(deadline, &reply).deadline.unwrap();
+
+    // The Renew is unicast to the server
+    let out = client.handle_timeout(t1);
+    assert_eq!(
+        out.transmit.as_ref().unwrap().dest,
+        "fd00::547".parse::<Ipv6Addr>().unwrap()
+    );
+    let renew = out.transmit.unwrap().msg;
+
+    // Until it says not to, at which point the Renew is multicast
+    let mut refusal = ClientMsg::new(MsgType::Reply, Some(renew.tx_id));
+    refusal.options.push(Dhcpv6Option::ClientId(test_duid(1)));
+    refusal.options.push(Dhcpv6Option::ServerId(test_duid(2)));
+    refusal.options.push(test_status(StatusCode::UseMulticast));
+    let out = client.handle_msg(t1, &refusal);
+    let transmit = out.transmit.unwrap();
+    assert_eq!(transmit.dest, params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS);
+    assert_eq!(transmit.msg.msg_type, MsgType::Renew);
+    assert_eq!(client.state(), ClientState::Renewing);
+
+    // The server has lost the binding, so the client requests it again
+    let mut reply = test_response(&renew, MsgType::Reply, 2, "fd00::2", 0, 0);
+    let mut ia = IaNaOption::new(1);
+    ia.options.push(test_status(StatusCode::NoBinding));
+    reply.options[2] = Dhcpv6Option::IaNa(ia);
+    let out = client.handle_msg(t1, &reply);
+    let request = out.transmit.unwrap().msg;
+    assert_eq!(request.msg_type, MsgType::Request);
+    assert_eq!(client.state(), ClientState::Requesting);
+    assert_eq!(
+        request.find_one_option(OPTION_SERVERID),
+        Some(&Dhcpv6Option::ServerId(test_duid(2)))
+    );
+    assert_eq!(
+        find_ia_na(&request, 1),
+        Some(&Dhcpv6Option::IaNa(IaNaOption {
+            iaid: 1,
+            t1: 0,
+            t2: 0,
+            options: vec![Dhcpv6Option::IaAddr(IaAddrOption::new(
+                "fd00::2".parse().unwrap()
+            ))],
+        }))
+    );
+    let reply = test_response(&request, MsgType::Reply, 2, "fd00::2", 0, 0);
+    client.handle_msg(t1, &reply);
+    assert_eq!(client.state(), ClientState::Bound);
+
+    // NotOnLink sends the client back to soliciting
+    let t1 = client.poll_timeout().unwrap();
+    let renew = client.handle_timeout(t1).transmit.unwrap().msg;
+    let mut reply = test_response(&renew, MsgType::Reply, 2, "fd00::2", 0, 0);
+    let mut ia = IaNaOption::new(1);
+    ia.options.push(test_status(StatusCode::NotOnLink));
+    reply.options[2] = Dhcpv6Option::IaNa(ia);
+    client.handle_msg(t1, &reply);
+    assert_eq!(client.state(), ClientState::Init);
+    assert!(client.lease().is_none());
+}
+
+#[test]
+fn test_process_reply_lifetimes() {
+    let now = Instant::now();
+    let mut request = ClientMsg::new(MsgType::Request, Some(1));
+    request.options.push(Dhcpv6Option::ClientId(test_duid(1)));
+
+    // An IA with T1 > T2 is as good as no IA at all
+    let reply = test_response(&request, MsgType::Reply, 2, "fd00::2", 2000, 1000);
+    let outcome = process_reply(now, None, MsgType::Request, 1, &reply);
+    assert_eq!(outcome.lease, None);
+    assert_eq!(outcome.action, Some(ReplyAction::Solicit));
+
+    // An address that never expires never needs renewing
+    let mut reply = test_response(&request, MsgType::Reply, 2, "fd00::2", 0, 0);
+    if let Some(Dhcpv6Option::IaNa(ia)) = reply.options.get_mut(2) {
+        let mut forever = IaAddrOption::new("fd00::2".parse().unwrap());
+        forever.preferred_lifetime = INFINITY;
+        forever.valid_lifetime = INFINITY;
+        ia.options[0] = Dhcpv6Option::IaAddr(forever);
+    }
+    let lease = process_reply(now, None, MsgType::Request, 1, &reply)
+        .lease
+        .unwrap();
+    assert_eq!((lease.t1, lease.t2), (INFINITY, INFINITY));
+    assert_eq!(lease.renew_at(), None);
+    assert_eq!(lease.expires_at(), None);
+    let later = now + Duration::from_secs(1_000_000);
+    assert_eq!(aged_addrs(&lease, later), lease.addrs);
+
+    // With T1 and T2 left to the client and a deprecated address, the
+    // client renews well before the address expires, rather than at once
+    let mut reply = test_response(&request, MsgType::Reply, 2, "fd00::2", 0, 0);
+    if let Some(Dhcpv6Option::IaNa(ia)) = reply.options.get_mut(2) {
+        let mut deprecated = IaAddrOption::new("fd00::2".parse().unwrap());
+        deprecated.preferred_lifetime = 0;
+        deprecated.valid_lifetime = 4000;
+        ia.options[0] = Dhcpv6Option::IaAddr(deprecated);
+    }
+    let lease = process_reply(now, None, MsgType::Request, 1, &reply)
+        .lease
+        .unwrap();
+    assert_eq!((lease.t1, lease.t2), (2000, 3200));
+    assert_eq!(lease.renew_at(), Some(now + Duration::from_secs(2000)));
+
+    // A timer the server did supply is kept, and only the other is chosen
+    let timers = |t1, t2| {
+        let reply = test_response(&request, MsgType::Reply, 2, "fd00::2", t1, t2);
+        let lease = process_reply(now, None, MsgType::Request, 1, &reply)
+            .lease
+            .unwrap();
+        (lease.t1, lease.t2)
+    };
+    assert_eq!(timers(0, 0), (1500, 2400));
+    assert_eq!(timers(1000, 0), (1000, 2400));
+    assert_eq!(timers(2800, 0), (2800, 2800));
+    assert_eq!(timers(0, 1200), (1200, 1200));
+    assert_eq!(timers(0, 3000), (1500, 3000));
+}
+
+#[test]
+fn test_client_seeded() {
+    let t0 = Instant::now();
+    let run = |seed: u64| {
+        let config = ClientConfig::new(test_duid(1), 1);
+        let mut client = Client::with_rng(config, StdRng::seed_from_u64(seed));
+        let deadline = client.start(t0).deadline.unwrap();
+        let out = client.handle_timeout(deadline);
+        (deadline, out.transmit.unwrap().msg.tx_id, out.deadline)
+    };
+
+    // The same seed gives the same delay, transaction ID and timeout
+    assert_eq!(run(1), run(1));
+    assert_ne!(run(1), run(2));
+}
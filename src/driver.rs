@@ -0,0 +1,317 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Async drivers which run the sans-IO client and server on a tokio
+//! `UdpSocket`.
+//!
+//! Each driver owns its engine and socket.  Received datagrams are decoded
+//! and fed to the engine, the engine's deadlines become timers, and anything
+//! the engine wants sent goes out on the socket.  Changes to leases are
+//! reported on a channel, so the rest of the system can react to them without
+//! reaching into the engine.
+
+use std::net::{SocketAddr, SocketAddrV6};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::client::{Client, Lease, Output};
+use crate::server::{AddressAllocator, LeaseEvent, Server};
+use crate::transport::{self, io_error, scoped, RECV_BUFFER_SIZE};
+use crate::*;
+
+/// How often the server driver sweeps expired bindings back into the pool,
+/// unless told otherwise
+const DEFAULT_EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A change to the lease held by a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    /// A lease was acquired, or an existing one was extended or changed
+    Bound(Lease),
+    /// The lease expired, or the server withdrew it
+    Lost,
+}
+
+// Binds a socket the same way as the blocking transport, and hands it over to
+// tokio.  This must be called from within the runtime.
+fn bind(port: u16) -> Result<UdpSocket> {
+    let socket = transport::bind(port)?;
+    socket.set_nonblocking(true).map_err(io_error)?;
+    UdpSocket::from_std(socket).map_err(io_error)
+}
+
+// Waits for a datagram, or until the deadline passes.  Returns None if the
+// deadline passed first.
+async fn recv_until(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    deadline: Option<Instant>,
+) -> Result<Option<(usize, SocketAddrV6)>> {
+    let rx = match deadline {
+        Some(deadline) => {
+            match time::timeout_at(time::Instant::from_std(deadline), socket.recv_from(buf)).await {
+                Ok(rx) => rx,
+                Err(_) => return Ok(None),
+            }
+        }
+        None => socket.recv_from(buf).await,
+    };
+    match rx.map_err(io_error)? {
+        (len, SocketAddr::V6(src)) => Ok(Some((len, src))),
+        (_, SocketAddr::V4(src)) => Err(Error::Other(format!("unexpected source: {}", src))),
+    }
+}
+
+/// Runs a `Client` on a UDP socket
+pub struct ClientDriver {
+    client: Client,
+    socket: UdpSocket,
+    ifindex: u32,
+    server_port: u16,
+    server: Option<Ipv6Addr>,
+    // The lease as of the last event sent, so only changes are reported
+    reported: Option<Lease>,
+    events: mpsc::UnboundedSender<ClientEvent>,
+}
+
+impl ClientDriver {
+    /// Binds the client port for a client on the given interface.  Returns
+    /// the driver along with the receiving end of its event stream.
+    pub async fn new(
+        client: Client,
+        ifindex: u32,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<ClientEvent>)> {
+        ClientDriver::with_ports(client, ifindex, params::CLIENT_PORT, params::SERVER_PORT).await
+    }
+
+    /// Binds `client_port` rather than the standard client port, and sends
+    /// to servers on `server_port`.  A client port of 0 binds an ephemeral
+    /// port.
+    pub async fn with_ports(
+        client: Client,
+        ifindex: u32,
+        client_port: u16,
+        server_port: u16,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<ClientEvent>)> {
+        let (events, rx) = mpsc::unbounded_channel();
+        let driver = ClientDriver {
+            client,
+            socket: bind(client_port)?,
+            ifindex,
+            server_port,
+            server: None,
+            reported: None,
+            events,
+        };
+        Ok((driver, rx))
+    }
+
+    /// Sends every message to the given address, rather than to wherever the
+    /// client asks for.  This is useful when there is no multicast route to
+    /// the servers.
+    pub fn set_server(&mut self, server: Option<Ipv6Addr>) {
+        self.server = server;
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(io_error)
+    }
+
+    /// Starts the client and runs it until a socket error occurs.  Messages
+    /// that fail to decode are dropped.
+    pub async fn run(&mut self) -> Result<()> {
+        let out = self.client.start(Instant::now());
+        self.process(out).await?;
+
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let deadline = self.client.poll_timeout();
+            let out = match recv_until(&self.socket, &mut buf, deadline).await? {
+                Some((len, _)) => match ClientMsg::decode(&buf[..len]) {
+                    Ok(msg) => self.client.handle_msg(Instant::now(), &msg),
+                    Err(_) => continue,
+                },
+                None => self.client.handle_timeout(Instant::now()),
+            };
+            self.process(out).await?;
+        }
+    }
+
+    // Sends anything the client asked for, and reports any change to its
+    // lease.
+    async fn process(&mut self, out: Output) -> Result<()> {
+        if let Some(transmit) = out.transmit {
+            let dest = self.server.unwrap_or(transmit.dest);
+            let dest = scoped(
+                SocketAddrV6::new(dest, self.server_port, 0, 0),
+                self.ifindex,
+            );
+            self.socket
+                .send_to(&transmit.msg.encode()?, dest)
+                .await
+                .map_err(io_error)?;
+        }
+
+        let lease = self.client.lease();
+        if lease != self.reported.as_ref() {
+            let event = match lease {
+                Some(lease) => ClientEvent::Bound(lease.clone()),
+                None => ClientEvent::Lost,
+            };
+            // Nobody listening for events is not an error
+            let _ = self.events.send(event);
+            self.reported = lease.cloned();
+        }
+        Ok(())
+    }
+}
+
+/// Runs a `Server` on a UDP socket
+pub struct ServerDriver<A: AddressAllocator> {
+    server: Server<A>,
+    socket: UdpSocket,
+    expire_interval: Duration,
+    events: mpsc::UnboundedSender<LeaseEvent>,
+}
+
+impl<A: AddressAllocator> ServerDriver<A> {
+    /// Binds the server port and joins All_DHCP_Relay_Agents_and_Servers on
+    /// each of the given interfaces.  Returns the driver along with the
+    /// receiving end of its lease event stream.
+    pub async fn new(
+        server: Server<A>,
+        interfaces: &[u32],
+    ) -> Result<(Self, mpsc::UnboundedReceiver<LeaseEvent>)> {
+        ServerDriver::with_port(server, interfaces, params::SERVER_PORT).await
+    }
+
+    /// Binds `port` rather than the standard server port.  A port of 0 binds
+    /// an ephemeral port.
+    pub async fn with_port(
+        server: Server<A>,
+        interfaces: &[u32],
+        port: u16,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<LeaseEvent>)> {
+        let socket = bind(port)?;
+        for ifindex in interfaces {
+            socket
+                .join_multicast_v6(&params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS, *ifindex)
+                .map_err(io_error)?;
+        }
+        let (events, rx) = mpsc::unbounded_channel();
+        let driver = ServerDriver {
+            server,
+            socket,
+            expire_interval: DEFAULT_EXPIRE_INTERVAL,
+            events,
+        };
+        Ok((driver, rx))
+    }
+
+    /// Sets how often expired bindings are returned to the pool
+    pub fn set_expire_interval(&mut self, interval: Duration) {
+        self.expire_interval = interval;
+    }
+
+    pub fn server(&self) -> &Server<A> {
+        &self.server
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(io_error)
+    }
+
+    /// Runs the server until a socket error occurs.  Messages that fail to
+    /// decode are dropped.
+    pub async fn run(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        let mut sweep = Instant::now() + self.expire_interval;
+        loop {
+            match recv_until(&self.socket, &mut buf, Some(sweep)).await? {
+                Some((len, src)) => {
+                    let msg = match Message::decode(&buf[..len]) {
+                        Ok(msg) => msg,
+                        Err(_) => continue,
+                    };
+                    if let Some(reply) = self.server.handle(SystemTime::now(), &msg) {
+                        // Responses go back to wherever the message came
+                        // from: the client itself, or the relay that
+                        // forwarded it.
+                        self.socket
+                            .send_to(&reply.encode()?, src)
+                            .await
+                            .map_err(io_error)?;
+                    }
+                }
+                None => {
+                    self.server.expire(SystemTime::now());
+                    sweep = Instant::now() + self.expire_interval;
+                }
+            }
+
+            for event in self.server.take_events() {
+                let _ = self.events.send(event);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_driver_loopback() {
+    use crate::client::ClientConfig;
+    use crate::pool::{AddressPool, Strategy};
+    use crate::server::ServerConfig;
+    use crate::test::test_duid;
+
+    let pool = AddressPool::new("fd00::".parse().unwrap(), 120, Strategy::Sequential).unwrap();
+    let mut config = ServerConfig::new(test_duid(1));
+    // Skip waiting out the first Solicit retransmission for other servers
+    config.preference = Some(selection::MAX_PREFERENCE);
+    let (mut server, mut server_events) =
+        ServerDriver::with_port(Server::new(config, pool), &[], 0)
+            .await
+            .unwrap();
+    let server_port = server.local_addr().unwrap().port();
+
+    let client = Client::new(ClientConfig::new(test_duid(2), 7));
+    let (mut client, mut client_events) = ClientDriver::with_ports(client, 0, 0, server_port)
+        .await
+        .unwrap();
+    client.set_server(Some(Ipv6Addr::LOCALHOST));
+
+    let bound = async {
+        loop {
+            if let Some(ClientEvent::Bound(lease)) = client_events.recv().await {
+                return lease;
+            }
+        }
+    };
+    // Both drivers are Send, so they can run on tasks of their own
+    let server_task = tokio::spawn(async move { server.run().await });
+    let client_task = tokio::spawn(async move { client.run().await });
+    let lease = time::timeout(Duration::from_secs(10), bound).await.unwrap();
+    assert_eq!(lease.iaid, 7);
+    assert_eq!(lease.server_id, test_duid(1));
+    assert_eq!(lease.addrs[0].addr, "fd00::1".parse::<Ipv6Addr>().unwrap());
+
+    match server_events.recv().await {
+        Some(LeaseEvent::Bound {
+            duid: d,
+            iaid,
+            binding,
+        }) => {
+            assert_eq!(d, test_duid(2));
+            assert_eq!(iaid, 7);
+            assert_eq!(binding.addr, lease.addrs[0].addr);
+        }
+        e => panic!("unexpected event: {:?}", e),
+    }
+    server_task.abort();
+    client_task.abort();
+}
@@ -0,0 +1,663 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Storage for the bindings a server has committed to, so they survive a
+//! restart.
+//!
+//! Bindings are keyed by the client's DUID, the IAID and the type of the IA.
+//! Two stores are provided: one which only keeps bindings in memory, and one
+//! which also records every change in an append-only journal file.
+//!
+//! Each journal record is framed with its length and a CRC-32 of its
+//! contents.  If the server crashes part way through writing a record, the
+//! damaged tail is discarded when the journal is next opened.  The journal
+//! is periodically compacted by writing the live bindings to a new file and
+//! renaming it over the old one.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::buffer::{Buffer, Sink};
+use crate::options::{Duid, OPTION_IA_NA, OPTION_IA_PD, OPTION_IA_TA};
+use crate::server::{Binding, LeaseEvent};
+use crate::*;
+
+/// The types of IA a binding can belong to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IaType {
+    Na,
+    Ta,
+    Pd,
+}
+
+impl From<IaType> for u16 {
+    fn from(ia_type: IaType) -> u16 {
+        match ia_type {
+            IaType::Na => OPTION_IA_NA,
+            IaType::Ta => OPTION_IA_TA,
+            IaType::Pd => OPTION_IA_PD,
+        }
+    }
+}
+
+/// Identifies a single IA belonging to a client
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LeaseKey {
+    pub duid: Duid,
+    pub iaid: u32,
+    pub ia_type: IaType,
+}
+
+/// An address or prefix bound to an IA.  Addresses have a prefix length of
+/// 128.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredLease {
+    pub addr: Ipv6Addr,
+    pub prefix_len: u8,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub expires: SystemTime,
+}
+
+impl From<&Binding> for StoredLease {
+    fn from(b: &Binding) -> StoredLease {
+        StoredLease {
+            addr: b.addr,
+            prefix_len: 128,
+            preferred_lifetime: b.preferred_lifetime,
+            valid_lifetime: b.valid_lifetime,
+            expires: b.expires,
+        }
+    }
+}
+
+/// A place to keep a server's bindings
+pub trait LeaseStore {
+    /// Returns the leases bound to the IA, if any
+    fn get(&self, key: &LeaseKey) -> Option<&[StoredLease]>;
+
+    /// Replaces the leases bound to the IA
+    fn put(&mut self, key: LeaseKey, leases: Vec<StoredLease>) -> Result<()>;
+
+    /// Removes the IA's binding.  Removing a binding that doesn't exist is
+    /// not an error.
+    fn remove(&mut self, key: &LeaseKey) -> Result<()>;
+
+    /// Returns all of the bindings, ordered by key
+    fn entries(&self) -> Box<dyn Iterator<Item = (&LeaseKey, &[StoredLease])> + '_>;
+
+    /// Records a change reported by the server
+    fn apply(&mut self, event: &LeaseEvent) -> Result<()> {
+        let key = |duid: &Duid, iaid: u32| LeaseKey {
+            duid: duid.clone(),
+            iaid,
+            ia_type: IaType::Na,
+        };
+        match event {
+            LeaseEvent::Bound {
+                duid,
+                iaid,
+                binding,
+            } => self.put(key(duid, *iaid), vec![StoredLease::from(binding)]),
+            LeaseEvent::Released { duid, iaid, .. }
+            | LeaseEvent::Declined { duid, iaid, .. }
+            | LeaseEvent::Expired { duid, iaid, .. } => self.remove(&key(duid, *iaid)),
+        }
+    }
+}
+
+/// A lease store which keeps its bindings in memory
+#[derive(Clone, Debug, Default)]
+pub struct MemoryLeaseStore {
+    leases: BTreeMap<LeaseKey, Vec<StoredLease>>,
+}
+
+impl MemoryLeaseStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+}
+
+impl LeaseStore for MemoryLeaseStore {
+    fn get(&self, key: &LeaseKey) -> Option<&[StoredLease]> {
+        self.leases.get(key).map(|l| l.as_slice())
+    }
+
+    fn put(&mut self, key: LeaseKey, leases: Vec<StoredLease>) -> Result<()> {
+        self.leases.insert(key, leases);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &LeaseKey) -> Result<()> {
+        self.leases.remove(key);
+        Ok(())
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&LeaseKey, &[StoredLease])> + '_> {
+        Box::new(self.leases.iter().map(|(k, l)| (k, l.as_slice())))
+    }
+}
+
+/// By default, journal records are compacted away once there are this many
+/// more of them than there are live bindings
+const COMPACT_THRESHOLD: usize = 1024;
+
+const RECORD_PUT: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+
+// Each record is preceded by its length and CRC
+const RECORD_HEADER: usize = 8;
+
+// The CRC-32 used by Ethernet and zlib
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> Error {
+    Error::Other(format!("{}: {}", path.display(), e))
+}
+
+fn encode_key(buf: &mut Vec<u8>, key: &LeaseKey) -> Result<()> {
+    let duid = key.duid.to_bytes();
+    buf.put_16(u16::from(key.ia_type))?;
+    buf.put_32(key.iaid)?;
+    buf.put_16(duid.len() as u16)?;
+    buf.put_bytes(&duid)
+}
+
+fn encode_record(op: u8, key: &LeaseKey, leases: &[StoredLease]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    payload.put_8(op)?;
+    encode_key(&mut payload, key)?;
+    if op == RECORD_PUT {
+        payload.put_16(leases.len() as u16)?;
+        for l in leases {
+            let expires = l
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            payload.put_ipv6addr(&l.addr)?;
+            payload.put_8(l.prefix_len)?;
+            payload.put_32(l.preferred_lifetime)?;
+            payload.put_32(l.valid_lifetime)?;
+            payload.put_64(expires)?;
+        }
+    }
+
+    let mut record = Vec::with_capacity(RECORD_HEADER + payload.len());
+    record.put_32(payload.len() as u32)?;
+    record.put_32(crc32(&payload))?;
+    record.put_bytes(&payload)?;
+    Ok(record)
+}
+
+// Applies a single record's payload to the in-memory copy of the bindings
+fn replay_record(payload: &[u8], leases: &mut MemoryLeaseStore) -> Result<()> {
+    let mut buf = Buffer::new_from_slice(payload);
+    let op = buf.get_8()?;
+    let ia_type = match buf.get_16()? {
+        OPTION_IA_NA => IaType::Na,
+        OPTION_IA_TA => IaType::Ta,
+        OPTION_IA_PD => IaType::Pd,
+        x => return Err(Error::Other(format!("bad IA type in journal: {}", x))),
+    };
+    let iaid = buf.get_32()?;
+    let duid_len = buf.get_16()? as usize;
+    let duid = Duid::from_bytes(buf.get_slice(duid_len)?)?;
+    let key = LeaseKey {
+        duid,
+        iaid,
+        ia_type,
+    };
+
+    match op {
+        RECORD_PUT => {
+            let cnt = buf.get_16()?;
+            let mut list = Vec::with_capacity(cnt as usize);
+            for _ in 0..cnt {
+                list.push(StoredLease {
+                    addr: buf.get_ipv6addr()?,
+                    prefix_len: buf.get_8()?,
+                    preferred_lifetime: buf.get_32()?,
+                    valid_lifetime: buf.get_32()?,
+                    expires: UNIX_EPOCH + Duration::from_secs(buf.get_64()?),
+                });
+            }
+            leases.put(key, list)
+        }
+        RECORD_REMOVE => leases.remove(&key),
+        x => Err(Error::Other(format!("bad journal record type: {}", x))),
+    }
+}
+
+// Replays a journal, returning the bindings it describes, the number of
+// intact records, and the length of the intact prefix of the journal.  A
+// torn or corrupt record ends the intact prefix, but a record which passes
+// its CRC and still can't be applied means the journal can't be trusted, so
+// that is an error rather than something to be cut off.
+fn replay(data: &[u8]) -> Result<(MemoryLeaseStore, usize, usize)> {
+    let mut leases = MemoryLeaseStore::new();
+    let mut records = 0;
+    let mut offset = 0;
+    while data.len() - offset >= RECORD_HEADER {
+        let mut buf = Buffer::new_from_slice(&data[offset..]);
+        let (len, crc) = match (buf.get_32(), buf.get_32()) {
+            (Ok(len), Ok(crc)) => (len as usize, crc),
+            _ => break,
+        };
+        let payload = match buf.get_slice(len) {
+            Ok(payload) if crc32(payload) == crc => payload,
+            _ => break,
+        };
+        if let Err(e) = replay_record(payload, &mut leases) {
+            return Err(Error::Other(format!(
+                "bad journal record at offset {}: {:?}",
+                offset, e
+            )));
+        }
+        records += 1;
+        offset += RECORD_HEADER + len;
+    }
+    Ok((leases, records, offset))
+}
+
+/// A lease store which records every change in an append-only journal file
+#[derive(Debug)]
+pub struct FileLeaseStore {
+    path: PathBuf,
+    file: File,
+    leases: MemoryLeaseStore,
+    // The number of records in the journal
+    records: usize,
+    compact_threshold: usize,
+}
+
+impl FileLeaseStore {
+    /// Opens the journal at the given path, creating it if necessary.  Any
+    /// incomplete or corrupt records at the end of the journal are removed,
+    /// but a record which is intact and can't be understood is an error.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| io_error(&path, e))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| io_error(&path, e))?;
+        let (leases, records, intact) = match replay(&data) {
+            Ok(replayed) => replayed,
+            Err(Error::Other(e)) => return Err(Error::Other(format!("{}: {}", path.display(), e))),
+            Err(e) => return Err(e),
+        };
+        if intact < data.len() {
+            file.set_len(intact as u64)
+                .map_err(|e| io_error(&path, e))?;
+            file.sync_all().map_err(|e| io_error(&path, e))?;
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| io_error(&path, e))?;
+        Ok(FileLeaseStore {
+            path,
+            file,
+            leases,
+            records,
+            compact_threshold: COMPACT_THRESHOLD,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+
+    /// Sets how many more records than live bindings the journal may hold
+    /// before it is compacted
+    pub fn set_compact_threshold(&mut self, threshold: usize) {
+        self.compact_threshold = threshold;
+    }
+
+    // Appends a record to the journal, and waits for it to reach the disk
+    fn append(&mut self, record: &[u8]) -> Result<()> {
+        self.file
+            .write_all(record)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| io_error(&self.path, e))?;
+        self.records += 1;
+        Ok(())
+    }
+
+    // Compacts the journal if it has grown too far past the live bindings.
+    // This must only be called once the bindings are up to date, as they
+    // are all that the compacted journal will hold.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.records > self.leases.len() + self.compact_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the journal so it holds only the live bindings
+    pub fn compact(&mut self) -> Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut data = Vec::new();
+        for (key, leases) in self.leases.entries() {
+            data.extend(encode_record(RECORD_PUT, key, leases)?);
+        }
+        let mut file = File::create(&tmp).map_err(|e| io_error(&tmp, e))?;
+        file.write_all(&data)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| io_error(&tmp, e))?;
+        fs::rename(&tmp, &self.path).map_err(|e| io_error(&self.path, e))?;
+
+        // Make the rename itself durable.  Not every platform allows a
+        // directory to be synced, so this is best effort.
+        if let Some(dir) = self.path.parent() {
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| io_error(&self.path, e))?;
+        self.records = self.leases.len();
+        Ok(())
+    }
+}
+
+impl LeaseStore for FileLeaseStore {
+    fn get(&self, key: &LeaseKey) -> Option<&[StoredLease]> {
+        self.leases.get(key)
+    }
+
+    fn put(&mut self, key: LeaseKey, leases: Vec<StoredLease>) -> Result<()> {
+        self.append(&encode_record(RECORD_PUT, &key, &leases)?)?;
+        self.leases.put(key, leases)?;
+        self.maybe_compact()
+    }
+
+    fn remove(&mut self, key: &LeaseKey) -> Result<()> {
+        if self.leases.get(key).is_none() {
+            return Ok(());
+        }
+        self.append(&encode_record(RECORD_REMOVE, key, &[])?)?;
+        self.leases.remove(key)?;
+        self.maybe_compact()
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&LeaseKey, &[StoredLease])> + '_> {
+        self.leases.entries()
+    }
+}
+
+#[cfg(test)]
+use crate::test::{test_client_msg, test_duid};
+
+#[cfg(test)]
+fn test_key(id: u8, iaid: u32) -> LeaseKey {
+    LeaseKey {
+        duid: test_duid(id.into()),
+        iaid,
+        ia_type: IaType::Na,
+    }
+}
+
+#[cfg(test)]
+fn test_lease(addr: &str) -> StoredLease {
+    StoredLease {
+        addr: addr.parse().unwrap(),
+        prefix_len: 128,
+        preferred_lifetime: 3600,
+        valid_lifetime: 7200,
+        expires: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+    }
+}
+
+// Returns a journal path unique to the test
+#[cfg(test)]
+fn test_journal(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dhcpv6-{}-{}.journal", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+}
+
+#[test]
+fn test_memory_store() {
+    let mut store = MemoryLeaseStore::new();
+    store
+        .put(test_key(2, 1), vec![test_lease("fd00::2")])
+        .unwrap();
+    store
+        .put(test_key(1, 1), vec![test_lease("fd00::1")])
+        .unwrap();
+    let mut pd = test_key(1, 1);
+    pd.ia_type = IaType::Pd;
+    let mut prefix = test_lease("2001:db8:1::");
+    prefix.prefix_len = 56;
+    store.put(pd.clone(), vec![prefix.clone()]).unwrap();
+
+    // Entries are ordered by DUID, then IAID, then IA type
+    let keys: Vec<LeaseKey> = store.entries().map(|(k, _)| k.clone()).collect();
+    assert_eq!(keys, vec![test_key(1, 1), pd.clone(), test_key(2, 1)]);
+    assert_eq!(store.get(&pd), Some(&[prefix][..]));
+
+    store.remove(&test_key(2, 1)).unwrap();
+    store.remove(&test_key(2, 1)).unwrap();
+    assert_eq!(store.len(), 2);
+}
+
+#[test]
+fn test_file_store() {
+    let path = test_journal("store");
+    {
+        let mut store = FileLeaseStore::open(&path).unwrap();
+        store
+            .put(test_key(1, 1), vec![test_lease("fd00::1")])
+            .unwrap();
+        store
+            .put(test_key(2, 1), vec![test_lease("fd00::2")])
+            .unwrap();
+        store
+            .put(test_key(1, 1), vec![test_lease("fd00::3")])
+            .unwrap();
+        store.remove(&test_key(2, 1)).unwrap();
+    }
+
+    // The journal is replayed when the store is reopened
+    let mut store = FileLeaseStore::open(&path).unwrap();
+    assert_eq!(store.len(), 1);
+    assert_eq!(
+        store.get(&test_key(1, 1)),
+        Some(&[test_lease("fd00::3")][..])
+    );
+
+    // Compaction leaves just the one live binding
+    let before = fs::metadata(&path).unwrap().len();
+    store.compact().unwrap();
+    let after = fs::metadata(&path).unwrap().len();
+    assert!(after < before);
+    store
+        .put(test_key(3, 7), vec![test_lease("fd00::7")])
+        .unwrap();
+    drop(store);
+
+    let store = FileLeaseStore::open(&path).unwrap();
+    assert_eq!(store.len(), 2);
+    assert_eq!(
+        store.get(&test_key(3, 7)),
+        Some(&[test_lease("fd00::7")][..])
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_file_store_recovery() {
+    let path = test_journal("recovery");
+    {
+        let mut store = FileLeaseStore::open(&path).unwrap();
+        store
+            .put(test_key(1, 1), vec![test_lease("fd00::1")])
+            .unwrap();
+        store
+            .put(test_key(2, 1), vec![test_lease("fd00::2")])
+            .unwrap();
+    }
+
+    // Simulate a crash part way through writing the second record
+    let len = fs::metadata(&path).unwrap().len();
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len - 5).unwrap();
+    drop(file);
+
+    let mut store = FileLeaseStore::open(&path).unwrap();
+    assert_eq!(store.len(), 1);
+    assert!(store.get(&test_key(1, 1)).is_some());
+    assert!(store.get(&test_key(2, 1)).is_none());
+
+    // The damaged tail was cut off, so new records can follow the good ones
+    store
+        .put(test_key(3, 1), vec![test_lease("fd00::3")])
+        .unwrap();
+    drop(store);
+
+    // A corrupted record is treated the same way
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&path, &data).unwrap();
+    let store = FileLeaseStore::open(&path).unwrap();
+    assert_eq!(store.len(), 1);
+    assert!(store.get(&test_key(3, 1)).is_none());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_file_store_bad_record() {
+    let path = test_journal("bad-record");
+    {
+        let mut store = FileLeaseStore::open(&path).unwrap();
+        store
+            .put(test_key(1, 1), vec![test_lease("fd00::1")])
+            .unwrap();
+    }
+
+    // A record with a valid CRC, but an unknown type, followed by a good one
+    let mut data = fs::read(&path).unwrap();
+    let payload = [0xffu8, 0x00, 0x03];
+    data.put_32(payload.len() as u32).unwrap();
+    data.put_32(crc32(&payload)).unwrap();
+    data.put_bytes(&payload).unwrap();
+    data.extend_from_slice(&encode_record(RECORD_REMOVE, &test_key(1, 1), &[]).unwrap());
+    fs::write(&path, &data).unwrap();
+
+    // The journal is left alone rather than losing the records after it
+    assert!(FileLeaseStore::open(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), data);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_file_store_compaction() {
+    let path = test_journal("compaction");
+    {
+        let mut store = FileLeaseStore::open(&path).unwrap();
+        store.set_compact_threshold(2);
+
+        // Each of these crosses the threshold, so the change that triggers
+        // compaction has to be in the compacted journal
+        for id in 1..=5 {
+            store
+                .put(test_key(id, 1), vec![test_lease("fd00::1")])
+                .unwrap();
+            store
+                .put(test_key(id, 1), vec![test_lease("fd00::2")])
+                .unwrap();
+            assert!(store.records <= store.len() + 2);
+        }
+        store.remove(&test_key(1, 1)).unwrap();
+        store.remove(&test_key(2, 1)).unwrap();
+        store.remove(&test_key(3, 1)).unwrap();
+        assert_eq!(store.records, store.len());
+    }
+
+    let store = FileLeaseStore::open(&path).unwrap();
+    let keys: Vec<LeaseKey> = store.entries().map(|(k, _)| k.clone()).collect();
+    assert_eq!(keys, vec![test_key(4, 1), test_key(5, 1)]);
+    assert_eq!(
+        store.get(&test_key(5, 1)),
+        Some(&[test_lease("fd00::2")][..])
+    );
+    assert_eq!(store.records, 2);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_server_restore() {
+    use crate::pool::{AddressPool, Strategy};
+    use crate::server::{Server, ServerConfig};
+
+    let mut store = MemoryLeaseStore::new();
+    let duid = test_key(100, 0).duid;
+    let pool = AddressPool::new("fd00::".parse().unwrap(), 120, Strategy::Sequential).unwrap();
+    let mut server = Server::new(ServerConfig::new(duid.clone()), pool);
+    let now = SystemTime::now();
+
+    // Bind an address, and record it in the store
+    let key = test_key(1, 1);
+    let request = test_client_msg(MsgType::Request, 1, Some(&duid), &[1]);
+    server.handle_client_msg(now, &request).unwrap();
+    for event in server.take_events() {
+        store.apply(&event).unwrap();
+    }
+    let addr = store.get(&key).unwrap()[0].addr;
+
+    // A new server picks up where the old one left off
+    let pool = AddressPool::new("fd00::".parse().unwrap(), 120, Strategy::Sequential).unwrap();
+    let mut server = Server::new(ServerConfig::new(duid), pool);
+    assert_eq!(server.restore(now, &store), 1);
+    assert_eq!(server.binding(&key.duid, 1).unwrap().addr, addr);
+    assert!(server.allocator().is_in_use(addr));
+}
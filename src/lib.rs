@@ -3,21 +3,64 @@
 use std::convert::{From, TryFrom};
 use std::fmt;
 use std::net::Ipv6Addr;
+use std::time::Instant;
 
+use buffer::{BufferMut, Counter, Sink};
+
+pub mod auth;
 mod buffer;
+pub mod client;
+#[cfg(feature = "tokio")]
+pub mod driver;
+pub mod lease;
 pub mod options;
 pub mod params;
+pub mod pool;
+pub mod prefix_pool;
+pub mod relay;
+pub mod retransmit;
+pub mod selection;
+pub mod server;
+pub mod stateless;
 #[cfg(test)]
 mod test;
+#[cfg(feature = "transport")]
+pub mod transport;
+pub mod view;
 
 type Result<T> = std::result::Result<T, Error>;
 
+// Runs an encoder over a fixed-size output buffer, returning the number of
+// bytes used.  If the buffer fills up, the error reports how much space the
+// encoding actually needs.
+fn encode_into_slice(
+    out: &mut [u8],
+    encode: impl FnOnce(&mut BufferMut) -> Result<()>,
+    encoded_len: impl FnOnce() -> Result<usize>,
+) -> Result<usize> {
+    let mut buf = BufferMut::new_from_slice(out);
+    match encode(&mut buf) {
+        Ok(()) => Ok(buf.offset()),
+        Err(Error::TooShort) => Err(Error::BufferTooSmall(encoded_len()?)),
+        Err(e) => Err(e),
+    }
+}
+
+// Runs an encoder over a Counter, to determine how large the encoded data is
+fn count_encoded(encode: impl FnOnce(&mut Counter) -> Result<()>) -> Result<usize> {
+    let mut counter = Counter::default();
+    encode(&mut counter)?;
+    Ok(counter.offset())
+}
+
 #[derive(PartialEq)]
 pub enum Error {
     UnknownMsgCode(u8),
     BadOption(String),
     Unimplemented(String),
+    AuthFailed(String),
     TooShort,
+    BufferTooSmall(usize),
     Other(String),
 }
 
@@ -30,7 +73,9 @@ impl fmt::Debug for Error {
                 Error::UnknownMsgCode(code) => format!("Unknown message code: '{}'", code),
                 Error::BadOption(option) => format!("Bad option: '{}'", option),
                 Error::Unimplemented(x) => format!("Unimplemented functionality: '{}'", x),
+                Error::AuthFailed(x) => format!("Authentication failed: '{}'", x),
                 Error::TooShort => "buffer too short".to_string(),
+                Error::BufferTooSmall(x) => format!("output buffer too small: {} bytes needed", x),
                 Error::Other(x) => x.to_string(),
             }
         )
@@ -53,8 +98,41 @@ impl Into<Error> for &str {
     }
 }
 
-/// All of the DHCPv6 message types defined in rfc3315
-#[derive(Copy, Clone, PartialEq)]
+/// A source of the current time.  Tests can supply a fake clock, while a
+/// sans-IO caller can simply pass the Instant at which an event occurred.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl Clock for Instant {
+    fn now(&self) -> Instant {
+        *self
+    }
+}
+
+/// All of the DHCPv6 message types registered with IANA.  Types that aren't
+/// recognized are preserved, so they can be displayed and re-encoded.
+///
+/// `Unknown` is only meant to hold codes which have no variant of their own,
+/// which is all `from_code` will put in it.  A hand-built `Unknown` holding a
+/// registered code still compares equal to that code's variant.
+///
+/// Because `Unknown` carries data, a message type can't be converted with
+/// `as`: `MsgType::Solicit as u8` doesn't compile.  Use `u8::from`, which
+/// also handles `Unknown`.  The discriminants only document the codes on the
+/// wire.
+#[repr(u8)]
+#[derive(Copy, Clone)]
 pub enum MsgType {
     Solicit = 1,
     Advertise = 2,
@@ -69,63 +147,193 @@ pub enum MsgType {
     InformationRequest = 11,
     RelayForw = 12,
     RelayRepl = 13,
+    LeaseQuery = 14,
+    LeaseQueryReply = 15,
+    LeaseQueryDone = 16,
+    LeaseQueryData = 17,
+    ReconfigureRequest = 18,
+    ReconfigureReply = 19,
+    Dhcpv4Query = 20,
+    Dhcpv4Response = 21,
+    ActiveLeaseQuery = 22,
+    StartTls = 23,
+    BndUpd = 24,
+    BndReply = 25,
+    PoolReq = 26,
+    PoolResp = 27,
+    UpdReq = 28,
+    UpdReqAll = 29,
+    UpdDone = 30,
+    Connect = 31,
+    ConnectReply = 32,
+    Disconnect = 33,
+    State = 34,
+    Contact = 35,
+    AddrRegInform = 36,
+    AddrRegReply = 37,
+    Unknown(u8),
+}
+
+impl PartialEq for MsgType {
+    fn eq(&self, other: &Self) -> bool {
+        u8::from(*self) == u8::from(*other)
+    }
 }
 
 impl fmt::Debug for MsgType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                MsgType::Solicit => "solicit",
-                MsgType::Advertise => "advertise",
-                MsgType::Request => "request",
-                MsgType::Confirm => "confirm",
-                MsgType::Renew => "renew",
-                MsgType::Rebind => "rebind",
-                MsgType::Reply => "reply",
-                MsgType::Release => "release",
-                MsgType::Decline => "decline",
-                MsgType::Reconfigure => "reconfigure",
-                MsgType::InformationRequest => "infoRequest",
-                MsgType::RelayForw => "relayForw",
-                MsgType::RelayRepl => "relayReply",
-            }
-        )
+        let name = match self {
+            MsgType::Solicit => "solicit",
+            MsgType::Advertise => "advertise",
+            MsgType::Request => "request",
+            MsgType::Confirm => "confirm",
+            MsgType::Renew => "renew",
+            MsgType::Rebind => "rebind",
+            MsgType::Reply => "reply",
+            MsgType::Release => "release",
+            MsgType::Decline => "decline",
+            MsgType::Reconfigure => "reconfigure",
+            MsgType::InformationRequest => "infoRequest",
+            MsgType::RelayForw => "relayForw",
+            MsgType::RelayRepl => "relayReply",
+            MsgType::LeaseQuery => "leaseQuery",
+            MsgType::LeaseQueryReply => "leaseQueryReply",
+            MsgType::LeaseQueryDone => "leaseQueryDone",
+            MsgType::LeaseQueryData => "leaseQueryData",
+            MsgType::ReconfigureRequest => "reconfigureRequest",
+            MsgType::ReconfigureReply => "reconfigureReply",
+            MsgType::Dhcpv4Query => "dhcpv4Query",
+            MsgType::Dhcpv4Response => "dhcpv4Response",
+            MsgType::ActiveLeaseQuery => "activeLeaseQuery",
+            MsgType::StartTls => "startTls",
+            MsgType::BndUpd => "bndUpd",
+            MsgType::BndReply => "bndReply",
+            MsgType::PoolReq => "poolReq",
+            MsgType::PoolResp => "poolResp",
+            MsgType::UpdReq => "updReq",
+            MsgType::UpdReqAll => "updReqAll",
+            MsgType::UpdDone => "updDone",
+            MsgType::Connect => "connect",
+            MsgType::ConnectReply => "connectReply",
+            MsgType::Disconnect => "disconnect",
+            MsgType::State => "state",
+            MsgType::Contact => "contact",
+            MsgType::AddrRegInform => "addrRegInform",
+            MsgType::AddrRegReply => "addrRegReply",
+            MsgType::Unknown(code) => return write!(f, "unknown({})", code),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl MsgType {
+    /// Converts any message type, registered or not.  Types without a variant
+    /// of their own become `Unknown`.
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => MsgType::Solicit,
+            2 => MsgType::Advertise,
+            3 => MsgType::Request,
+            4 => MsgType::Confirm,
+            5 => MsgType::Renew,
+            6 => MsgType::Rebind,
+            7 => MsgType::Reply,
+            8 => MsgType::Release,
+            9 => MsgType::Decline,
+            10 => MsgType::Reconfigure,
+            11 => MsgType::InformationRequest,
+            12 => MsgType::RelayForw,
+            13 => MsgType::RelayRepl,
+            14 => MsgType::LeaseQuery,
+            15 => MsgType::LeaseQueryReply,
+            16 => MsgType::LeaseQueryDone,
+            17 => MsgType::LeaseQueryData,
+            18 => MsgType::ReconfigureRequest,
+            19 => MsgType::ReconfigureReply,
+            20 => MsgType::Dhcpv4Query,
+            21 => MsgType::Dhcpv4Response,
+            22 => MsgType::ActiveLeaseQuery,
+            23 => MsgType::StartTls,
+            24 => MsgType::BndUpd,
+            25 => MsgType::BndReply,
+            26 => MsgType::PoolReq,
+            27 => MsgType::PoolResp,
+            28 => MsgType::UpdReq,
+            29 => MsgType::UpdReqAll,
+            30 => MsgType::UpdDone,
+            31 => MsgType::Connect,
+            32 => MsgType::ConnectReply,
+            33 => MsgType::Disconnect,
+            34 => MsgType::State,
+            35 => MsgType::Contact,
+            36 => MsgType::AddrRegInform,
+            37 => MsgType::AddrRegReply,
+            _ => MsgType::Unknown(code),
+        }
     }
 }
 
 impl TryFrom<u8> for MsgType {
     type Error = ();
 
+    /// Converts a registered message type, failing for any type which has no
+    /// variant of its own
     fn try_from(code: u8) -> std::result::Result<Self, Self::Error> {
-        match code {
-            1 => Ok(MsgType::Solicit),
-            2 => Ok(MsgType::Advertise),
-            3 => Ok(MsgType::Request),
-            4 => Ok(MsgType::Confirm),
-            5 => Ok(MsgType::Renew),
-            6 => Ok(MsgType::Rebind),
-            7 => Ok(MsgType::Reply),
-            8 => Ok(MsgType::Release),
-            9 => Ok(MsgType::Decline),
-            10 => Ok(MsgType::Reconfigure),
-            11 => Ok(MsgType::InformationRequest),
-            12 => Ok(MsgType::RelayForw),
-            13 => Ok(MsgType::RelayRepl),
-            _ => Err(()),
+        match MsgType::from_code(code) {
+            MsgType::Unknown(_) => Err(()),
+            known => Ok(known),
         }
     }
 }
 
 impl From<MsgType> for u8 {
     fn from(msg: MsgType) -> u8 {
-        msg as u8
+        match msg {
+            MsgType::Solicit => 1,
+            MsgType::Advertise => 2,
+            MsgType::Request => 3,
+            MsgType::Confirm => 4,
+            MsgType::Renew => 5,
+            MsgType::Rebind => 6,
+            MsgType::Reply => 7,
+            MsgType::Release => 8,
+            MsgType::Decline => 9,
+            MsgType::Reconfigure => 10,
+            MsgType::InformationRequest => 11,
+            MsgType::RelayForw => 12,
+            MsgType::RelayRepl => 13,
+            MsgType::LeaseQuery => 14,
+            MsgType::LeaseQueryReply => 15,
+            MsgType::LeaseQueryDone => 16,
+            MsgType::LeaseQueryData => 17,
+            MsgType::ReconfigureRequest => 18,
+            MsgType::ReconfigureReply => 19,
+            MsgType::Dhcpv4Query => 20,
+            MsgType::Dhcpv4Response => 21,
+            MsgType::ActiveLeaseQuery => 22,
+            MsgType::StartTls => 23,
+            MsgType::BndUpd => 24,
+            MsgType::BndReply => 25,
+            MsgType::PoolReq => 26,
+            MsgType::PoolResp => 27,
+            MsgType::UpdReq => 28,
+            MsgType::UpdReqAll => 29,
+            MsgType::UpdDone => 30,
+            MsgType::Connect => 31,
+            MsgType::ConnectReply => 32,
+            MsgType::Disconnect => 33,
+            MsgType::State => 34,
+            MsgType::Contact => 35,
+            MsgType::AddrRegInform => 36,
+            MsgType::AddrRegReply => 37,
+            MsgType::Unknown(code) => code,
+        }
     }
 }
 
 /// RFC-defined parameters governing how frequently, and for how long, a
 /// message should be retransmitted before accepting that no one is listening.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RetransmitParams {
     pub irt: u32, // Initial retransmission time
     pub mrt: u32, // Maximum retransmission time
@@ -191,12 +399,35 @@ pub fn retransmit_params(msg_type: MsgType) -> Option<RetransmitParams> {
             mrc: 0,
             mrd: 0,
         }),
+        MsgType::Reconfigure => Some(RetransmitParams {
+            irt: params::REC_TIMEOUT,
+            mrt: 0,
+            mrc: params::REC_MAX_RC,
+            mrd: 0,
+        }),
+        MsgType::LeaseQuery => Some(RetransmitParams {
+            irt: params::LQ_QUERY_TIMEOUT,
+            mrt: params::LQ_MAX_RT,
+            mrc: params::LQ_MAX_RC,
+            mrd: 0,
+        }),
         _ => None,
     }
 }
 
-/// All of the DHCPv6 status codes defined in rfc3315
-#[derive(PartialEq, Clone, Copy)]
+/// All of the DHCPv6 status codes registered with IANA.  Codes that aren't
+/// recognized are preserved, so they can be re-encoded unchanged.
+///
+/// `Unknown` is only meant to hold codes which have no variant of their own,
+/// which is all `from_code` will put in it.  A hand-built `Unknown` holding a
+/// registered code still compares equal to that code's variant.
+///
+/// Because `Unknown` carries data, a status code can't be converted with
+/// `as`: `StatusCode::NoBinding as u16` doesn't compile.  Use `u16::from`,
+/// which also handles `Unknown`.  The discriminants only document the codes
+/// on the wire.
+#[repr(u16)]
+#[derive(Clone, Copy)]
 pub enum StatusCode {
     Success = 0,
     UnspecFail = 1,
@@ -204,38 +435,138 @@ pub enum StatusCode {
     NoBinding = 3,
     NotOnLink = 4,
     UseMulticast = 5,
+    NoPrefixAvail = 6,
+    UnknownQueryType = 7,
+    MalformedQuery = 8,
+    NotConfigured = 9,
+    NotAllowed = 10,
+    QueryTerminated = 11,
+    DataMissing = 12,
+    CatchUpComplete = 13,
+    NotSupported = 14,
+    TlsConnectionRefused = 15,
+    AddressInUse = 16,
+    ConfigurationConflict = 17,
+    MissingBindingInformation = 18,
+    OutdatedBindingInformation = 19,
+    ServerShuttingDown = 20,
+    DnsUpdateNotSupported = 21,
+    ExcessiveTimeSkew = 22,
+    Unknown(u16),
+}
+
+impl PartialEq for StatusCode {
+    fn eq(&self, other: &Self) -> bool {
+        u16::from(*self) == u16::from(*other)
+    }
+}
+
+impl StatusCode {
+    /// Converts any status code, registered or not.  Codes without a variant
+    /// of their own become `Unknown`.
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            0 => StatusCode::Success,
+            1 => StatusCode::UnspecFail,
+            2 => StatusCode::NoAddrsAvail,
+            3 => StatusCode::NoBinding,
+            4 => StatusCode::NotOnLink,
+            5 => StatusCode::UseMulticast,
+            6 => StatusCode::NoPrefixAvail,
+            7 => StatusCode::UnknownQueryType,
+            8 => StatusCode::MalformedQuery,
+            9 => StatusCode::NotConfigured,
+            10 => StatusCode::NotAllowed,
+            11 => StatusCode::QueryTerminated,
+            12 => StatusCode::DataMissing,
+            13 => StatusCode::CatchUpComplete,
+            14 => StatusCode::NotSupported,
+            15 => StatusCode::TlsConnectionRefused,
+            16 => StatusCode::AddressInUse,
+            17 => StatusCode::ConfigurationConflict,
+            18 => StatusCode::MissingBindingInformation,
+            19 => StatusCode::OutdatedBindingInformation,
+            20 => StatusCode::ServerShuttingDown,
+            21 => StatusCode::DnsUpdateNotSupported,
+            22 => StatusCode::ExcessiveTimeSkew,
+            x => StatusCode::Unknown(x),
+        }
+    }
 }
 
 impl TryFrom<u16> for StatusCode {
     type Error = ();
 
+    /// Converts a registered status code, failing for any code which has no
+    /// variant of its own
     fn try_from(code: u16) -> std::result::Result<Self, Self::Error> {
+        match StatusCode::from_code(code) {
+            StatusCode::Unknown(_) => Err(()),
+            known => Ok(known),
+        }
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(code: StatusCode) -> u16 {
         match code {
-            0 => Ok(StatusCode::Success),
-            1 => Ok(StatusCode::UnspecFail),
-            2 => Ok(StatusCode::NoAddrsAvail),
-            3 => Ok(StatusCode::NoBinding),
-            4 => Ok(StatusCode::NotOnLink),
-            5 => Ok(StatusCode::UseMulticast),
-            _ => Err(()),
+            StatusCode::Success => 0,
+            StatusCode::UnspecFail => 1,
+            StatusCode::NoAddrsAvail => 2,
+            StatusCode::NoBinding => 3,
+            StatusCode::NotOnLink => 4,
+            StatusCode::UseMulticast => 5,
+            StatusCode::NoPrefixAvail => 6,
+            StatusCode::UnknownQueryType => 7,
+            StatusCode::MalformedQuery => 8,
+            StatusCode::NotConfigured => 9,
+            StatusCode::NotAllowed => 10,
+            StatusCode::QueryTerminated => 11,
+            StatusCode::DataMissing => 12,
+            StatusCode::CatchUpComplete => 13,
+            StatusCode::NotSupported => 14,
+            StatusCode::TlsConnectionRefused => 15,
+            StatusCode::AddressInUse => 16,
+            StatusCode::ConfigurationConflict => 17,
+            StatusCode::MissingBindingInformation => 18,
+            StatusCode::OutdatedBindingInformation => 19,
+            StatusCode::ServerShuttingDown => 20,
+            StatusCode::DnsUpdateNotSupported => 21,
+            StatusCode::ExcessiveTimeSkew => 22,
+            StatusCode::Unknown(x) => x,
         }
     }
 }
 
 impl fmt::Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                StatusCode::Success => "Success",
-                StatusCode::UnspecFail => "UnspecFail",
-                StatusCode::NoAddrsAvail => "noAddrsAvail",
-                StatusCode::NoBinding => "NoBinding",
-                StatusCode::NotOnLink => "NotOnLink",
-                StatusCode::UseMulticast => "UseMulticast",
-            }
-        )
+        let name = match self {
+            StatusCode::Success => "Success",
+            StatusCode::UnspecFail => "UnspecFail",
+            StatusCode::NoAddrsAvail => "noAddrsAvail",
+            StatusCode::NoBinding => "NoBinding",
+            StatusCode::NotOnLink => "NotOnLink",
+            StatusCode::UseMulticast => "UseMulticast",
+            StatusCode::NoPrefixAvail => "NoPrefixAvail",
+            StatusCode::UnknownQueryType => "UnknownQueryType",
+            StatusCode::MalformedQuery => "MalformedQuery",
+            StatusCode::NotConfigured => "NotConfigured",
+            StatusCode::NotAllowed => "NotAllowed",
+            StatusCode::QueryTerminated => "QueryTerminated",
+            StatusCode::DataMissing => "DataMissing",
+            StatusCode::CatchUpComplete => "CatchUpComplete",
+            StatusCode::NotSupported => "NotSupported",
+            StatusCode::TlsConnectionRefused => "TLSConnectionRefused",
+            StatusCode::AddressInUse => "AddressInUse",
+            StatusCode::ConfigurationConflict => "ConfigurationConflict",
+            StatusCode::MissingBindingInformation => "MissingBindingInformation",
+            StatusCode::OutdatedBindingInformation => "OutdatedBindingInformation",
+            StatusCode::ServerShuttingDown => "ServerShuttingDown",
+            StatusCode::DnsUpdateNotSupported => "DNSUpdateNotSupported",
+            StatusCode::ExcessiveTimeSkew => "ExcessiveTimeSkew",
+            StatusCode::Unknown(x) => return write!(f, "Unknown({})", x),
+        };
+        write!(f, "{}", name)
     }
 }
 
@@ -246,7 +577,7 @@ impl fmt::Debug for StatusCode {
 }
 
 /// Client-Server DHCPv6 message as defined in rfc3315, section 6.
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct ClientMsg {
     pub msg_type: MsgType,
     pub tx_id: u32,
@@ -305,7 +636,7 @@ impl ClientMsg {
         let mut buf = buffer::Buffer::new_from_slice(buf);
 
         let code = buf.get_8()?;
-        let msg_type = MsgType::try_from(code).map_err(|_| Error::UnknownMsgCode(code))?;
+        let msg_type = MsgType::from_code(code);
         let tx_id = buf.get_24()?;
         let options = options::parse_options(&mut buf)?;
         Ok(ClientMsg {
@@ -315,18 +646,45 @@ impl ClientMsg {
         })
     }
 
+    /// Decodes a message as `decode` does, then discards any IAs, addresses
+    /// and prefixes whose timers or lifetimes are inconsistent, as a client
+    /// is required to.
+    pub fn decode_strict(buf: &[u8]) -> Result<ClientMsg> {
+        let mut msg = ClientMsg::decode(buf)?;
+        options::discard_invalid(&mut msg.options);
+        Ok(msg)
+    }
+
+    fn encode_to<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_8(u8::from(self.msg_type))?;
+        buf.put_24(self.tx_id)?;
+        options::encode_options_into(&self.options, buf)
+    }
+
     // Deparses the provided client message into a DHCPv6 packet
     pub fn encode(&self) -> Result<Vec<u8>> {
         let mut buf = Vec::with_capacity(2048);
-
-        buf.push(self.msg_type as u8);
-        buf.push(((self.tx_id >> 16) & 0xff) as u8);
-        buf.push(((self.tx_id >> 8) & 0xff) as u8);
-        buf.push((self.tx_id & 0xff) as u8);
-        buf.extend_from_slice(&options::encode_options(&self.options)?);
+        self.encode_to(&mut buf)?;
         Ok(buf)
     }
 
+    /// Deparses the message directly into the provided buffer, returning the
+    /// number of bytes used.  If the buffer is too small, the error reports
+    /// the number of bytes needed.
+    pub fn encode_into(&self, out: &mut [u8]) -> Result<usize> {
+        encode_into_slice(out, |buf| self.encode_to(buf), || self.encoded_len())
+    }
+
+    /// Deparses the message onto the end of the provided buffer
+    pub fn encode_append(&self, out: &mut Vec<u8>) -> Result<()> {
+        self.encode_to(out)
+    }
+
+    /// Returns the number of bytes needed to hold the deparsed message
+    pub fn encoded_len(&self) -> Result<usize> {
+        count_encoded(|buf| self.encode_to(buf))
+    }
+
     /// Find the first option of the given type in the message's option list
     pub fn find_one_option(&self, opt_type: u16) -> Option<&options::Dhcpv6Option> {
         find_one_option(&self.options, opt_type)
@@ -344,6 +702,7 @@ impl ClientMsg {
 }
 
 /// RelayMessage as defined in rfc3315, section 6
+#[derive(Clone, PartialEq)]
 pub struct RelayMsg {
     pub msg_type: MsgType,
     pub hop_count: u8,
@@ -352,15 +711,304 @@ pub struct RelayMsg {
     pub option: Vec<options::Dhcpv6Option>,
 }
 
+impl fmt::Debug for RelayMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "type: {:?}  hops: {}  link: {}  peer: {}  options: {:?}",
+            self.msg_type, self.hop_count, self.link_addr, self.peer_addr, self.option
+        )
+    }
+}
+
+impl fmt::Display for RelayMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}  hops: {}  peer: {}",
+            self.msg_type, self.hop_count, self.peer_addr
+        )
+    }
+}
+
 impl RelayMsg {
     // Attempts to parse the contents of the provided buffer, and returns
     // the RelayMsg encoded within.
-    pub fn decode(_buf: &[u8]) -> Result<RelayMsg> {
-        Err(Error::Unimplemented("RelayMsg decode".to_string()))
+    pub fn decode(buf: &[u8]) -> Result<RelayMsg> {
+        let mut buf = buffer::Buffer::new_from_slice(buf);
+
+        let code = buf.get_8()?;
+        let msg_type = match MsgType::from_code(code) {
+            t @ (MsgType::RelayForw | MsgType::RelayRepl) => t,
+            _ => return Err(Error::UnknownMsgCode(code)),
+        };
+        let hop_count = buf.get_8()?;
+        let link_addr = buf.get_ipv6addr()?;
+        let peer_addr = buf.get_ipv6addr()?;
+        let option = options::parse_options(&mut buf)?;
+        Ok(RelayMsg {
+            msg_type,
+            hop_count,
+            link_addr,
+            peer_addr,
+            option,
+        })
+    }
+
+    fn encode_header_to<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_8(u8::from(self.msg_type))?;
+        buf.put_8(self.hop_count)?;
+        buf.put_ipv6addr(&self.link_addr)?;
+        buf.put_ipv6addr(&self.peer_addr)
+    }
+
+    fn encode_to<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        self.encode_header_to(buf)?;
+        options::encode_options_into(&self.option, buf)
     }
 
     // Deparses the provided relay message into a DHCPv6 packet
-    pub fn encode(_msg: &RelayMsg) -> Result<Vec<u8>> {
-        Err(Error::Unimplemented("RelayMsg encode".to_string()))
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(2048);
+        self.encode_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Deparses the message directly into the provided buffer, returning the
+    /// number of bytes used.  If the buffer is too small, the error reports
+    /// the number of bytes needed.
+    pub fn encode_into(&self, out: &mut [u8]) -> Result<usize> {
+        encode_into_slice(out, |buf| self.encode_to(buf), || self.encoded_len())
+    }
+
+    /// Returns the number of bytes needed to hold the deparsed message
+    pub fn encoded_len(&self) -> Result<usize> {
+        count_encoded(|buf| self.encode_to(buf))
+    }
+
+    // Removes the OPTION_RELAY_MSG from the option list, and returns the
+    // message it carried.
+    fn take_payload(&mut self) -> Result<Vec<u8>> {
+        self.take_payload_at().map(|(_, payload)| payload)
+    }
+
+    // As take_payload, but also returns the position in the option list at
+    // which the OPTION_RELAY_MSG appeared.
+    fn take_payload_at(&mut self) -> Result<(usize, Vec<u8>)> {
+        let idx = self
+            .option
+            .iter()
+            .position(|o| u16::from(o) == options::OPTION_RELAY_MSG)
+            .ok_or_else(|| Error::BadOption("relay message has no payload".to_string()))?;
+        match self.option.remove(idx) {
+            options::Dhcpv6Option::RelayMsg(payload) => Ok((idx, payload)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Find the first option of the given type in the message's option list
+    pub fn find_one_option(&self, opt_type: u16) -> Option<&options::Dhcpv6Option> {
+        find_one_option(&self.option, opt_type)
+    }
+
+    /// Find all options of the given type in the message's option list
+    pub fn find_all_options(&self, opt_type: u16) -> Vec<&options::Dhcpv6Option> {
+        find_all_options(&self.option, opt_type)
+    }
+
+    /// Returns 'true' iff the message's option list contains an option of the given type
+    pub fn has_option(&self, opt_type: u16) -> bool {
+        self.option.iter().any(|o| opt_type == u16::from(o))
+    }
+}
+
+/// A client message, along with the relay agents it passed through on its way
+/// to or from the server.
+///
+/// The relays are ordered from the outermost (i.e., the one closest to the
+/// server) to the innermost (the one closest to the client).  The
+/// OPTION_RELAY_MSG has been removed from each relay's option list, as its
+/// payload has been decoded into the next relay or into the client message.
+#[derive(Debug, PartialEq)]
+pub struct RelayChain {
+    pub relays: Vec<RelayMsg>,
+    pub msg: ClientMsg,
+    /// For each relay, the position in its option list at which the
+    /// OPTION_RELAY_MSG appeared, so the relay is re-encoded with its options
+    /// in their original order.  A relay without an entry, or whose entry is
+    /// past the end of its options, has the payload appended.
+    pub payload_at: Vec<usize>,
+}
+
+impl RelayChain {
+    // The layers are written from the outside in.  Each relay's options up
+    // to its OPTION_RELAY_MSG are written, then the payload, and the
+    // OPTION_RELAY_MSG length is backfilled once the payload is complete.
+    // Only then are any options that followed the payload written.
+    fn encode_to<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        self.encode_layer_to(0, buf)
+    }
+
+    fn encode_layer_to<S: Sink>(&self, layer: usize, buf: &mut S) -> Result<()> {
+        let relay = match self.relays.get(layer) {
+            Some(relay) => relay,
+            None => return self.msg.encode_to(buf),
+        };
+        let at = match self.payload_at.get(layer) {
+            Some(at) => (*at).min(relay.option.len()),
+            None => relay.option.len(),
+        };
+        relay.encode_header_to(buf)?;
+        options::encode_options_into(&relay.option[..at], buf)?;
+        buf.put_16(options::OPTION_RELAY_MSG)?;
+        let len = buf.reserve_16()?;
+        self.encode_layer_to(layer + 1, buf)?;
+        buf.backfill_16(len)?;
+        options::encode_options_into(&relay.option[at..], buf)
+    }
+
+    // Deparses the relay chain into a DHCPv6 packet
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(2048);
+        self.encode_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Wraps a server's response to the client message in a chain of
+    /// Relay-reply messages which mirrors this chain of Relay-forward messages.
+    /// Each layer keeps the hop count, link address and peer address of the
+    /// corresponding Relay-forward, and echoes its Interface-ID option.
+    pub fn reply(&self, msg: ClientMsg) -> RelayChain {
+        let relays: Vec<RelayMsg> = self
+            .relays
+            .iter()
+            .map(|relay| {
+                let mut option = Vec::new();
+                if let Some(options::Dhcpv6Option::InterfaceId(id)) =
+                    relay.find_one_option(options::OPTION_INTERFACE_ID)
+                {
+                    option.push(options::Dhcpv6Option::InterfaceId(id.clone()));
+                }
+                RelayMsg {
+                    msg_type: MsgType::RelayRepl,
+                    hop_count: relay.hop_count,
+                    link_addr: relay.link_addr,
+                    peer_addr: relay.peer_addr,
+                    option,
+                }
+            })
+            .collect();
+        let payload_at = relays.iter().map(|r| r.option.len()).collect();
+        RelayChain {
+            relays,
+            msg,
+            payload_at,
+        }
+    }
+}
+
+/// A DHCPv6 packet, which is either a message exchanged directly with a
+/// client or one that has been wrapped by one or more relay agents.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Client(ClientMsg),
+    Relayed(RelayChain),
+}
+
+impl Message {
+    // Attempts to parse the contents of the provided buffer.  Any relay
+    // messages are unwrapped until the client message at the core is reached.
+    pub fn decode(buf: &[u8]) -> Result<Message> {
+        let mut relays: Vec<RelayMsg> = Vec::new();
+        let mut payload_at = Vec::new();
+        let mut payload: Option<Vec<u8>> = None;
+
+        loop {
+            let data = payload.as_deref().unwrap_or(buf);
+            let code = *data.first().ok_or(Error::TooShort)?;
+            if !matches!(
+                MsgType::from_code(code),
+                MsgType::RelayForw | MsgType::RelayRepl
+            ) {
+                let msg = ClientMsg::decode(data)?;
+                return Ok(if relays.is_empty() {
+                    Message::Client(msg)
+                } else {
+                    Message::Relayed(RelayChain {
+                        relays,
+                        msg,
+                        payload_at,
+                    })
+                });
+            }
+
+            let mut relay = RelayMsg::decode(data)?;
+            if let Some(outer) = relays.first() {
+                if outer.msg_type != relay.msg_type {
+                    return Err(Error::Other("mismatched relay message types".to_string()));
+                }
+            }
+            if relays.len() >= params::HOP_COUNT_LIMIT as usize
+                || relay.hop_count as u32 > params::HOP_COUNT_LIMIT
+            {
+                return Err(Error::Other("hop count limit exceeded".to_string()));
+            }
+            let (at, data) = relay.take_payload_at()?;
+            payload = Some(data);
+            payload_at.push(at);
+            relays.push(relay);
+        }
+    }
+
+    fn encode_to<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        match self {
+            Message::Client(msg) => msg.encode_to(buf),
+            Message::Relayed(chain) => chain.encode_to(buf),
+        }
+    }
+
+    // Deparses the message, including any relay layers, into a DHCPv6 packet
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(2048);
+        self.encode_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Deparses the message directly into the provided buffer, returning the
+    /// number of bytes used.  If the buffer is too small, the error reports
+    /// the number of bytes needed.
+    pub fn encode_into(&self, out: &mut [u8]) -> Result<usize> {
+        encode_into_slice(out, |buf| self.encode_to(buf), || self.encoded_len())
+    }
+
+    /// Returns the number of bytes needed to hold the deparsed message
+    pub fn encoded_len(&self) -> Result<usize> {
+        count_encoded(|buf| self.encode_to(buf))
+    }
+
+    /// Returns the client message at the core of the packet
+    pub fn client_msg(&self) -> &ClientMsg {
+        match self {
+            Message::Client(msg) => msg,
+            Message::Relayed(chain) => &chain.msg,
+        }
+    }
+
+    /// Returns the relays the message passed through, from the outermost to
+    /// the innermost.  This is empty if the message was not relayed.
+    pub fn relays(&self) -> &[RelayMsg] {
+        match self {
+            Message::Client(_) => &[],
+            Message::Relayed(chain) => &chain.relays,
+        }
+    }
+
+    /// Wraps a server's response to this message in the same relay layers,
+    /// so it will retrace the request's path back to the client.
+    pub fn reply(&self, msg: ClientMsg) -> Message {
+        match self {
+            Message::Client(_) => Message::Client(msg),
+            Message::Relayed(chain) => Message::Relayed(chain.reply(msg)),
+        }
     }
 }
@@ -1,8 +1,9 @@
 // Copyright 2021 Oxide Computer Company
 
 use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
-use crate::buffer::Buffer;
+use crate::buffer::{Buffer, Sink};
 use crate::*;
 
 const IPV6_SIZE: usize = 16; // 16 octets
@@ -29,9 +30,98 @@ pub const OPTION_RECONF_MSG: u16 = 19;
 pub const OPTION_RECONF_ACCEPT: u16 = 20;
 pub const OPTION_DNS_SERVERS: u16 = 23;
 pub const OPTION_DOMAIN_LIST: u16 = 24;
+pub const OPTION_IA_PD: u16 = 25;
+pub const OPTION_IAPREFIX: u16 = 26;
+pub const OPTION_INFORMATION_REFRESH_TIME: u16 = 32;
+
+/// A lifetime or timer value meaning forever, as described in rfc8415,
+/// section 7.7
+pub const INFINITY: u32 = 0xffff_ffff;
+
+/// Returns when a lifetime or timer of the given number of seconds, starting
+/// at the clock's current time, runs out.  None means it never does.
+pub fn lifetime_end<C: Clock>(clock: &C, seconds: u32) -> Option<Instant> {
+    match seconds {
+        INFINITY => None,
+        s => Some(clock.now() + Duration::from_secs(s as u64)),
+    }
+}
+
+/// The shortest T1 a client chooses for itself.  However short the
+/// lifetimes it has been given, it doesn't renew more often than this.
+pub const MIN_CLIENT_T1: u32 = 60;
+
+/// Returns the T1 and T2 recommended by rfc8415, section 21.4 for an IA
+/// with the given shortest preferred and valid lifetimes: half and four
+/// fifths of the preferred lifetime, or forever if the lifetime is.  If the
+/// preferred lifetime is 0, as it is for deprecated addresses, the valid
+/// lifetime is used instead.
+///
+/// T1 is at least MIN_CLIENT_T1, so a client can't end up renewing in a
+/// tight loop, but neither timer is ever pushed past four fifths of the
+/// lifetime.  The client always renews before the lifetime runs out.
+pub fn default_timers(shortest_preferred: u32, shortest_valid: u32) -> (u32, u32) {
+    let lifetime = match shortest_preferred {
+        0 => shortest_valid,
+        p => p,
+    };
+    match lifetime {
+        INFINITY => (INFINITY, INFINITY),
+        l => {
+            let t2 = (l as u64 * 4 / 5) as u32;
+            ((l / 2).max(MIN_CLIENT_T1).min(t2), t2)
+        }
+    }
+}
+
+/// Fills in whichever of T1 and T2 the server left as 0 from `defaults`,
+/// keeping any value the server did supply.  A chosen T1 is never later
+/// than the server's T2, nor a chosen T2 earlier than the server's T1.
+pub fn fill_timers(t1: u32, t2: u32, defaults: (u32, u32)) -> (u32, u32) {
+    match (t1, t2) {
+        (0, 0) => defaults,
+        (0, t2) => (defaults.0.min(t2), t2),
+        (t1, 0) => (t1, defaults.1.max(t1)),
+        timers => timers,
+    }
+}
+
+// rfc8415, sections 21.4 and 21.21: T1 may not exceed T2, unless either is
+// left for the client to choose.
+fn timers_valid(t1: u32, t2: u32) -> bool {
+    t1 == 0 || t2 == 0 || t1 <= t2
+}
+
+/// Removes the options rfc8415 requires a client to ignore: any IA_NA or
+/// IA_PD whose T1 exceeds its T2, and any IA Address or IA Prefix whose
+/// preferred lifetime exceeds its valid lifetime.
+pub fn discard_invalid(options: &mut Vec<Dhcpv6Option>) {
+    options.retain(|o| match o {
+        Dhcpv6Option::IaNa(ia) => ia.is_valid(),
+        Dhcpv6Option::IaPd(ia) => ia.is_valid(),
+        Dhcpv6Option::IaAddr(a) => a.is_valid(),
+        Dhcpv6Option::IaPrefix(p) => p.is_valid(),
+        _ => true,
+    });
+    for opt in options.iter_mut() {
+        match opt {
+            Dhcpv6Option::IaNa(ia) => discard_invalid(&mut ia.options),
+            Dhcpv6Option::IaTa(ia) => discard_invalid(&mut ia.options),
+            Dhcpv6Option::IaPd(ia) => discard_invalid(&mut ia.options),
+            _ => {}
+        }
+    }
+}
+
+/// Protocol, algorithm, and replay detection method codes used by the
+/// Authentication option
+pub const AUTH_PROTOCOL_DELAYED: u8 = 2;
+pub const AUTH_PROTOCOL_RECONFIGURE_KEY: u8 = 3;
+pub const AUTH_ALGORITHM_HMAC_MD5: u8 = 1;
+pub const AUTH_RDM_MONOTONIC: u8 = 0;
 
 /// All the supported DHCPv6 option types
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Dhcpv6Option {
     ClientId(Duid),
     ServerId(Duid),
@@ -42,7 +132,7 @@ pub enum Dhcpv6Option {
     Preference(u8),
     ElapsedTime(u16),
     RelayMsg(Vec<u8>),
-    Auth,
+    Auth(AuthOption),
     Unicast(Ipv6Addr),
     StatusCode(StatusCodeOption),
     RapidCommit,
@@ -54,6 +144,9 @@ pub enum Dhcpv6Option {
     ReconfAccept,
     DnsServers(Vec<Ipv6Addr>),
     DomainList(Vec<String>),
+    IaPd(IaPdOption),
+    IaPrefix(IaPrefixOption),
+    InformationRefreshTime(u32),
     Other(OtherOption),
 }
 
@@ -71,7 +164,7 @@ impl From<&Dhcpv6Option> for u16 {
             Dhcpv6Option::Preference(_) => OPTION_PREFERENCE,
             Dhcpv6Option::ElapsedTime(_) => OPTION_ELAPSED_TIME,
             Dhcpv6Option::RelayMsg(_) => OPTION_RELAY_MSG,
-            Dhcpv6Option::Auth => OPTION_AUTH,
+            Dhcpv6Option::Auth(_) => OPTION_AUTH,
             Dhcpv6Option::Unicast(_) => OPTION_UNICAST,
             Dhcpv6Option::StatusCode(_) => OPTION_STATUS_CODE,
             Dhcpv6Option::RapidCommit => OPTION_RAPID_COMMIT,
@@ -83,6 +176,9 @@ impl From<&Dhcpv6Option> for u16 {
             Dhcpv6Option::ReconfAccept => OPTION_RECONF_ACCEPT,
             Dhcpv6Option::DnsServers(_) => OPTION_DNS_SERVERS,
             Dhcpv6Option::DomainList(_) => OPTION_DOMAIN_LIST,
+            Dhcpv6Option::IaPd(_) => OPTION_IA_PD,
+            Dhcpv6Option::IaPrefix(_) => OPTION_IAPREFIX,
+            Dhcpv6Option::InformationRefreshTime(_) => OPTION_INFORMATION_REFRESH_TIME,
             Dhcpv6Option::Other(x) => x.code,
         }
     }
@@ -136,7 +232,7 @@ trait OptionParse {
     fn parse(len: usize, buf: &mut Buffer) -> Result<Self>
     where
         Self: Sized;
-    fn encode(&self) -> Result<Vec<u8>>;
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()>;
 }
 
 impl OptionParse for Vec<u8> {
@@ -145,8 +241,8 @@ impl OptionParse for Vec<u8> {
         Ok(d.to_vec())
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        Ok(self.to_vec())
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_bytes(self)
     }
 }
 
@@ -161,11 +257,11 @@ impl OptionParse for Vec<u16> {
         Ok(v)
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::with_capacity(self.len() * 2);
-        self.iter()
-            .for_each(|&x| v.extend_from_slice(&x.to_be_bytes()));
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        for &x in self {
+            buf.put_16(x)?;
+        }
+        Ok(())
     }
 }
 
@@ -178,8 +274,8 @@ impl OptionParse for Ipv6Addr {
         buf.get_ipv6addr()
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        Ok(self.octets().to_vec())
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_ipv6addr(self)
     }
 }
 
@@ -197,15 +293,15 @@ impl OptionParse for Vec<Ipv6Addr> {
         Ok(v)
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::with_capacity(IPV6_SIZE * self.len());
-        self.iter()
-            .for_each(|&ipv6| v.extend_from_slice(&ipv6.octets()));
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        for ipv6 in self {
+            buf.put_ipv6addr(ipv6)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Eq, Hash, Clone, PartialEq)]
+#[derive(Eq, Hash, Ord, PartialOrd, Clone, PartialEq)]
 pub struct DuidLLT {
     pub type_code: u16, // constant 1
     pub hw_type: u16,
@@ -254,17 +350,15 @@ impl OptionParse for DuidLLT {
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.type_code.to_be_bytes());
-        v.extend_from_slice(&self.hw_type.to_be_bytes());
-        v.extend_from_slice(&self.time.to_be_bytes());
-        v.extend_from_slice(&self.link_layer.to_vec());
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_16(self.type_code)?;
+        buf.put_16(self.hw_type)?;
+        buf.put_32(self.time)?;
+        buf.put_bytes(&self.link_layer)
     }
 }
 
-#[derive(Eq, Hash, Clone, PartialEq)]
+#[derive(Eq, Hash, Ord, PartialOrd, Clone, PartialEq)]
 pub struct DuidEn {
     pub type_code: u16, // constant 2
     pub enterprise_code: u32,
@@ -309,16 +403,14 @@ impl OptionParse for DuidEn {
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.type_code.to_be_bytes());
-        v.extend_from_slice(&self.enterprise_code.to_be_bytes());
-        v.extend_from_slice(&self.identifier);
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_16(self.type_code)?;
+        buf.put_32(self.enterprise_code)?;
+        buf.put_bytes(&self.identifier)
     }
 }
 
-#[derive(Eq, Hash, Clone, PartialEq)]
+#[derive(Eq, Hash, Ord, PartialOrd, Clone, PartialEq)]
 pub struct DuidLL {
     pub type_code: u16, // constant 3
     pub hw_type: u16,
@@ -359,52 +451,94 @@ impl OptionParse for DuidLL {
         Ok(DuidLL {
             type_code: 3,
             hw_type: buf.get_16()?,
-            link_layer: buf.get_bytes(len - 6)?,
+            link_layer: buf.get_bytes(len - 2)?,
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.type_code.to_be_bytes());
-        v.extend_from_slice(&self.hw_type.to_be_bytes());
-        v.extend_from_slice(&self.link_layer.to_vec());
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_16(self.type_code)?;
+        buf.put_16(self.hw_type)?;
+        buf.put_bytes(&self.link_layer)
     }
 }
 
-#[derive(Eq, Hash, Clone, Debug, PartialEq)]
+/// A DHCP Unique Identifier, as defined in rfc8415, section 11.  A DUID of an
+/// unrecognized type is kept as an opaque blob so it can still be compared
+/// and re-encoded.  `Unknown` never holds one of the recognized types.
+#[derive(Eq, Hash, Ord, PartialOrd, Clone, Debug, PartialEq)]
 pub enum Duid {
     Llt(DuidLLT),
     En(DuidEn),
     Ll(DuidLL),
+    Uuid([u8; 16]),
+    Unknown { type_code: u16, data: Vec<u8> },
+}
+
+impl Duid {
+    /// Returns the DUID in its wire format, as carried in a Client or
+    /// Server Identifier option
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Writing to a Vec can't fail
+        self.encode(&mut buf).unwrap();
+        buf
+    }
+
+    /// Decodes a DUID from its wire format
+    pub fn from_bytes(data: &[u8]) -> Result<Duid> {
+        Duid::parse(data.len(), &mut Buffer::new_from_slice(data))
+    }
 }
 
 impl OptionParse for Duid {
     fn parse(len: usize, buf: &mut Buffer) -> Result<Duid> {
+        if len < 2 {
+            return Err(Error::TooShort);
+        }
         let type_code = buf.get_16()?;
-
         let remaining = len - 2;
-        if remaining <= 128 {
-            match type_code {
-                1 => Ok(Duid::Llt(DuidLLT::parse(remaining, buf)?)),
-                2 => Ok(Duid::En(DuidEn::parse(remaining, buf)?)),
-                3 => Ok(Duid::Ll(DuidLL::parse(remaining, buf)?)),
-                _ => Err(Error::BadOption("invalid DUID type".into())),
+        let data = buf.get_slice(remaining)?;
+
+        if remaining > 128 {
+            return Err(Error::BadOption("DUID too long".into()));
+        }
+
+        let mut duid_buf = Buffer::new_from_slice(data);
+        match type_code {
+            1 => DuidLLT::parse(remaining, &mut duid_buf).map(Duid::Llt),
+            2 => DuidEn::parse(remaining, &mut duid_buf).map(Duid::En),
+            3 => DuidLL::parse(remaining, &mut duid_buf).map(Duid::Ll),
+            4 if remaining == 16 => {
+                let mut uuid = [0u8; 16];
+                uuid.copy_from_slice(data);
+                Ok(Duid::Uuid(uuid))
             }
-        } else {
-            Err(Error::BadOption("duid to long".into()))
+            4 => Err(Error::BadOption("DUID-UUID must be 16 bytes".into())),
+            _ => Ok(Duid::Unknown {
+                type_code,
+                data: data.to_vec(),
+            }),
         }
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
         match self {
-            Duid::Llt(x) => x.encode(),
-            Duid::En(x) => x.encode(),
-            Duid::Ll(x) => x.encode(),
+            Duid::Llt(x) => x.encode(buf),
+            Duid::En(x) => x.encode(buf),
+            Duid::Ll(x) => x.encode(buf),
+            Duid::Uuid(x) => {
+                buf.put_16(4)?;
+                buf.put_bytes(x)
+            }
+            Duid::Unknown { type_code, data } => {
+                buf.put_16(*type_code)?;
+                buf.put_bytes(data)
+            }
         }
     }
 }
 
+#[derive(Clone)]
 pub struct IaNaOption {
     pub iaid: u32,
     pub t1: u32,
@@ -421,6 +555,52 @@ impl IaNaOption {
             options: Vec::new(),
         }
     }
+
+    /// Returns false if T1 is greater than T2, in which case a client must
+    /// ignore the IA
+    pub fn is_valid(&self) -> bool {
+        timers_valid(self.t1, self.t2)
+    }
+
+    /// Returns the IA Address options in the IA, skipping any whose
+    /// lifetimes are inconsistent
+    pub fn addrs(&self) -> impl Iterator<Item = &IaAddrOption> {
+        self.options.iter().filter_map(|o| match o {
+            Dhcpv6Option::IaAddr(a) if a.is_valid() => Some(a),
+            _ => None,
+        })
+    }
+
+    /// Returns T1 and T2, with the values the client should choose for any
+    /// the server has left as 0.  Both are 0 if either was left as 0 and the
+    /// IA holds no usable addresses.
+    pub fn effective_timers(&self) -> (u32, u32) {
+        if self.t1 != 0 && self.t2 != 0 {
+            return (self.t1, self.t2);
+        }
+        let usable = || self.addrs().filter(|a| a.valid_lifetime > 0);
+        match (
+            usable().map(|a| a.preferred_lifetime).min(),
+            usable().map(|a| a.valid_lifetime).min(),
+        ) {
+            (Some(preferred), Some(valid)) => {
+                fill_timers(self.t1, self.t2, default_timers(preferred, valid))
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Returns when a client that has just received the IA should renew it,
+    /// or None if it never needs to
+    pub fn renew_at<C: Clock>(&self, clock: &C) -> Option<Instant> {
+        lifetime_end(clock, self.effective_timers().0)
+    }
+
+    /// Returns when a client that has just received the IA should rebind it,
+    /// or None if it never needs to
+    pub fn rebind_at<C: Clock>(&self, clock: &C) -> Option<Instant> {
+        lifetime_end(clock, self.effective_timers().1)
+    }
 }
 
 impl PartialEq for IaNaOption {
@@ -456,16 +636,15 @@ impl OptionParse for IaNaOption {
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.iaid.to_be_bytes());
-        v.extend_from_slice(&self.t1.to_be_bytes());
-        v.extend_from_slice(&self.t2.to_be_bytes());
-        v.extend_from_slice(&encode_options(&self.options)?);
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_32(self.iaid)?;
+        buf.put_32(self.t1)?;
+        buf.put_32(self.t2)?;
+        encode_options_into(&self.options, buf)
     }
 }
 
+#[derive(Clone)]
 pub struct IaTaOption {
     pub iaid: u32,
     pub options: Vec<Dhcpv6Option>,
@@ -499,14 +678,13 @@ impl OptionParse for IaTaOption {
         Ok(IaTaOption { iaid, options })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.iaid.to_be_bytes());
-        v.extend_from_slice(&encode_options(&self.options)?);
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_32(self.iaid)?;
+        encode_options_into(&self.options, buf)
     }
 }
 
+#[derive(Clone)]
 pub struct IaAddrOption {
     pub addr: Ipv6Addr,
     pub preferred_lifetime: u32,
@@ -523,6 +701,22 @@ impl IaAddrOption {
             options: Vec::new(),
         }
     }
+
+    /// Returns false if the preferred lifetime is greater than the valid
+    /// lifetime, in which case a client must ignore the address
+    pub fn is_valid(&self) -> bool {
+        self.preferred_lifetime <= self.valid_lifetime
+    }
+
+    /// Returns when the address, received now, stops being preferred
+    pub fn preferred_until<C: Clock>(&self, clock: &C) -> Option<Instant> {
+        lifetime_end(clock, self.preferred_lifetime)
+    }
+
+    /// Returns when the address, received now, becomes invalid
+    pub fn valid_until<C: Clock>(&self, clock: &C) -> Option<Instant> {
+        lifetime_end(clock, self.valid_lifetime)
+    }
 }
 
 impl PartialEq for IaAddrOption {
@@ -558,17 +752,168 @@ impl OptionParse for IaAddrOption {
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.addr.octets());
-        v.extend_from_slice(&self.preferred_lifetime.to_be_bytes());
-        v.extend_from_slice(&self.valid_lifetime.to_be_bytes());
-        v.extend_from_slice(&encode_options(&self.options)?);
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_ipv6addr(&self.addr)?;
+        buf.put_32(self.preferred_lifetime)?;
+        buf.put_32(self.valid_lifetime)?;
+        encode_options_into(&self.options, buf)
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct IaPdOption {
+    pub iaid: u32,
+    pub t1: u32,
+    pub t2: u32,
+    pub options: Vec<Dhcpv6Option>,
+}
+
+impl IaPdOption {
+    pub fn new(iaid: u32) -> Self {
+        IaPdOption {
+            iaid,
+            t1: 0,
+            t2: 0,
+            options: Vec::new(),
+        }
+    }
+
+    /// Returns false if T1 is greater than T2, in which case a client must
+    /// ignore the IA
+    pub fn is_valid(&self) -> bool {
+        timers_valid(self.t1, self.t2)
+    }
+}
+
+impl PartialEq for IaPdOption {
+    fn eq(&self, other: &Self) -> bool {
+        self.iaid == other.iaid
+            && self.t1 == other.t1
+            && self.t2 == other.t2
+            && compare_options(&self.options, &other.options).is_ok()
+    }
+}
+
+impl fmt::Debug for IaPdOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "iaid: {}  t1: {}  t2: {}  options: {:?}",
+            self.iaid, self.t1, self.t2, self.options
+        )
+    }
+}
+
+impl OptionParse for IaPdOption {
+    fn parse(len: usize, buf: &mut Buffer) -> Result<IaPdOption> {
+        if len < 12 {
+            return Err(Error::TooShort);
+        }
+        let iaid = buf.get_32()?;
+        let t1 = buf.get_32()?;
+        let t2 = buf.get_32()?;
+        let options = parse_nested_options(buf, len - 12)?;
+        Ok(IaPdOption {
+            iaid,
+            t1,
+            t2,
+            options,
+        })
+    }
+
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_32(self.iaid)?;
+        buf.put_32(self.t1)?;
+        buf.put_32(self.t2)?;
+        encode_options_into(&self.options, buf)
+    }
+}
+
+#[derive(Clone)]
+pub struct IaPrefixOption {
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub prefix_len: u8,
+    pub prefix: Ipv6Addr,
+    pub options: Vec<Dhcpv6Option>,
+}
+
+impl IaPrefixOption {
+    pub fn new(prefix: Ipv6Addr, prefix_len: u8) -> Self {
+        IaPrefixOption {
+            preferred_lifetime: 0,
+            valid_lifetime: 0,
+            prefix_len,
+            prefix,
+            options: Vec::new(),
+        }
+    }
+
+    /// Returns false if the preferred lifetime is greater than the valid
+    /// lifetime, in which case a client must ignore the prefix
+    pub fn is_valid(&self) -> bool {
+        self.preferred_lifetime <= self.valid_lifetime
+    }
+
+    /// Returns when the prefix, received now, becomes invalid
+    pub fn valid_until<C: Clock>(&self, clock: &C) -> Option<Instant> {
+        lifetime_end(clock, self.valid_lifetime)
+    }
+}
+
+impl PartialEq for IaPrefixOption {
+    fn eq(&self, other: &Self) -> bool {
+        self.prefix == other.prefix
+            && self.prefix_len == other.prefix_len
+            && self.preferred_lifetime == other.preferred_lifetime
+            && self.valid_lifetime == other.valid_lifetime
+            && compare_options(&self.options, &other.options).is_ok()
+    }
+}
+
+impl fmt::Debug for IaPrefixOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "prefix: {}/{}  preferred: {}  valid: {}  options: {:?}",
+            self.prefix,
+            self.prefix_len,
+            self.preferred_lifetime,
+            self.valid_lifetime,
+            self.options
+        )
+    }
+}
+
+impl OptionParse for IaPrefixOption {
+    fn parse(len: usize, buf: &mut Buffer) -> Result<IaPrefixOption> {
+        if len < 25 {
+            return Err(Error::TooShort);
+        }
+        let preferred_lifetime = buf.get_32()?;
+        let valid_lifetime = buf.get_32()?;
+        let prefix_len = buf.get_8()?;
+        let prefix = buf.get_ipv6addr()?;
+        let options = parse_nested_options(buf, len - 25)?;
+        Ok(IaPrefixOption {
+            preferred_lifetime,
+            valid_lifetime,
+            prefix_len,
+            prefix,
+            options,
+        })
+    }
+
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_32(self.preferred_lifetime)?;
+        buf.put_32(self.valid_lifetime)?;
+        buf.put_8(self.prefix_len)?;
+        buf.put_ipv6addr(&self.prefix)?;
+        encode_options_into(&self.options, buf)
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct StatusCodeOption {
     pub code: StatusCode,
     pub msg: Vec<u8>,
@@ -576,32 +921,128 @@ pub struct StatusCodeOption {
 
 impl fmt::Debug for StatusCodeOption {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let msg = match std::str::from_utf8(&self.msg) {
-            Ok(msg) => msg,
-            Err(_) => return Err(std::fmt::Error),
-        };
-        write!(f, "code: {}  msg: {}", self.code, msg)
+        write!(
+            f,
+            "code: {}  msg: {}",
+            self.code,
+            String::from_utf8_lossy(&self.msg)
+        )
     }
 }
 
 impl OptionParse for StatusCodeOption {
     fn parse(len: usize, buf: &mut Buffer) -> Result<StatusCodeOption> {
-        let code = StatusCode::try_from(buf.get_16()?)
-            .map_err(|_| Error::Other("invalid status code".to_string()))?;
+        if len < 2 {
+            return Err(Error::TooShort);
+        }
+        let code = StatusCode::from_code(buf.get_16()?);
         let msg = buf.get_bytes(len - 2)?;
         Ok(StatusCodeOption { code, msg })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        let code = self.code as u16;
-        v.extend_from_slice(&code.to_be_bytes());
-        v.extend_from_slice(&self.msg);
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_16(u16::from(self.code))?;
+        buf.put_bytes(&self.msg)
     }
 }
 
-#[derive(PartialEq)]
+/// The authentication information carried in an Authentication option.  The
+/// two forms used by the Reconfigure Key Authentication Protocol (rfc8415,
+/// section 20.4) are decoded.  The information for any other protocol is kept
+/// as raw bytes.
+#[derive(Clone, PartialEq)]
+pub enum AuthInfo {
+    ReconfKey([u8; 16]),
+    HmacMd5([u8; 16]),
+    Other(Vec<u8>),
+}
+
+const RKAP_RECONF_KEY: u8 = 1;
+pub(crate) const RKAP_HMAC_MD5: u8 = 2;
+
+impl fmt::Debug for AuthInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthInfo::ReconfKey(x) => write!(f, "reconfigure key: {}", hex(x)),
+            AuthInfo::HmacMd5(x) => write!(f, "hmac-md5: {}", hex(x)),
+            AuthInfo::Other(x) => write!(f, "{}", hex(x)),
+        }
+    }
+}
+
+/// The Authentication option as defined in rfc8415, section 21.11
+#[derive(Clone, PartialEq)]
+pub struct AuthOption {
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub rdm: u8,
+    pub replay_detection: u64,
+    pub info: AuthInfo,
+}
+
+impl fmt::Debug for AuthOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "protocol: {}  algorithm: {}  rdm: {}  replay: 0x{:x}  info: {:?}",
+            self.protocol, self.algorithm, self.rdm, self.replay_detection, self.info
+        )
+    }
+}
+
+impl OptionParse for AuthOption {
+    fn parse(len: usize, buf: &mut Buffer) -> Result<AuthOption> {
+        if len < 11 {
+            return Err(Error::TooShort);
+        }
+        let protocol = buf.get_8()?;
+        let algorithm = buf.get_8()?;
+        let rdm = buf.get_8()?;
+        let replay_detection = buf.get_64()?;
+        let data = buf.get_bytes(len - 11)?;
+
+        let info = match (protocol, data.len()) {
+            (AUTH_PROTOCOL_RECONFIGURE_KEY, 17) => {
+                let mut value = [0u8; 16];
+                value.copy_from_slice(&data[1..]);
+                match data[0] {
+                    RKAP_RECONF_KEY => AuthInfo::ReconfKey(value),
+                    RKAP_HMAC_MD5 => AuthInfo::HmacMd5(value),
+                    _ => AuthInfo::Other(data),
+                }
+            }
+            _ => AuthInfo::Other(data),
+        };
+
+        Ok(AuthOption {
+            protocol,
+            algorithm,
+            rdm,
+            replay_detection,
+            info,
+        })
+    }
+
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_8(self.protocol)?;
+        buf.put_8(self.algorithm)?;
+        buf.put_8(self.rdm)?;
+        buf.put_64(self.replay_detection)?;
+        match &self.info {
+            AuthInfo::ReconfKey(x) => {
+                buf.put_8(RKAP_RECONF_KEY)?;
+                buf.put_bytes(x)
+            }
+            AuthInfo::HmacMd5(x) => {
+                buf.put_8(RKAP_HMAC_MD5)?;
+                buf.put_bytes(x)
+            }
+            AuthInfo::Other(x) => buf.put_bytes(x),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct ClassData {
     pub len: usize,
     pub data: Vec<u8>,
@@ -615,8 +1056,8 @@ impl fmt::Debug for ClassData {
 
 impl OptionParse for Vec<ClassData> {
     fn parse(len: usize, buf: &mut Buffer) -> Result<Vec<ClassData>> {
-        let data = buf.get_bytes(len)?;
-        let mut class_buf = buffer::Buffer::new_from_slice(&data);
+        let data = buf.get_slice(len)?;
+        let mut class_buf = buffer::Buffer::new_from_slice(data);
         let mut v = Vec::new();
         while class_buf.left() > 0 {
             let len = class_buf.get_16()? as usize;
@@ -627,17 +1068,16 @@ impl OptionParse for Vec<ClassData> {
         Ok(v)
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
         for class in self {
-            v.extend_from_slice(&class.len.to_be_bytes());
-            v.extend_from_slice(&class.data)
+            buf.put_16(class.len as u16)?;
+            buf.put_bytes(&class.data)?;
         }
-        Ok(v)
+        Ok(())
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct VendorClassOption {
     pub enterprise_number: u32,
     pub data: Vec<ClassData>,
@@ -663,18 +1103,13 @@ impl OptionParse for VendorClassOption {
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.enterprise_number.to_be_bytes());
-        for class in &self.data {
-            v.extend_from_slice(&class.len.to_be_bytes());
-            v.extend_from_slice(&class.data)
-        }
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_32(self.enterprise_number)?;
+        self.data.encode(buf)
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct VendorOption {
     pub enterprise_number: u32,
     pub data: Vec<u8>,
@@ -701,11 +1136,9 @@ impl OptionParse for VendorOption {
         })
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.enterprise_number.to_be_bytes());
-        v.extend_from_slice(&self.data);
-        Ok(v)
+    fn encode<S: Sink>(&self, buf: &mut S) -> Result<()> {
+        buf.put_32(self.enterprise_number)?;
+        buf.put_bytes(&self.data)
     }
 }
 
@@ -744,8 +1177,7 @@ fn domain_validate(domain: &str) -> Result<()> {
     Ok(())
 }
 
-fn domain_list_encode(opt: &[String]) -> Result<Vec<u8>> {
-    let mut v = Vec::new();
+fn domain_list_encode<S: Sink>(opt: &[String], buf: &mut S) -> Result<()> {
     for domain in opt {
         domain_validate(domain)?;
 
@@ -753,50 +1185,45 @@ fn domain_list_encode(opt: &[String]) -> Result<Vec<u8>> {
         for label in domain.split('.') {
             if !label.is_empty() {
                 labels += 1;
-                v.push(label.len() as u8);
-                v.extend_from_slice(&label.as_bytes());
+                buf.put_8(label.len() as u8)?;
+                buf.put_bytes(label.as_bytes())?;
             }
         }
         if labels > 0 {
-            v.push(0);
+            buf.put_8(0)?;
         }
     }
-    Ok(v)
+    Ok(())
 }
 
-fn domain_parse(mut buf: Vec<u8>) -> Result<(String, Vec<u8>)> {
+// Parses a single domain name from the front of the buffer, returning the name
+// and whatever remains of the buffer.
+fn domain_parse(buf: &[u8]) -> Result<(String, &[u8])> {
     let mut domain = String::new();
+    let mut idx = 0;
 
-    while !buf.is_empty() {
-        let len = buf.remove(0) as usize;
+    while idx < buf.len() {
+        let len = buf[idx] as usize;
+        idx += 1;
         if len == 0 {
             break;
         }
-        if len > buf.len() {
+        if len > buf.len() - idx {
             return Err(Error::BadOption("domain option overflow".to_string()));
         }
 
         if !domain.is_empty() {
             domain.push('.');
         }
-        for _ in 0..len {
-            let c = match std::char::from_u32(buf.remove(0) as u32) {
-                Some(c) => c,
-                None => {
-                    return Err(Error::BadOption(
-                        "domain contains invalid character".to_string(),
-                    ))
-                }
-            };
-            domain.push(c);
-        }
+        domain.extend(buf[idx..idx + len].iter().map(|&c| c as char));
+        idx += len;
     }
     domain_validate(&domain)?;
-    Ok((domain, buf))
+    Ok((domain, &buf[idx..]))
 }
 
 fn domain_list_parse(len: usize, buf: &mut Buffer) -> Result<Vec<String>> {
-    let mut data = buf.get_bytes(len)?;
+    let mut data = buf.get_slice(len)?;
     let mut list = Vec::new();
 
     while !data.is_empty() {
@@ -810,7 +1237,7 @@ fn domain_list_parse(len: usize, buf: &mut Buffer) -> Result<Vec<String>> {
     Ok(list)
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct OtherOption {
     pub code: u16,
     pub len: usize,
@@ -837,54 +1264,65 @@ fn other_option(code: u16, len: usize, buf: &mut Buffer) -> Result<OtherOption>
     })
 }
 
-fn encode_one(opt: &Dhcpv6Option) -> Result<Vec<u8>> {
-    let mut v = Vec::new();
-    let data = match opt {
-        Dhcpv6Option::ClientId(x) => x.encode()?,
-        Dhcpv6Option::ServerId(x) => x.encode()?,
-        Dhcpv6Option::IaNa(x) => x.encode()?,
-        Dhcpv6Option::IaTa(x) => x.encode()?,
-        Dhcpv6Option::IaAddr(x) => x.encode()?,
-        Dhcpv6Option::Oro(x) => x.encode()?,
-        Dhcpv6Option::Preference(x) => (*x).to_be_bytes().to_vec(),
-        Dhcpv6Option::ElapsedTime(x) => (*x).to_be_bytes().to_vec(),
-        Dhcpv6Option::RelayMsg(x) => x.encode()?,
-        Dhcpv6Option::Auth => {
-            return Err(Error::Unimplemented("Authentication option".to_string()))
-        }
-        Dhcpv6Option::Unicast(x) => x.encode()?,
-        Dhcpv6Option::StatusCode(x) => x.encode()?,
-        Dhcpv6Option::RapidCommit => Vec::new(), // no payload to push
-        Dhcpv6Option::UserClass(x) => x.encode()?,
-        Dhcpv6Option::VendorClass(x) => x.encode()?,
-        Dhcpv6Option::VendorOpts(x) => x.encode()?,
-        Dhcpv6Option::InterfaceId(x) => x.encode()?,
-        Dhcpv6Option::ReconfMsg(x) => vec![*x],
-        Dhcpv6Option::ReconfAccept => Vec::new(), // no payload to push
-        Dhcpv6Option::DnsServers(x) => x.encode()?,
-        Dhcpv6Option::DomainList(x) => domain_list_encode(&x)?,
-        Dhcpv6Option::Other(x) => x.data.to_vec(),
+// Encodes a single option.  The option's length isn't known until its payload
+// has been encoded, so space is reserved for it and then backfilled.
+fn encode_one<S: Sink>(opt: &Dhcpv6Option, buf: &mut S) -> Result<()> {
+    buf.put_16(opt.into())?;
+    let len = buf.reserve_16()?;
+    match opt {
+        Dhcpv6Option::ClientId(x) => x.encode(buf)?,
+        Dhcpv6Option::ServerId(x) => x.encode(buf)?,
+        Dhcpv6Option::IaNa(x) => x.encode(buf)?,
+        Dhcpv6Option::IaTa(x) => x.encode(buf)?,
+        Dhcpv6Option::IaAddr(x) => x.encode(buf)?,
+        Dhcpv6Option::Oro(x) => x.encode(buf)?,
+        Dhcpv6Option::Preference(x) => buf.put_8(*x)?,
+        Dhcpv6Option::ElapsedTime(x) => buf.put_16(*x)?,
+        Dhcpv6Option::RelayMsg(x) => x.encode(buf)?,
+        Dhcpv6Option::Auth(x) => x.encode(buf)?,
+        Dhcpv6Option::Unicast(x) => x.encode(buf)?,
+        Dhcpv6Option::StatusCode(x) => x.encode(buf)?,
+        Dhcpv6Option::RapidCommit => {} // no payload to push
+        Dhcpv6Option::UserClass(x) => x.encode(buf)?,
+        Dhcpv6Option::VendorClass(x) => x.encode(buf)?,
+        Dhcpv6Option::VendorOpts(x) => x.encode(buf)?,
+        Dhcpv6Option::InterfaceId(x) => x.encode(buf)?,
+        Dhcpv6Option::ReconfMsg(x) => buf.put_8(*x)?,
+        Dhcpv6Option::ReconfAccept => {} // no payload to push
+        Dhcpv6Option::DnsServers(x) => x.encode(buf)?,
+        Dhcpv6Option::DomainList(x) => domain_list_encode(x, buf)?,
+        Dhcpv6Option::IaPd(x) => x.encode(buf)?,
+        Dhcpv6Option::IaPrefix(x) => x.encode(buf)?,
+        Dhcpv6Option::InformationRefreshTime(x) => buf.put_32(*x)?,
+        Dhcpv6Option::Other(x) => buf.put_bytes(&x.data)?,
     };
-    let code: u16 = opt.into();
-    v.extend_from_slice(&code.to_be_bytes());
-    v.extend_from_slice(&(data.len() as u16).to_be_bytes());
-    v.extend_from_slice(&data);
+    buf.backfill_16(len)
+}
 
-    Ok(v)
+pub(crate) fn encode_options_into<S: Sink>(opts: &[Dhcpv6Option], buf: &mut S) -> Result<()> {
+    for opt in opts {
+        encode_one(opt, buf)?;
+    }
+    Ok(())
 }
 
 pub fn encode_options(opts: &[Dhcpv6Option]) -> Result<Vec<u8>> {
     let mut v = Vec::new();
-    for opt in opts {
-        v.extend_from_slice(&encode_one(opt)?);
-    }
+    encode_options_into(opts, &mut v)?;
     Ok(v)
 }
 
 fn parse_one(buf: &mut Buffer) -> Result<Dhcpv6Option> {
     let code = buf.get_16()?;
     let len = buf.get_16()? as usize;
-    let next = buf.get_offset() + len;
+    let data = buf.get_slice(len)?;
+    parse_option_data(code, data)
+}
+
+// Parses the payload of a single option with the given code
+pub(crate) fn parse_option_data(code: u16, data: &[u8]) -> Result<Dhcpv6Option> {
+    let len = data.len();
+    let buf = &mut Buffer::new_from_slice(data);
 
     let opt = match code {
         OPTION_CLIENTID => Dhcpv6Option::ClientId(Duid::parse(len, buf)?),
@@ -896,7 +1334,7 @@ fn parse_one(buf: &mut Buffer) -> Result<Dhcpv6Option> {
         OPTION_PREFERENCE => Dhcpv6Option::Preference(buf.get_8()?),
         OPTION_ELAPSED_TIME => Dhcpv6Option::ElapsedTime(buf.get_16()?),
         OPTION_RELAY_MSG => Dhcpv6Option::RelayMsg(Vec::<u8>::parse(len, buf)?),
-        OPTION_AUTH => return Err(Error::Unimplemented("Authentication option".to_string())),
+        OPTION_AUTH => Dhcpv6Option::Auth(AuthOption::parse(len, buf)?),
         OPTION_UNICAST => Dhcpv6Option::Unicast(buf.get_ipv6addr()?),
         OPTION_STATUS_CODE => Dhcpv6Option::StatusCode(StatusCodeOption::parse(len, buf)?),
         OPTION_RAPID_COMMIT => Dhcpv6Option::RapidCommit,
@@ -908,15 +1346,17 @@ fn parse_one(buf: &mut Buffer) -> Result<Dhcpv6Option> {
         OPTION_RECONF_ACCEPT => Dhcpv6Option::ReconfAccept,
         OPTION_DNS_SERVERS => Dhcpv6Option::DnsServers(Vec::<Ipv6Addr>::parse(len, buf)?),
         OPTION_DOMAIN_LIST => Dhcpv6Option::DomainList(domain_list_parse(len, buf)?),
+        OPTION_IA_PD => Dhcpv6Option::IaPd(IaPdOption::parse(len, buf)?),
+        OPTION_IAPREFIX => Dhcpv6Option::IaPrefix(IaPrefixOption::parse(len, buf)?),
+        OPTION_INFORMATION_REFRESH_TIME => Dhcpv6Option::InformationRefreshTime(buf.get_32()?),
         _ => Dhcpv6Option::Other(other_option(code, len, buf)?),
     };
-    buf.set_offset(next)?;
     Ok(opt)
 }
 
 fn parse_nested_options(buf: &mut Buffer, len: usize) -> Result<Vec<Dhcpv6Option>> {
-    let data = buf.get_bytes(len)?;
-    let mut options_buf = buffer::Buffer::new_from_slice(&data);
+    let data = buf.get_slice(len)?;
+    let mut options_buf = buffer::Buffer::new_from_slice(data);
     parse_options(&mut options_buf)
 }
 
@@ -1,5 +1,7 @@
 // Copyright 2021 Oxide Computer Company
 
+use std::net::Ipv6Addr;
+
 pub const SOL_MAX_DELAY: u32 = 1; // Max delay of first Solicit
 pub const SOL_TIMEOUT: u32 = 1; // Initial Solicit timeout
 pub const SOL_MAX_RT: u32 = 120; // Max Solicit timeout value
@@ -23,4 +25,14 @@ pub const DEC_TIMEOUT: u32 = 1; // Initial Decline timeout
 pub const DEC_MAX_RC: u32 = 5; //Max Decline attempts
 pub const REC_TIMEOUT: u32 = 2; // Initial Reconfigure timeout
 pub const REC_MAX_RC: u32 = 8; //Max Reconfigure attempts
+pub const LQ_QUERY_TIMEOUT: u32 = 1; // Initial Leasequery timeout (rfc5007)
+pub const LQ_MAX_RT: u32 = 10; // Max Leasequery timeout value (rfc5007)
+pub const LQ_MAX_RC: u32 = 5; // Max Leasequery retry attempts (rfc5007)
+pub const IRT_DEFAULT: u32 = 86400; // Default information refresh time
+pub const IRT_MINIMUM: u32 = 600; // Min information refresh time
 pub const HOP_COUNT_LIMIT: u32 = 32; //Max hop count in a Relay-forward message
+
+pub const CLIENT_PORT: u16 = 546; // Port on which clients listen
+pub const SERVER_PORT: u16 = 547; // Port on which servers and relay agents listen
+pub const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+pub const ALL_DHCP_SERVERS: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 1, 3);
@@ -0,0 +1,524 @@
+// Copyright 2021 Oxide Computer Company
+
+//! An allocator for the addresses handed out in IA_NA options.
+//!
+//! A pool covers a contiguous range of addresses, typically a whole prefix.
+//! Only the addresses that have been handed out, declined or excluded are
+//! tracked, so a /64 costs no more than a /120 until it starts filling up.
+
+use std::collections::{BTreeSet, HashMap};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::options::Duid;
+use crate::server::AddressAllocator;
+use crate::*;
+
+/// How a pool chooses the next address to hand out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Addresses are handed out in order, starting from the bottom of the
+    /// pool
+    Sequential,
+    /// Addresses are chosen at random, which makes them hard to guess
+    Random,
+    /// The address is derived from a hash of the client's DUID and IAID, so
+    /// a client tends to get the same address even if its lease is lost
+    Hashed,
+}
+
+/// A pool of addresses from which IA_NA addresses are allocated.  The
+/// default RNG is seeded from the OS, and unlike the thread-local one it is
+/// Send, so a server built on the pool can be moved onto another task.
+pub struct AddressPool<R: Rng = StdRng> {
+    // The pool covers the addresses from first to last inclusive
+    first: u128,
+    last: u128,
+    strategy: Strategy,
+    rng: R,
+    // Inclusive ranges of addresses that are never handed out
+    excluded: Vec<(u128, u128)>,
+    reservations: HashMap<Duid, u128>,
+    reserved: BTreeSet<u128>,
+    in_use: BTreeSet<u128>,
+    declined: BTreeSet<u128>,
+    // Where the next sequential search begins
+    cursor: u128,
+    rapid_commit: Option<bool>,
+}
+
+impl<R: Rng> fmt::Debug for AddressPool<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} - {}  {:?}  in use: {}  declined: {}",
+            Ipv6Addr::from(self.first),
+            Ipv6Addr::from(self.last),
+            self.strategy,
+            self.in_use.len(),
+            self.declined.len()
+        )
+    }
+}
+
+// A 64-bit FNV-1a hash.  Unlike std's default hasher, its output is fixed,
+// so a client's address doesn't change when the server is rebuilt.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl AddressPool<StdRng> {
+    /// Returns a pool covering all of the given prefix, apart from the
+    /// Subnet-Router anycast address at the bottom of it.
+    pub fn new(prefix: Ipv6Addr, prefix_len: u8, strategy: Strategy) -> Result<Self> {
+        AddressPool::with_rng(prefix, prefix_len, strategy, StdRng::from_entropy())
+    }
+
+    /// Returns a pool covering the addresses from first to last inclusive
+    pub fn from_range(first: Ipv6Addr, last: Ipv6Addr, strategy: Strategy) -> Result<Self> {
+        AddressPool::range_with_rng(first, last, strategy, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> AddressPool<R> {
+    /// Returns a pool covering the given prefix, using the supplied RNG for
+    /// the random strategy
+    pub fn with_rng(prefix: Ipv6Addr, prefix_len: u8, strategy: Strategy, rng: R) -> Result<Self> {
+        if prefix_len >= 127 {
+            return Err(Error::Other(format!(
+                "a /{} is too small for an address pool",
+                prefix_len
+            )));
+        }
+        let mask = u128::MAX >> prefix_len;
+        let base = u128::from(prefix) & !mask;
+        AddressPool::range_with_rng(
+            Ipv6Addr::from(base + 1),
+            Ipv6Addr::from(base | mask),
+            strategy,
+            rng,
+        )
+    }
+
+    /// Returns a pool covering a range of addresses, using the supplied RNG
+    /// for the random strategy
+    pub fn range_with_rng(
+        first: Ipv6Addr,
+        last: Ipv6Addr,
+        strategy: Strategy,
+        rng: R,
+    ) -> Result<Self> {
+        let (first, last) = (u128::from(first), u128::from(last));
+        if first > last {
+            return Err(Error::Other("address pool range is empty".into()));
+        }
+        Ok(AddressPool {
+            first,
+            last,
+            strategy,
+            rng,
+            excluded: Vec::new(),
+            reservations: HashMap::new(),
+            reserved: BTreeSet::new(),
+            in_use: BTreeSet::new(),
+            declined: BTreeSet::new(),
+            cursor: first,
+            rapid_commit: None,
+        })
+    }
+
+    /// Sets whether Solicits with the Rapid Commit option are committed
+    /// straight away for addresses from this pool.  None defers to the
+    /// server's configuration.
+    pub fn set_rapid_commit(&mut self, policy: Option<bool>) {
+        self.rapid_commit = policy;
+    }
+
+    /// Prevents the addresses from first to last inclusive from being
+    /// handed out
+    pub fn exclude(&mut self, first: Ipv6Addr, last: Ipv6Addr) -> Result<()> {
+        let (first, last) = (u128::from(first), u128::from(last));
+        if first > last {
+            return Err(Error::Other("excluded range is empty".into()));
+        }
+        self.excluded.push((first, last));
+        Ok(())
+    }
+
+    /// Sets aside an address for the client with the given DUID.  It will be
+    /// handed out to that client, and no other.
+    pub fn reserve(&mut self, duid: Duid, addr: Ipv6Addr) -> Result<()> {
+        let addr = u128::from(addr);
+        if !self.in_range(addr) {
+            return Err(Error::Other(format!(
+                "{} is outside the pool",
+                Ipv6Addr::from(addr)
+            )));
+        }
+        if self.reservations.get(&duid) == Some(&addr) {
+            return Ok(());
+        }
+        if self.reserved.contains(&addr) {
+            return Err(Error::Other(format!(
+                "{} is already reserved",
+                Ipv6Addr::from(addr)
+            )));
+        }
+        if let Some(old) = self.reservations.insert(duid, addr) {
+            self.reserved.remove(&old);
+        }
+        self.reserved.insert(addr);
+        Ok(())
+    }
+
+    /// Returns the number of addresses currently handed out
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// Returns true if the address is in use
+    pub fn is_in_use(&self, addr: Ipv6Addr) -> bool {
+        self.in_use.contains(&u128::from(addr))
+    }
+
+    fn in_range(&self, addr: u128) -> bool {
+        addr >= self.first && addr <= self.last
+    }
+
+    fn is_excluded(&self, addr: u128) -> bool {
+        self.excluded.iter().any(|(f, l)| addr >= *f && addr <= *l)
+    }
+
+    // Returns true if the address can be handed out to the client it has
+    // been reserved for, if any
+    fn is_available(&self, addr: u128) -> bool {
+        self.in_range(addr)
+            && !self.is_excluded(addr)
+            && !self.in_use.contains(&addr)
+            && !self.declined.contains(&addr)
+    }
+
+    // Returns true if the address can be handed out to a client without a
+    // reservation
+    fn is_free(&self, addr: u128) -> bool {
+        self.is_available(addr) && !self.reserved.contains(&addr)
+    }
+
+    // Returns the first free address at or after start, wrapping around at
+    // the top of the pool.  Each step moves past at least one address that
+    // is tracked as unavailable, so this is bounded by the number of
+    // addresses in use rather than the size of the pool.
+    fn search(&self, start: u128) -> Option<u128> {
+        let mut addr = Some(start);
+        let mut wrapped = false;
+        loop {
+            let candidate = match addr {
+                Some(a) if a <= self.last && !(wrapped && a >= start) => a,
+                _ if wrapped || start == self.first => return None,
+                _ => {
+                    wrapped = true;
+                    addr = Some(self.first);
+                    continue;
+                }
+            };
+            if let Some((_, l)) = self
+                .excluded
+                .iter()
+                .find(|(f, l)| candidate >= *f && candidate <= *l)
+            {
+                addr = l.checked_add(1);
+            } else if self.in_use.contains(&candidate)
+                || self.declined.contains(&candidate)
+                || self.reserved.contains(&candidate)
+            {
+                addr = candidate.checked_add(1);
+            } else {
+                return Some(candidate);
+            }
+        }
+    }
+
+    // Returns the address at which to start looking for a free address
+    fn starting_point(&mut self, duid: &Duid, iaid: u32) -> u128 {
+        // The size only overflows if the pool is the entire address space
+        let size = (self.last - self.first).checked_add(1);
+        let offset = |x: u128| match size {
+            Some(size) => x % size,
+            None => x,
+        };
+        match self.strategy {
+            Strategy::Sequential => self.cursor,
+            Strategy::Random => {
+                let x = self.rng.gen::<u128>();
+                self.first + offset(x)
+            }
+            Strategy::Hashed => {
+                let mut data = duid.to_bytes();
+                data.extend_from_slice(&iaid.to_be_bytes());
+                self.first + offset(fnv1a(&data) as u128)
+            }
+        }
+    }
+}
+
+impl<R: Rng> AddressAllocator for AddressPool<R> {
+    fn allocate(&mut self, duid: &Duid, iaid: u32, hint: Option<Ipv6Addr>) -> Option<Ipv6Addr> {
+        let addr = match self.reservations.get(duid) {
+            Some(reserved) if self.is_available(*reserved) => *reserved,
+            _ => match hint.map(u128::from) {
+                Some(hint) if self.is_free(hint) => hint,
+                _ => {
+                    let start = self.starting_point(duid, iaid);
+                    self.search(start)?
+                }
+            },
+        };
+
+        self.in_use.insert(addr);
+        if self.strategy == Strategy::Sequential && addr >= self.cursor {
+            self.cursor = match addr {
+                _ if addr >= self.last => self.first,
+                _ => addr + 1,
+            };
+        }
+        Some(Ipv6Addr::from(addr))
+    }
+
+    fn release(&mut self, addr: Ipv6Addr) {
+        self.in_use.remove(&u128::from(addr));
+    }
+
+    fn decline(&mut self, addr: Ipv6Addr) {
+        let addr = u128::from(addr);
+        if self.in_range(addr) {
+            self.in_use.remove(&addr);
+            self.declined.insert(addr);
+        }
+    }
+
+    fn contains(&self, addr: Ipv6Addr) -> bool {
+        self.in_range(u128::from(addr))
+    }
+
+    fn claim(&mut self, addr: Ipv6Addr) -> bool {
+        let addr = u128::from(addr);
+        self.in_range(addr) && !self.is_excluded(addr) && self.in_use.insert(addr)
+    }
+
+    fn rapid_commit(&self) -> Option<bool> {
+        self.rapid_commit
+    }
+}
+
+#[cfg(test)]
+use crate::test::{addr, test_client_msg, test_duid};
+
+#[test]
+fn test_pool_sequential() {
+    let mut pool = AddressPool::new(addr("fd00::"), 125, Strategy::Sequential).unwrap();
+    pool.exclude(addr("fd00::3"), addr("fd00::4")).unwrap();
+    let duid = test_duid(1);
+
+    // The anycast address at the bottom of the prefix and the excluded
+    // range are skipped
+    let mut got = Vec::new();
+    while let Some(a) = pool.allocate(&duid, got.len() as u32, None) {
+        got.push(a);
+    }
+    let expected: Vec<Ipv6Addr> = ["fd00::1", "fd00::2", "fd00::5", "fd00::6", "fd00::7"]
+        .iter()
+        .map(|a| addr(a))
+        .collect();
+    assert_eq!(got, expected);
+    assert_eq!(pool.in_use(), 5);
+
+    // A released address is found again after the search wraps
+    pool.release(addr("fd00::2"));
+    assert_eq!(pool.allocate(&duid, 9, None), Some(addr("fd00::2")));
+
+    // A declined address is never handed out again
+    pool.decline(addr("fd00::5"));
+    assert_eq!(pool.allocate(&duid, 10, None), None);
+    assert!(pool.contains(addr("fd00::5")));
+    assert!(!pool.contains(addr("fd00::8")));
+}
+
+#[test]
+fn test_pool_hints_and_reservations() {
+    let mut pool =
+        AddressPool::from_range(addr("fd00::10"), addr("fd00::1f"), Strategy::Sequential).unwrap();
+    pool.reserve(test_duid(1), addr("fd00::10")).unwrap();
+    assert!(pool.reserve(test_duid(2), addr("fd00::10")).is_err());
+    assert!(pool.reserve(test_duid(2), addr("fd00::20")).is_err());
+
+    // The reserved address is skipped for everyone else, even if they ask
+    // for it
+    let a = pool.allocate(&test_duid(2), 1, Some(addr("fd00::10")));
+    assert_eq!(a, Some(addr("fd00::11")));
+
+    // A hint is honoured if the address is free
+    let a = pool.allocate(&test_duid(3), 1, Some(addr("fd00::1a")));
+    assert_eq!(a, Some(addr("fd00::1a")));
+    let a = pool.allocate(&test_duid(4), 1, Some(addr("fd00::1a")));
+    assert_eq!(a, Some(addr("fd00::1b")));
+
+    // The reservation wins over the client's own hint
+    let a = pool.allocate(&test_duid(1), 1, Some(addr("fd00::15")));
+    assert_eq!(a, Some(addr("fd00::10")));
+
+    // Reserving the same address for the same client again is harmless
+    pool.reserve(test_duid(1), addr("fd00::10")).unwrap();
+    assert!(pool.reserve(test_duid(2), addr("fd00::10")).is_err());
+}
+
+#[test]
+fn test_pool_unavailable_reservations() {
+    let mut pool =
+        AddressPool::from_range(addr("fd00::10"), addr("fd00::1f"), Strategy::Sequential).unwrap();
+    pool.reserve(test_duid(1), addr("fd00::10")).unwrap();
+    pool.reserve(test_duid(2), addr("fd00::18")).unwrap();
+    pool.exclude(addr("fd00::18"), addr("fd00::19")).unwrap();
+
+    // A declined reservation isn't handed out again, not even to the client
+    // it was reserved for
+    let a = pool.allocate(&test_duid(1), 1, None);
+    assert_eq!(a, Some(addr("fd00::10")));
+    pool.decline(addr("fd00::10"));
+    let a = pool.allocate(&test_duid(1), 1, None);
+    assert_eq!(a, Some(addr("fd00::11")));
+
+    // Nor is a reservation in an excluded range
+    let a = pool.allocate(&test_duid(2), 1, None);
+    assert_eq!(a, Some(addr("fd00::12")));
+}
+
+#[test]
+fn test_pool_hashed() {
+    let mut a = AddressPool::new(addr("fd00::"), 64, Strategy::Hashed).unwrap();
+    let mut b = AddressPool::new(addr("fd00::"), 64, Strategy::Hashed).unwrap();
+
+    // The same client gets the same address from either pool, and different
+    // IAs get different addresses
+    let first = a.allocate(&test_duid(1), 1, None).unwrap();
+    assert_eq!(b.allocate(&test_duid(1), 1, None), Some(first));
+    let second = a.allocate(&test_duid(1), 2, None).unwrap();
+    assert_ne!(first, second);
+
+    // A collision moves on to the next free address
+    a.release(first);
+    a.claim(first);
+    let next = a.allocate(&test_duid(1), 1, None).unwrap();
+    assert_eq!(u128::from(next), u128::from(first) + 1);
+}
+
+#[test]
+fn test_pool_random_64() {
+    let rng = StdRng::seed_from_u64(1);
+    let mut pool = AddressPool::with_rng(addr("fd00:1::"), 64, Strategy::Random, rng).unwrap();
+
+    // Only the allocated addresses are tracked, so a /64 is cheap
+    let mut seen = BTreeSet::new();
+    for i in 0..10000 {
+        let a = pool.allocate(&test_duid(i), 1, None).unwrap();
+        assert!(pool.contains(a));
+        assert!(seen.insert(a));
+    }
+    assert_eq!(pool.in_use(), 10000);
+}
+
+#[test]
+fn test_pool_server() {
+    use crate::server::{Server, ServerConfig};
+    use std::time::SystemTime;
+
+    let pool = AddressPool::new(addr("fd00::"), 120, Strategy::Sequential).unwrap();
+    let mut server = Server::new(ServerConfig::new(test_duid(100)), pool);
+
+    let solicit = test_client_msg(MsgType::Solicit, 1, None, &[1]);
+    let advertise = server
+        .handle_client_msg(SystemTime::now(), &solicit)
+        .unwrap();
+    match advertise.find_one_option(options::OPTION_IA_NA) {
+        Some(options::Dhcpv6Option::IaNa(ia)) => match &ia.options[0] {
+            options::Dhcpv6Option::IaAddr(a) => assert_eq!(a.addr, addr("fd00::1")),
+            _ => panic!("no address offered"),
+        },
+        _ => panic!("no IA_NA offered"),
+    }
+    assert!(server.allocator().is_in_use(addr("fd00::1")));
+}
+
+#[test]
+fn test_pool_rebind_takeover() {
+    use crate::server::{Server, ServerConfig};
+    use std::time::SystemTime;
+
+    let pool = AddressPool::new(addr("fd00::"), 120, Strategy::Sequential).unwrap();
+    let mut server = Server::new(ServerConfig::new(test_duid(100)), pool);
+    let now = SystemTime::now();
+    let rebind = |client: u16, a: &str| {
+        let mut msg = test_client_msg(MsgType::Rebind, client, None, &[]);
+        let mut ia = options::IaNaOption::new(1);
+        ia.options
+            .push(options::Dhcpv6Option::IaAddr(options::IaAddrOption::new(
+                addr(a),
+            )));
+        msg.options.push(options::Dhcpv6Option::IaNa(ia));
+        msg
+    };
+    let lifetime = |reply: &ClientMsg| match reply.find_one_option(options::OPTION_IA_NA) {
+        Some(options::Dhcpv6Option::IaNa(ia)) => match &ia.options[0] {
+            options::Dhcpv6Option::IaAddr(a) => (a.addr, a.valid_lifetime),
+            _ => panic!("no address in the IA_NA"),
+        },
+        _ => panic!("no IA_NA in the reply"),
+    };
+
+    // A free address on the link is taken over
+    let reply = server
+        .handle_client_msg(now, &rebind(1, "fd00::5"))
+        .unwrap();
+    let (a, valid) = lifetime(&reply);
+    assert_eq!(a, addr("fd00::5"));
+    assert_ne!(valid, 0);
+    assert!(server.allocator().is_in_use(addr("fd00::5")));
+
+    // Another client can't take it, nor is it handed a different one
+    let reply = server
+        .handle_client_msg(now, &rebind(2, "fd00::5"))
+        .unwrap();
+    assert_eq!(lifetime(&reply), (addr("fd00::5"), 0));
+    assert_eq!(server.allocator().in_use(), 1);
+}
+
+#[test]
+fn test_pool_rapid_commit() {
+    use crate::server::{Server, ServerConfig};
+    use std::time::SystemTime;
+
+    let mut solicit = test_client_msg(MsgType::Solicit, 1, None, &[1]);
+    solicit.options.push(options::Dhcpv6Option::RapidCommit);
+    let answer = |pool_policy: Option<bool>, server_policy: bool| {
+        let mut pool = AddressPool::new(addr("fd00::"), 120, Strategy::Sequential).unwrap();
+        pool.set_rapid_commit(pool_policy);
+        let mut config = ServerConfig::new(test_duid(100));
+        config.rapid_commit = server_policy;
+        let mut server = Server::new(config, pool);
+        server
+            .handle_client_msg(SystemTime::now(), &solicit)
+            .unwrap()
+            .msg_type
+    };
+
+    // Without a policy of its own, the pool follows the server
+    assert_eq!(answer(None, true), MsgType::Reply);
+    assert_eq!(answer(None, false), MsgType::Advertise);
+    assert_eq!(answer(Some(false), true), MsgType::Advertise);
+    assert_eq!(answer(Some(true), false), MsgType::Reply);
+}
@@ -0,0 +1,336 @@
+// Copyright 2021 Oxide Computer Company
+
+//! An allocator for the prefixes delegated in IA_PD options (rfc8415,
+//! section 6.3).
+//!
+//! The pool carves delegations out of a single parent prefix, such as a /48.
+//! Delegations are usually of a configured length, but a client may hint
+//! that it wants a longer or shorter prefix.  Only the delegations which
+//! have been handed out are recorded, keyed by their first address, so the
+//! free space is found by walking the gaps between them.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use crate::options::{Dhcpv6Option, IaPdOption, IaPrefixOption};
+use crate::*;
+
+/// A prefix delegated to a client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delegation {
+    pub prefix: Ipv6Addr,
+    pub prefix_len: u8,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    /// When the prefix is returned to the pool
+    pub expires: SystemTime,
+}
+
+impl Delegation {
+    /// Returns the IAPREFIX option describing the delegation
+    pub fn to_option(&self) -> IaPrefixOption {
+        let mut opt = IaPrefixOption::new(self.prefix, self.prefix_len);
+        opt.preferred_lifetime = self.preferred_lifetime;
+        opt.valid_lifetime = self.valid_lifetime;
+        opt
+    }
+}
+
+/// A pool of prefixes to be delegated to requesting routers
+#[derive(Debug)]
+pub struct PrefixPool {
+    base: u128,
+    prefix_len: u8,
+    delegated_len: u8,
+    // The range of lengths a client may ask for
+    min_len: u8,
+    max_len: u8,
+    preferred_lifetime: u32,
+    valid_lifetime: u32,
+    in_use: BTreeMap<u128, Delegation>,
+}
+
+// Returns the number of addresses covered by a prefix of the given length,
+// less one so a /0 doesn't overflow
+fn span(len: u8) -> u128 {
+    u128::MAX.checked_shr(len as u32).unwrap_or(0)
+}
+
+fn mask(addr: u128, len: u8) -> u128 {
+    addr & !span(len)
+}
+
+impl PrefixPool {
+    /// Returns a pool which splits the given prefix into delegations of
+    /// delegated_len bits.  Clients may not ask for any other length until
+    /// set_length_range() is called.
+    pub fn new(prefix: Ipv6Addr, prefix_len: u8, delegated_len: u8) -> Result<Self> {
+        if prefix_len > 128 || delegated_len > 128 || delegated_len < prefix_len {
+            return Err(Error::Other(format!(
+                "can't delegate /{}s from a /{}",
+                delegated_len, prefix_len
+            )));
+        }
+        Ok(PrefixPool {
+            base: mask(u128::from(prefix), prefix_len),
+            prefix_len,
+            delegated_len,
+            min_len: delegated_len,
+            max_len: delegated_len,
+            preferred_lifetime: 3600,
+            valid_lifetime: 7200,
+            in_use: BTreeMap::new(),
+        })
+    }
+
+    /// Sets the range of prefix lengths which will be honoured when a client
+    /// includes a length hint.  Hints outside this range are clamped to it.
+    pub fn set_length_range(&mut self, min_len: u8, max_len: u8) -> Result<()> {
+        if min_len < self.prefix_len || max_len > 128 || min_len > max_len {
+            return Err(Error::Other(format!(
+                "invalid delegation length range /{}-/{}",
+                min_len, max_len
+            )));
+        }
+        self.min_len = min_len;
+        self.max_len = max_len;
+        Ok(())
+    }
+
+    pub fn set_lifetimes(&mut self, preferred_lifetime: u32, valid_lifetime: u32) {
+        self.preferred_lifetime = preferred_lifetime;
+        self.valid_lifetime = valid_lifetime;
+    }
+
+    /// Returns the number of prefixes currently delegated
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// Returns true if the prefix lies within the pool
+    pub fn contains(&self, prefix: Ipv6Addr, prefix_len: u8) -> bool {
+        prefix_len >= self.prefix_len && mask(u128::from(prefix), self.prefix_len) == self.base
+    }
+
+    // Returns true if no delegation overlaps the block starting at start
+    fn is_free(&self, start: u128, len: u8) -> bool {
+        let end = start + span(len);
+        // The only delegation that can overlap from below is the last one
+        // starting before the block.
+        if let Some((s, d)) = self.in_use.range(..start).next_back() {
+            if s + span(d.prefix_len) >= start {
+                return false;
+            }
+        }
+        self.in_use.range(start..=end).next().is_none()
+    }
+
+    // Returns the lowest free block of the given length
+    fn find_free(&self, len: u8) -> Option<u128> {
+        let size = span(len);
+        let end = self.base + span(self.prefix_len);
+        let align = |a: u128| a.checked_add(size).map(|x| mask(x, len));
+
+        let mut cursor = Some(self.base);
+        for (start, d) in self.in_use.iter() {
+            let c = cursor?;
+            let aligned = if mask(c, len) == c { c } else { align(c)? };
+            if aligned < *start && start - aligned > size {
+                return Some(aligned);
+            }
+            cursor = (start + span(d.prefix_len)).checked_add(1);
+        }
+        let c = cursor?;
+        let aligned = if mask(c, len) == c { c } else { align(c)? };
+        if aligned <= end && end - aligned >= size {
+            Some(aligned)
+        } else {
+            None
+        }
+    }
+
+    // Picks the length to delegate, given the client's hint
+    fn choose_len(&self, hint: Option<u8>) -> u8 {
+        match hint {
+            None | Some(0) => self.delegated_len,
+            Some(len) => len.clamp(self.min_len, self.max_len),
+        }
+    }
+
+    fn delegate(&mut self, now: SystemTime, start: u128, len: u8) -> Delegation {
+        let d = Delegation {
+            prefix: Ipv6Addr::from(start),
+            prefix_len: len,
+            preferred_lifetime: self.preferred_lifetime,
+            valid_lifetime: self.valid_lifetime,
+            expires: now + Duration::from_secs(self.valid_lifetime as u64),
+        };
+        self.in_use.insert(start, d.clone());
+        d
+    }
+
+    /// Delegates a prefix.  The hint is the prefix and length from an
+    /// IAPREFIX option sent by the client: a non-zero prefix asks for that
+    /// specific prefix, and a non-zero length asks for a prefix of that
+    /// length.  Returns None if there is no room left in the pool.
+    pub fn allocate(
+        &mut self,
+        now: SystemTime,
+        hint: Option<(Ipv6Addr, u8)>,
+    ) -> Option<Delegation> {
+        self.reclaim(now);
+        let len = self.choose_len(hint.map(|(_, len)| len));
+
+        if let Some((prefix, hint_len)) = hint {
+            let start = u128::from(prefix);
+            if start != 0
+                && hint_len == len
+                && mask(start, len) == start
+                && self.contains(prefix, len)
+                && self.is_free(start, len)
+            {
+                return Some(self.delegate(now, start, len));
+            }
+        }
+
+        let start = self.find_free(len)?;
+        Some(self.delegate(now, start, len))
+    }
+
+    /// Delegates a prefix for an IA_PD, using the first IAPREFIX option in
+    /// it as a hint.
+    pub fn allocate_for(&mut self, now: SystemTime, ia: &IaPdOption) -> Option<Delegation> {
+        let hint = ia.options.iter().find_map(|o| match o {
+            Dhcpv6Option::IaPrefix(p) => Some((p.prefix, p.prefix_len)),
+            _ => None,
+        });
+        self.allocate(now, hint)
+    }
+
+    /// Extends the lifetimes of a delegated prefix.  Returns None if the
+    /// prefix is not currently delegated.
+    pub fn renew(
+        &mut self,
+        now: SystemTime,
+        prefix: Ipv6Addr,
+        prefix_len: u8,
+    ) -> Option<Delegation> {
+        let start = u128::from(prefix);
+        match self.in_use.get(&start) {
+            Some(d) if d.prefix_len == prefix_len => Some(self.delegate(now, start, prefix_len)),
+            _ => None,
+        }
+    }
+
+    /// Returns a delegated prefix to the pool
+    pub fn release(&mut self, prefix: Ipv6Addr, prefix_len: u8) -> bool {
+        let start = u128::from(prefix);
+        match self.in_use.get(&start) {
+            Some(d) if d.prefix_len == prefix_len => self.in_use.remove(&start).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Returns any delegations whose valid lifetime has run out to the pool
+    pub fn reclaim(&mut self, now: SystemTime) -> Vec<Delegation> {
+        let expired: Vec<u128> = self
+            .in_use
+            .iter()
+            .filter(|(_, d)| d.expires <= now)
+            .map(|(s, _)| *s)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|s| self.in_use.remove(&s))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+use crate::test::addr;
+
+#[test]
+fn test_prefix_pool_split() {
+    let mut pool = PrefixPool::new(addr("2001:db8:1::"), 48, 56).unwrap();
+    let now = SystemTime::now();
+
+    let mut got = Vec::new();
+    while let Some(d) = pool.allocate(now, None) {
+        assert_eq!(d.prefix_len, 56);
+        got.push(d.prefix);
+    }
+    assert_eq!(got.len(), 256);
+    assert_eq!(got[0], addr("2001:db8:1::"));
+    assert_eq!(got[1], addr("2001:db8:1:100::"));
+    assert_eq!(got[255], addr("2001:db8:1:ff00::"));
+
+    // A released prefix is the only one available
+    assert!(pool.release(addr("2001:db8:1:4200::"), 56));
+    assert!(!pool.release(addr("2001:db8:1:4200::"), 56));
+    let d = pool.allocate(now, None).unwrap();
+    assert_eq!(d.prefix, addr("2001:db8:1:4200::"));
+}
+
+#[test]
+fn test_prefix_pool_hints() {
+    let mut pool = PrefixPool::new(addr("2001:db8:1::"), 48, 56).unwrap();
+    pool.set_length_range(52, 64).unwrap();
+    let now = SystemTime::now();
+
+    // A length hint is honoured within the configured range
+    let d = pool.allocate(now, Some((addr("::"), 60))).unwrap();
+    assert_eq!((d.prefix, d.prefix_len), (addr("2001:db8:1::"), 60));
+
+    // Shorter prefixes are aligned past the ones already delegated
+    let d = pool.allocate(now, Some((addr("::"), 48))).unwrap();
+    assert_eq!((d.prefix, d.prefix_len), (addr("2001:db8:1:1000::"), 52));
+    let d = pool.allocate(now, None).unwrap();
+    assert_eq!((d.prefix, d.prefix_len), (addr("2001:db8:1:100::"), 56));
+    let d = pool.allocate(now, Some((addr("::"), 64))).unwrap();
+    assert_eq!((d.prefix, d.prefix_len), (addr("2001:db8:1:10::"), 64));
+
+    // A specific prefix is honoured if it's free, and otherwise ignored
+    let d = pool
+        .allocate(now, Some((addr("2001:db8:1:8000::"), 56)))
+        .unwrap();
+    assert_eq!(d.prefix, addr("2001:db8:1:8000::"));
+    let d = pool
+        .allocate(now, Some((addr("2001:db8:1:1000::"), 56)))
+        .unwrap();
+    assert_eq!(d.prefix, addr("2001:db8:1:200::"));
+
+    // The IAPREFIX option carries the delegation to the client
+    let opt = d.to_option();
+    assert_eq!(opt.prefix, addr("2001:db8:1:200::"));
+    assert_eq!(opt.prefix_len, 56);
+    assert_eq!((opt.preferred_lifetime, opt.valid_lifetime), (3600, 7200));
+}
+
+#[test]
+fn test_prefix_pool_reclaim() {
+    let mut pool = PrefixPool::new(addr("2001:db8:1::"), 62, 64).unwrap();
+    pool.set_lifetimes(100, 200);
+    let now = SystemTime::now();
+
+    let mut ia = IaPdOption::new(1);
+    ia.options.push(Dhcpv6Option::IaPrefix(IaPrefixOption::new(
+        addr("2001:db8:1:3::"),
+        64,
+    )));
+    let first = pool.allocate_for(now, &ia).unwrap();
+    assert_eq!(first.prefix, addr("2001:db8:1:3::"));
+    for _ in 0..3 {
+        pool.allocate(now, None).unwrap();
+    }
+    assert!(pool.allocate(now, None).is_none());
+
+    // Renewing one delegation keeps it alive while the rest expire
+    let later = now + Duration::from_secs(150);
+    assert!(pool.renew(later, first.prefix, 64).is_some());
+    assert!(pool.renew(later, addr("2001:db8:1:3::"), 60).is_none());
+    let expired = pool.reclaim(now + Duration::from_secs(200));
+    assert_eq!(expired.len(), 3);
+    assert_eq!(pool.in_use(), 1);
+    assert!(pool.contains(addr("2001:db8:1:2::"), 64));
+    assert!(!pool.contains(addr("2001:db8:1:4::"), 64));
+}
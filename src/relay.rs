@@ -0,0 +1,334 @@
+// Copyright 2021 Oxide Computer Company
+
+//! A sans-IO DHCPv6 relay agent, as described in rfc8415, section 19.
+//!
+//! Messages arriving from clients, or from relays closer to the clients, are
+//! wrapped in a Relay-forward message and sent on towards the servers.  When
+//! a server's Relay-reply comes back, the relay unwraps it and uses the
+//! Interface-ID and link-address it supplied in the Relay-forward to decide
+//! where the contents should be delivered.
+
+use crate::options::{Dhcpv6Option, OPTION_INTERFACE_ID};
+use crate::*;
+
+/// A client-facing interface on which the relay listens
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayInterface {
+    pub ifindex: u32,
+    /// A global or unique local address assigned to the interface, which
+    /// tells the server which link the client is on.  This may be
+    /// unspecified, in which case the server must rely on the Interface-ID.
+    pub link_addr: Ipv6Addr,
+    /// If set, an Interface-ID option carrying this value is included in
+    /// every Relay-forward for the interface.  Otherwise, one holding the
+    /// ifindex is included whenever the link-address is unspecified.
+    pub interface_id: Option<Vec<u8>>,
+}
+
+impl RelayInterface {
+    // Returns the Interface-ID which identifies the interface
+    fn id(&self) -> Vec<u8> {
+        match &self.interface_id {
+            Some(id) => id.clone(),
+            None => self.ifindex.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// A Relay-forward message to be sent towards a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Forward {
+    pub msg: RelayMsg,
+    pub dest: Ipv6Addr,
+}
+
+/// A message unwrapped from a Relay-reply, to be sent towards a client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deliver {
+    /// The interface on which to send the message
+    pub ifindex: u32,
+    pub dest: Ipv6Addr,
+    /// The client port if the message is for a client, or the server port if
+    /// it is for another relay
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+/// A sans-IO relay agent
+#[derive(Clone, Debug)]
+pub struct RelayAgent {
+    interfaces: Vec<RelayInterface>,
+    servers: Vec<Ipv6Addr>,
+}
+
+impl RelayAgent {
+    /// Returns a relay which forwards messages to each of the given
+    /// destinations.  These may be the unicast addresses of servers, or the
+    /// All_DHCP_Servers multicast address.
+    pub fn new(servers: Vec<Ipv6Addr>) -> Self {
+        RelayAgent {
+            interfaces: Vec::new(),
+            servers,
+        }
+    }
+
+    pub fn add_interface(&mut self, interface: RelayInterface) {
+        self.interfaces.retain(|i| i.ifindex != interface.ifindex);
+        self.interfaces.push(interface);
+    }
+
+    pub fn interfaces(&self) -> &[RelayInterface] {
+        &self.interfaces
+    }
+
+    /// Wraps a message received on a client-facing interface in a
+    /// Relay-forward, returning a copy for each server.  The message may
+    /// come from a client, or from another relay.
+    pub fn forward(&self, ifindex: u32, src: Ipv6Addr, data: &[u8]) -> Result<Vec<Forward>> {
+        let interface = self
+            .interfaces
+            .iter()
+            .find(|i| i.ifindex == ifindex)
+            .ok_or_else(|| Error::Other(format!("unknown interface: {}", ifindex)))?;
+        let code = *data.first().ok_or(Error::TooShort)?;
+
+        let hop_count = match MsgType::from_code(code) {
+            MsgType::RelayForw => {
+                let inner = RelayMsg::decode(data)?;
+                if inner.hop_count as u32 >= params::HOP_COUNT_LIMIT {
+                    return Err(Error::Other("hop count limit exceeded".into()));
+                }
+                inner.hop_count + 1
+            }
+            // Messages only a server may send are never forwarded
+            MsgType::Advertise
+            | MsgType::Reply
+            | MsgType::Reconfigure
+            | MsgType::RelayRepl
+            | MsgType::Unknown(_) => return Err(Error::UnknownMsgCode(code)),
+            _ => 0,
+        };
+
+        // rfc8415, section 19.1.1: a message from another relay is given an
+        // unspecified link-address, since that relay has already identified
+        // the client's link.  The Relay-reply then has nothing but the
+        // Interface-ID to say which interface it belongs on.
+        let link_addr = match hop_count {
+            0 => interface.link_addr,
+            _ => Ipv6Addr::UNSPECIFIED,
+        };
+        let mut option = Vec::new();
+        if interface.interface_id.is_some() || link_addr.is_unspecified() {
+            option.push(Dhcpv6Option::InterfaceId(interface.id()));
+        }
+        option.push(Dhcpv6Option::RelayMsg(data.to_vec()));
+        let msg = RelayMsg {
+            msg_type: MsgType::RelayForw,
+            hop_count,
+            link_addr,
+            peer_addr: src,
+            option,
+        };
+
+        Ok(self
+            .servers
+            .iter()
+            .map(|dest| Forward {
+                msg: msg.clone(),
+                dest: *dest,
+            })
+            .collect())
+    }
+
+    /// Unwraps a Relay-reply received from a server, returning the message
+    /// it carries and where it should be delivered.
+    pub fn reply(&self, data: &[u8]) -> Result<Deliver> {
+        let mut msg = RelayMsg::decode(data)?;
+        if msg.msg_type != MsgType::RelayRepl {
+            return Err(Error::UnknownMsgCode(u8::from(msg.msg_type)));
+        }
+        let payload = msg.take_payload()?;
+
+        // Prefer the Interface-ID, since that's what it's for.  Without one,
+        // fall back to the link-address we put in the Relay-forward.
+        let interface = match msg.find_one_option(OPTION_INTERFACE_ID) {
+            Some(Dhcpv6Option::InterfaceId(id)) => self.interfaces.iter().find(|i| &i.id() == id),
+            _ => self
+                .interfaces
+                .iter()
+                .find(|i| !i.link_addr.is_unspecified() && i.link_addr == msg.link_addr),
+        }
+        .ok_or_else(|| Error::Other("no interface matches the relay reply".into()))?;
+
+        let port = match payload.first().map(|c| MsgType::from_code(*c)) {
+            Some(MsgType::RelayRepl) => params::SERVER_PORT,
+            Some(_) => params::CLIENT_PORT,
+            None => return Err(Error::TooShort),
+        };
+        Ok(Deliver {
+            ifindex: interface.ifindex,
+            dest: msg.peer_addr,
+            port,
+            data: payload,
+        })
+    }
+}
+
+#[cfg(test)]
+fn test_agent() -> RelayAgent {
+    let mut agent = RelayAgent::new(vec!["fd00::547".parse().unwrap()]);
+    agent.add_interface(RelayInterface {
+        ifindex: 2,
+        link_addr: "fd00:2::1".parse().unwrap(),
+        interface_id: None,
+    });
+    agent.add_interface(RelayInterface {
+        ifindex: 3,
+        link_addr: Ipv6Addr::UNSPECIFIED,
+        interface_id: Some(b"port3".to_vec()),
+    });
+    agent
+}
+
+#[cfg(test)]
+use crate::test::test_client_msg;
+
+#[test]
+fn test_relay_forward() {
+    let agent = test_agent();
+    let client: Ipv6Addr = "fe80::1".parse().unwrap();
+    let solicit = test_client_msg(MsgType::Solicit, 1, None, &[])
+        .encode()
+        .unwrap();
+
+    let fwd = agent.forward(2, client, &solicit).unwrap();
+    assert_eq!(fwd.len(), 1);
+    assert_eq!(fwd[0].dest, "fd00::547".parse::<Ipv6Addr>().unwrap());
+    let msg = &fwd[0].msg;
+    assert_eq!(msg.hop_count, 0);
+    assert_eq!(msg.link_addr, "fd00:2::1".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(msg.peer_addr, client);
+    assert!(!msg.has_option(OPTION_INTERFACE_ID));
+    assert_eq!(
+        msg.find_one_option(options::OPTION_RELAY_MSG),
+        Some(&Dhcpv6Option::RelayMsg(solicit.clone()))
+    );
+
+    // The Interface-ID is included when the interface has one
+    let fwd = agent.forward(3, client, &solicit).unwrap();
+    assert_eq!(
+        fwd[0].msg.find_one_option(OPTION_INTERFACE_ID),
+        Some(&Dhcpv6Option::InterfaceId(b"port3".to_vec()))
+    );
+
+    // The server sees the whole thing as a relayed Solicit
+    let decoded = Message::decode(&fwd[0].msg.encode().unwrap()).unwrap();
+    assert_eq!(decoded.client_msg().msg_type, MsgType::Solicit);
+
+    assert!(agent.forward(9, client, &solicit).is_err());
+    let mut reply = solicit.clone();
+    reply[0] = u8::from(MsgType::Reply);
+    assert!(agent.forward(2, client, &reply).is_err());
+}
+
+#[test]
+fn test_relay_hop_count() {
+    let agent = test_agent();
+    let relay: Ipv6Addr = "fd00:2::99".parse().unwrap();
+
+    let solicit = test_client_msg(MsgType::Solicit, 1, None, &[]);
+    let mut inner = RelayMsg {
+        msg_type: MsgType::RelayForw,
+        hop_count: 4,
+        link_addr: "fd00:9::1".parse().unwrap(),
+        peer_addr: "fe80::1".parse().unwrap(),
+        option: vec![Dhcpv6Option::RelayMsg(solicit.encode().unwrap())],
+    };
+    let fwd = agent.forward(2, relay, &inner.encode().unwrap()).unwrap();
+    assert_eq!(fwd[0].msg.hop_count, 5);
+    assert!(fwd[0].msg.link_addr.is_unspecified());
+    assert_eq!(fwd[0].msg.peer_addr, relay);
+
+    // Without a link-address, the interface is identified by its ifindex
+    assert_eq!(
+        fwd[0].msg.find_one_option(OPTION_INTERFACE_ID),
+        Some(&Dhcpv6Option::InterfaceId(vec![0, 0, 0, 2]))
+    );
+
+    inner.hop_count = params::HOP_COUNT_LIMIT as u8;
+    assert!(agent.forward(2, relay, &inner.encode().unwrap()).is_err());
+}
+
+#[test]
+fn test_relay_reply() {
+    let agent = test_agent();
+    let client: Ipv6Addr = "fe80::1".parse().unwrap();
+    let reply = ClientMsg::new(MsgType::Advertise, Some(0x1234))
+        .encode()
+        .unwrap();
+
+    // The Interface-ID picks the interface
+    let mut msg = RelayMsg {
+        msg_type: MsgType::RelayRepl,
+        hop_count: 0,
+        link_addr: Ipv6Addr::UNSPECIFIED,
+        peer_addr: client,
+        option: vec![
+            Dhcpv6Option::InterfaceId(b"port3".to_vec()),
+            Dhcpv6Option::RelayMsg(reply.clone()),
+        ],
+    };
+    let out = agent.reply(&msg.encode().unwrap()).unwrap();
+    assert_eq!(
+        out,
+        Deliver {
+            ifindex: 3,
+            dest: client,
+            port: params::CLIENT_PORT,
+            data: reply.clone(),
+        }
+    );
+
+    // Without one, the link-address is used
+    msg.option.remove(0);
+    msg.link_addr = "fd00:2::1".parse().unwrap();
+    assert_eq!(agent.reply(&msg.encode().unwrap()).unwrap().ifindex, 2);
+    msg.link_addr = "fd00:7::1".parse().unwrap();
+    assert!(agent.reply(&msg.encode().unwrap()).is_err());
+}
+
+#[test]
+fn test_relay_nested_reply() {
+    let agent = test_agent();
+    let mut downstream = RelayAgent::new(vec!["fd00:2::1".parse().unwrap()]);
+    downstream.add_interface(RelayInterface {
+        ifindex: 5,
+        link_addr: "fd00:9::1".parse().unwrap(),
+        interface_id: None,
+    });
+    let client: Ipv6Addr = "fe80::1".parse().unwrap();
+    let relay: Ipv6Addr = "fd00:2::99".parse().unwrap();
+
+    // A Solicit passes through both relays on its way to the server
+    let solicit = test_client_msg(MsgType::Solicit, 1, None, &[]);
+    let inner = downstream
+        .forward(5, client, &solicit.encode().unwrap())
+        .unwrap();
+    let inner = inner[0].msg.encode().unwrap();
+    let outer = agent.forward(2, relay, &inner).unwrap();
+    let request = Message::decode(&outer[0].msg.encode().unwrap()).unwrap();
+
+    // The server's answer finds its way back along the same path
+    let advertise = ClientMsg::new(MsgType::Advertise, Some(solicit.tx_id));
+    let reply = request.reply(advertise.clone()).encode().unwrap();
+    let out = agent.reply(&reply).unwrap();
+    assert_eq!(out.ifindex, 2);
+    assert_eq!(out.dest, relay);
+    assert_eq!(out.port, params::SERVER_PORT);
+
+    let out = downstream.reply(&out.data).unwrap();
+    assert_eq!(out.ifindex, 5);
+    assert_eq!(out.dest, client);
+    assert_eq!(out.port, params::CLIENT_PORT);
+    assert_eq!(out.data, advertise.encode().unwrap());
+}
@@ -0,0 +1,287 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Computes the retransmission timeouts for a client message, as described in
+//! rfc8415, section 15.
+//!
+//! Each timeout is derived from the previous one, with a random factor added
+//! so that clients which start at the same time don't stay synchronized:
+//!
+//! ```text
+//!     RT = IRT + RAND*IRT             (first transmission)
+//!     RT = 2*RTprev + RAND*RTprev     (subsequent transmissions)
+//!     RT = MRT + RAND*MRT             (if RT would exceed MRT)
+//! ```
+//!
+//! where RAND is chosen uniformly from -0.1 to 0.1.  Retransmission stops
+//! once the message has been sent MRC times, or MRD seconds after it was
+//! first sent.
+
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::*;
+
+/// Tracks the retransmission of a single message
+pub struct Retransmitter<R: Rng = StdRng> {
+    params: RetransmitParams,
+    mrd: Option<Duration>,
+    // rfc8415, section 18.2.1 requires the first RAND for Solicit to be
+    // strictly positive, so the first Solicit isn't retransmitted early.
+    positive_first: bool,
+    rng: R,
+    start: Option<Instant>,
+    deadline: Option<Instant>,
+    rt: Duration,
+    count: u32,
+}
+
+impl<R: Rng> fmt::Debug for Retransmitter<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}  mrd: {:?}  rt: {:?}  count: {}",
+            self.params, self.mrd, self.rt, self.count
+        )
+    }
+}
+
+impl Retransmitter<StdRng> {
+    /// Returns a Retransmitter for the given message type, or None if the
+    /// message isn't retransmitted.
+    pub fn new(msg_type: MsgType) -> Option<Self> {
+        Self::with_rng(msg_type, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> Retransmitter<R> {
+    /// Returns a Retransmitter for the given message type, drawing its
+    /// random factors from the supplied RNG.
+    pub fn with_rng(msg_type: MsgType, rng: R) -> Option<Self> {
+        let params = retransmit_params(msg_type)?;
+        let mut r = Self::with_params(params, rng);
+        r.positive_first = msg_type == MsgType::Solicit;
+        Some(r)
+    }
+
+    /// Returns a Retransmitter using an arbitrary set of parameters
+    pub fn with_params(params: RetransmitParams, rng: R) -> Self {
+        let mrd = match params.mrd {
+            0 => None,
+            mrd => Some(Duration::from_secs(mrd as u64)),
+        };
+        Retransmitter {
+            params,
+            mrd,
+            positive_first: false,
+            rng,
+            start: None,
+            deadline: None,
+            rt: Duration::from_secs(0),
+            count: 0,
+        }
+    }
+
+    /// Sets the maximum retransmission duration.  Renew and Rebind have no
+    /// fixed MRD: a Renew is retransmitted until T2, and a Rebind until the
+    /// valid lifetimes of all the client's addresses have expired.  Unlike
+    /// the MRD in RetransmitParams, a duration of 0 here means the exchange
+    /// fails as soon as the first timeout expires.
+    pub fn set_mrd(&mut self, mrd: Duration) {
+        self.mrd = Some(mrd);
+    }
+
+    // Returns base + RAND*scale, computed with millisecond granularity
+    fn jitter(&mut self, base: Duration, scale: Duration, positive: bool) -> Duration {
+        let max = (scale.as_millis() as i64 / 10).max(1);
+        let rand = if positive {
+            self.rng.gen_range(1..=max)
+        } else {
+            self.rng.gen_range(-max..=max)
+        };
+        Duration::from_millis((base.as_millis() as i64 + rand).max(0) as u64)
+    }
+
+    // Clamps the deadline to the end of the maximum retransmission duration
+    fn deadline_after(&self, now: Instant) -> Instant {
+        let next = now + self.rt;
+        match (self.start, self.mrd) {
+            (Some(start), Some(mrd)) => next.min(start + mrd),
+            _ => next,
+        }
+    }
+
+    /// Records the first transmission of the message, returning the time at
+    /// which it should be retransmitted.
+    pub fn start<C: Clock>(&mut self, clock: &C) -> Instant {
+        let now = clock.now();
+        let irt = Duration::from_secs(self.params.irt as u64);
+        self.rt = self.jitter(irt, irt, self.positive_first);
+        self.start = Some(now);
+        self.count = 1;
+        let deadline = self.deadline_after(now);
+        self.deadline = Some(deadline);
+        deadline
+    }
+
+    /// Called when the current timeout expires.  Returns the time at which
+    /// the message should next be retransmitted, or None if the exchange has
+    /// failed.  A retransmission is due whenever Some is returned.
+    pub fn next<C: Clock>(&mut self, clock: &C) -> Option<Instant> {
+        let now = clock.now();
+        let start = self.start?;
+        if self.params.mrc != 0 && self.count >= self.params.mrc {
+            self.deadline = None;
+            return None;
+        }
+        if let Some(mrd) = self.mrd {
+            if now >= start + mrd {
+                self.deadline = None;
+                return None;
+            }
+        }
+
+        let mut rt = self.jitter(self.rt * 2, self.rt, false);
+        let mrt = Duration::from_secs(self.params.mrt as u64);
+        if self.params.mrt != 0 && rt > mrt {
+            rt = self.jitter(mrt, mrt, false);
+        }
+        self.rt = rt;
+        self.count += 1;
+        let deadline = self.deadline_after(now);
+        self.deadline = Some(deadline);
+        Some(deadline)
+    }
+
+    /// Returns the time the message was first transmitted
+    pub fn started(&self) -> Option<Instant> {
+        self.start
+    }
+
+    /// Returns the time at which the current timeout expires
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the current retransmission timeout
+    pub fn rt(&self) -> Duration {
+        self.rt
+    }
+
+    /// Returns the number of times the message has been transmitted
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+fn within(rt: Duration, base: u64) -> bool {
+    let rt = rt.as_millis() as u64;
+    rt >= base * 900 && rt <= base * 1100
+}
+
+#[test]
+fn test_solicit_timeouts() {
+    for seed in 0..100 {
+        let rng = StdRng::seed_from_u64(seed);
+        let mut r = Retransmitter::with_rng(MsgType::Solicit, rng).unwrap();
+        let mut now = Instant::now();
+
+        now = r.start(&now);
+        let irt = Duration::from_secs(params::SOL_TIMEOUT as u64);
+        assert!(r.rt() > irt);
+        assert!(within(r.rt(), params::SOL_TIMEOUT as u64));
+
+        // Solicit has neither an MRC nor an MRD, so the timeout keeps growing
+        // until it reaches SOL_MAX_RT.
+        let mut prev = r.rt();
+        for _ in 0..20 {
+            now = r.next(&now).unwrap();
+            let rt = r.rt();
+            let max = Duration::from_secs(params::SOL_MAX_RT as u64);
+            if prev.mul_f64(2.1) < max {
+                assert!(rt >= prev.mul_f64(1.9) && rt <= prev.mul_f64(2.1));
+            } else {
+                assert!(within(rt, params::SOL_MAX_RT as u64));
+            }
+            prev = rt;
+        }
+        assert!(within(prev, params::SOL_MAX_RT as u64));
+        assert_eq!(r.count(), 21);
+    }
+}
+
+#[test]
+fn test_max_retransmit_count() {
+    let rng = StdRng::seed_from_u64(1);
+    let mut r = Retransmitter::with_rng(MsgType::Request, rng).unwrap();
+    let mut now = r.start(&Instant::now());
+
+    // The first RAND may be negative for anything other than Solicit
+    assert!(within(r.rt(), params::REQ_TIMEOUT as u64));
+    while let Some(deadline) = r.next(&now) {
+        now = deadline;
+    }
+    assert_eq!(r.count(), params::REQ_MAX_RC);
+    assert!(r.deadline().is_none());
+}
+
+#[test]
+fn test_max_retransmit_duration() {
+    let rng = StdRng::seed_from_u64(2);
+    let mut r = Retransmitter::with_rng(MsgType::Confirm, rng).unwrap();
+    let t0 = Instant::now();
+    let end = t0 + Duration::from_secs(params::CNF_MAX_RD as u64);
+
+    let mut now = r.start(&t0);
+    let mut last = now;
+    while let Some(deadline) = r.next(&now) {
+        assert!(deadline <= end);
+        last = deadline;
+        now = deadline;
+    }
+    assert_eq!(last, end);
+}
+
+#[test]
+fn test_dynamic_mrd() {
+    // A Renew is retransmitted until T2
+    let rng = StdRng::seed_from_u64(3);
+    let mut r = Retransmitter::with_rng(MsgType::Renew, rng).unwrap();
+    r.set_mrd(Duration::from_secs(100));
+    let t0 = Instant::now();
+    let mut now = r.start(&t0);
+    let mut sent = 1;
+    while let Some(deadline) = r.next(&now) {
+        now = deadline;
+        sent += 1;
+    }
+    assert_eq!(now, t0 + Duration::from_secs(100));
+    assert!(sent > 2);
+
+    // An MRD of 0 means give up when the first timeout expires
+    let rng = StdRng::seed_from_u64(4);
+    let mut r = Retransmitter::with_rng(MsgType::Rebind, rng).unwrap();
+    r.set_mrd(Duration::from_secs(0));
+    let now = r.start(&t0);
+    assert_eq!(now, t0);
+    assert!(r.next(&now).is_none());
+}
+
+#[test]
+fn test_deterministic() {
+    let run = |seed| {
+        let rng = StdRng::seed_from_u64(seed);
+        let mut r = Retransmitter::with_rng(MsgType::InformationRequest, rng).unwrap();
+        let mut now = r.start(&Instant::now());
+        let mut timeouts = vec![r.rt()];
+        for _ in 0..10 {
+            now = r.next(&now).unwrap();
+            timeouts.push(r.rt());
+        }
+        timeouts
+    };
+    assert_eq!(run(5), run(5));
+    assert_ne!(run(5), run(6));
+}
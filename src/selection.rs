@@ -0,0 +1,376 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Chooses among the servers which respond to a Solicit, following the rules
+//! in rfc8415, section 18.2.9.
+//!
+//! Advertises are ranked first by their Preference option, then by how many
+//! of the IAs in the Solicit they offer leases for, and finally by the order
+//! in which they arrived.  An Advertise with the maximum preference (255)
+//! should be acted on immediately.  Otherwise the client is expected to keep
+//! collecting Advertises until the first retransmission timeout of the
+//! Solicit expires, and then choose the best of them.  Timing is left to the
+//! caller.
+
+use crate::options::*;
+use crate::*;
+
+/// The preference value which tells the client to stop waiting for other
+/// Advertises
+pub const MAX_PREFERENCE: u8 = 255;
+
+/// How many rejected Advertises are kept for inspection.  Once the limit is
+/// reached, the oldest is dropped to make room for each new one.
+pub const MAX_REJECTED: usize = 16;
+
+/// Why an Advertise was not considered
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rejection {
+    /// The message is not an Advertise
+    NotAdvertise,
+    /// The Advertise has no Server Identifier
+    NoServerId,
+    /// The server says it has no addresses available
+    NoAddrsAvail,
+    /// The Advertise offers no leases for any of the requested IAs
+    NothingOffered,
+}
+
+/// The result of adding an Advertise to the selection
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// The Advertise is a candidate.  The client should keep waiting for
+    /// others until the first RT has elapsed.
+    Candidate,
+    /// The Advertise carries the maximum preference, so the client should
+    /// choose a server now.
+    SelectNow,
+    Rejected(Rejection),
+}
+
+/// An acceptable Advertise, along with the attributes used to rank it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Offer {
+    pub server_id: Duid,
+    pub preference: u8,
+    /// The number of requested IAs for which leases were offered
+    pub ias_offered: usize,
+    /// The position of the Advertise in the order of arrival
+    pub arrival: usize,
+    pub msg: ClientMsg,
+}
+
+/// Why an offer is ranked above the one that follows it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    /// This offer has the maximum preference value, and the next does not
+    MaxPreference,
+    /// This offer has a higher preference value
+    Preference { this: u8, next: u8 },
+    /// The preferences are equal, but this offer covers more of the
+    /// requested IAs
+    MoreIas { this: usize, next: usize },
+    /// The offers are otherwise equal, but this one arrived first
+    Earlier,
+    /// No other offer ranks below this one
+    Last,
+}
+
+/// A single entry in the ranking of offers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ranked {
+    pub offer: Offer,
+    /// Why this offer outranks the next one
+    pub reason: Reason,
+}
+
+/// Collects the Advertises received in response to a Solicit
+#[derive(Clone, Debug, Default)]
+pub struct ServerSelection {
+    // The IA option types and IAIDs the client asked for
+    requested: Vec<(u16, u32)>,
+    offers: Vec<Offer>,
+    rejected: Vec<(ClientMsg, Rejection)>,
+    received: usize,
+}
+
+// Returns true if the option list carries the given status code
+fn has_status(options: &[Dhcpv6Option], code: StatusCode) -> bool {
+    find_all_options(options, OPTION_STATUS_CODE)
+        .iter()
+        .any(|o| matches!(o, Dhcpv6Option::StatusCode(s) if s.code == code))
+}
+
+// Returns the IA option type and IAID of an IA option
+fn ia_key(opt: &Dhcpv6Option) -> Option<(u16, u32)> {
+    match opt {
+        Dhcpv6Option::IaNa(ia) => Some((OPTION_IA_NA, ia.iaid)),
+        Dhcpv6Option::IaTa(ia) => Some((OPTION_IA_TA, ia.iaid)),
+        Dhcpv6Option::IaPd(ia) => Some((OPTION_IA_PD, ia.iaid)),
+        _ => None,
+    }
+}
+
+// Returns true if the IA option offers at least one lease
+fn ia_offers_lease(opt: &Dhcpv6Option) -> bool {
+    match opt {
+        Dhcpv6Option::IaNa(ia) => {
+            !has_status(&ia.options, StatusCode::NoAddrsAvail)
+                && ia.options.iter().any(|o| u16::from(o) == OPTION_IAADDR)
+        }
+        Dhcpv6Option::IaTa(ia) => {
+            !has_status(&ia.options, StatusCode::NoAddrsAvail)
+                && ia.options.iter().any(|o| u16::from(o) == OPTION_IAADDR)
+        }
+        Dhcpv6Option::IaPd(ia) => {
+            !has_status(&ia.options, StatusCode::NoPrefixAvail)
+                && ia.options.iter().any(|o| u16::from(o) == OPTION_IAPREFIX)
+        }
+        _ => false,
+    }
+}
+
+impl ServerSelection {
+    /// Begins a selection for the IAs requested in the given Solicit
+    pub fn new(solicit: &ClientMsg) -> Self {
+        ServerSelection {
+            requested: solicit.options.iter().filter_map(ia_key).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Evaluates an Advertise, adding it to the candidates if it is
+    /// acceptable.
+    pub fn add(&mut self, msg: &ClientMsg) -> Verdict {
+        match self.evaluate(msg) {
+            Ok(offer) => {
+                let immediate = offer.preference == MAX_PREFERENCE;
+                self.offers.push(offer);
+                if immediate {
+                    Verdict::SelectNow
+                } else {
+                    Verdict::Candidate
+                }
+            }
+            Err(rejection) => {
+                if self.rejected.len() >= MAX_REJECTED {
+                    self.rejected.remove(0);
+                }
+                self.rejected.push((msg.clone(), rejection.clone()));
+                Verdict::Rejected(rejection)
+            }
+        }
+    }
+
+    fn evaluate(&mut self, msg: &ClientMsg) -> std::result::Result<Offer, Rejection> {
+        if msg.msg_type != MsgType::Advertise {
+            return Err(Rejection::NotAdvertise);
+        }
+        let server_id = match msg.find_one_option(OPTION_SERVERID) {
+            Some(Dhcpv6Option::ServerId(id)) => id.clone(),
+            _ => return Err(Rejection::NoServerId),
+        };
+        if has_status(&msg.options, StatusCode::NoAddrsAvail) {
+            return Err(Rejection::NoAddrsAvail);
+        }
+
+        let ias_offered = msg
+            .options
+            .iter()
+            .filter(|o| matches!(ia_key(o), Some(k) if self.requested.contains(&k)))
+            .filter(|o| ia_offers_lease(o))
+            .count();
+        if ias_offered == 0 {
+            return Err(Rejection::NothingOffered);
+        }
+
+        let preference = match msg.find_one_option(OPTION_PREFERENCE) {
+            Some(Dhcpv6Option::Preference(x)) => *x,
+            _ => 0,
+        };
+        let arrival = self.received;
+        self.received += 1;
+        Ok(Offer {
+            server_id,
+            preference,
+            ias_offered,
+            arrival,
+            msg: msg.clone(),
+        })
+    }
+
+    /// Returns true if no acceptable Advertise has been received
+    pub fn is_empty(&self) -> bool {
+        self.offers.is_empty()
+    }
+
+    /// Returns the most recent Advertises which were rejected, and why, oldest
+    /// first.  At most MAX_REJECTED are kept.
+    pub fn rejected(&self) -> &[(ClientMsg, Rejection)] {
+        &self.rejected
+    }
+
+    /// Returns all of the acceptable offers, best first
+    pub fn ranking(&self) -> Vec<Ranked> {
+        let mut offers = self.offers.clone();
+        offers.sort_by(|a, b| {
+            b.preference
+                .cmp(&a.preference)
+                .then(b.ias_offered.cmp(&a.ias_offered))
+                .then(a.arrival.cmp(&b.arrival))
+        });
+
+        let mut ranking = Vec::new();
+        for (idx, offer) in offers.iter().enumerate() {
+            let reason = match offers.get(idx + 1) {
+                None => Reason::Last,
+                Some(next)
+                    if offer.preference == MAX_PREFERENCE && next.preference != MAX_PREFERENCE =>
+                {
+                    Reason::MaxPreference
+                }
+                Some(next) if offer.preference != next.preference => Reason::Preference {
+                    this: offer.preference,
+                    next: next.preference,
+                },
+                Some(next) if offer.ias_offered != next.ias_offered => Reason::MoreIas {
+                    this: offer.ias_offered,
+                    next: next.ias_offered,
+                },
+                Some(_) => Reason::Earlier,
+            };
+            ranking.push(Ranked {
+                offer: offer.clone(),
+                reason,
+            });
+        }
+        ranking
+    }
+
+    /// Returns the best offer, if there is one
+    pub fn choose(&self) -> Option<Ranked> {
+        self.ranking().into_iter().next()
+    }
+}
+
+#[cfg(test)]
+use crate::test::{test_client_msg, test_duid};
+
+#[cfg(test)]
+fn test_advertise(server: u8, preference: Option<u8>, ia_nas: &[u32]) -> ClientMsg {
+    let mut msg = ClientMsg::new(MsgType::Advertise, Some(0x1234));
+    msg.options
+        .push(Dhcpv6Option::ServerId(test_duid(server.into())));
+    if let Some(preference) = preference {
+        msg.options.push(Dhcpv6Option::Preference(preference));
+    }
+    for iaid in ia_nas {
+        let mut ia_na = IaNaOption::new(*iaid);
+        let addr = format!("fd00::{}:{}", server, iaid).parse().unwrap();
+        ia_na
+            .options
+            .push(Dhcpv6Option::IaAddr(IaAddrOption::new(addr)));
+        msg.options.push(Dhcpv6Option::IaNa(ia_na));
+    }
+    msg
+}
+
+#[test]
+fn test_selection_ranking() {
+    let mut sel = ServerSelection::new(&test_client_msg(MsgType::Solicit, 1, None, &[1, 2]));
+    assert!(sel.choose().is_none());
+
+    assert_eq!(sel.add(&test_advertise(1, None, &[1])), Verdict::Candidate);
+    assert_eq!(
+        sel.add(&test_advertise(2, Some(5), &[1])),
+        Verdict::Candidate
+    );
+    assert_eq!(
+        sel.add(&test_advertise(3, None, &[1, 2])),
+        Verdict::Candidate
+    );
+    assert_eq!(
+        sel.add(&test_advertise(4, None, &[1, 2])),
+        Verdict::Candidate
+    );
+
+    let ranking = sel.ranking();
+    let order: Vec<usize> = ranking.iter().map(|r| r.offer.arrival).collect();
+    assert_eq!(order, vec![1, 2, 3, 0]);
+    assert_eq!(ranking[0].reason, Reason::Preference { this: 5, next: 0 });
+    assert_eq!(ranking[1].reason, Reason::Earlier);
+    assert_eq!(ranking[2].reason, Reason::MoreIas { this: 2, next: 1 });
+    assert_eq!(ranking[3].reason, Reason::Last);
+    assert_eq!(sel.choose().unwrap().offer.preference, 5);
+}
+
+#[test]
+fn test_selection_max_preference() {
+    let mut sel = ServerSelection::new(&test_client_msg(MsgType::Solicit, 1, None, &[1, 2]));
+    assert_eq!(
+        sel.add(&test_advertise(1, Some(10), &[1, 2])),
+        Verdict::Candidate
+    );
+    assert_eq!(
+        sel.add(&test_advertise(2, Some(255), &[1])),
+        Verdict::SelectNow
+    );
+    let best = sel.choose().unwrap();
+    assert_eq!(best.reason, Reason::MaxPreference);
+    assert_eq!(best.offer.arrival, 1);
+
+    // When the runner-up also has the maximum preference, something else
+    // decided the choice
+    assert_eq!(
+        sel.add(&test_advertise(3, Some(255), &[1, 2])),
+        Verdict::SelectNow
+    );
+    let ranking = sel.ranking();
+    assert_eq!(ranking[0].offer.arrival, 2);
+    assert_eq!(ranking[0].reason, Reason::MoreIas { this: 2, next: 1 });
+    assert_eq!(ranking[1].reason, Reason::MaxPreference);
+}
+
+#[test]
+fn test_selection_rejects() {
+    let mut sel = ServerSelection::new(&test_client_msg(MsgType::Solicit, 1, None, &[1, 2]));
+
+    let mut no_addrs = test_advertise(1, Some(255), &[1]);
+    no_addrs
+        .options
+        .push(Dhcpv6Option::StatusCode(StatusCodeOption {
+            code: StatusCode::NoAddrsAvail,
+            msg: b"no addresses".to_vec(),
+        }));
+    assert_eq!(
+        sel.add(&no_addrs),
+        Verdict::Rejected(Rejection::NoAddrsAvail)
+    );
+
+    // An IA which wasn't requested doesn't count
+    assert_eq!(
+        sel.add(&test_advertise(2, None, &[7])),
+        Verdict::Rejected(Rejection::NothingOffered)
+    );
+
+    let mut no_server = test_advertise(3, None, &[1]);
+    no_server.options.remove(0);
+    assert_eq!(
+        sel.add(&no_server),
+        Verdict::Rejected(Rejection::NoServerId)
+    );
+
+    let mut reply = test_advertise(4, None, &[1]);
+    reply.msg_type = MsgType::Reply;
+    assert_eq!(sel.add(&reply), Verdict::Rejected(Rejection::NotAdvertise));
+
+    assert!(sel.is_empty());
+    assert_eq!(sel.rejected().len(), 4);
+
+    // Only the most recent rejections are kept
+    for server in 0..MAX_REJECTED as u8 {
+        sel.add(&test_advertise(server, None, &[7]));
+    }
+    let rejected = sel.rejected();
+    assert_eq!(rejected.len(), MAX_REJECTED);
+    assert_eq!(rejected[0].0, test_advertise(0, None, &[7]));
+}
@@ -0,0 +1,977 @@
+// Copyright 2021 Oxide Computer Company
+
+//! A sans-IO DHCPv6 server, implementing stateful address assignment as
+//! described in rfc8415, section 18.3.
+//!
+//! The server is handed decoded messages, along with any relay context they
+//! arrived with, and returns the response to be sent.  Choosing addresses is
+//! delegated to an AddressAllocator, so the same server logic can be used
+//! with different pool implementations.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::lease::{IaType, LeaseStore};
+use crate::options::*;
+use crate::stateless::StatelessServer;
+use crate::*;
+
+/// How long an address offered in an Advertise is held for the client
+/// before it can be offered to anyone else
+pub const OFFER_LIFETIME: u32 = 60;
+
+/// Chooses the addresses handed out by the server
+pub trait AddressAllocator {
+    /// Returns an unused address for the given IA, marking it as in use.  If
+    /// the client has asked for a particular address and it is available,
+    /// the allocator should return it.  None is returned if the pool has
+    /// been exhausted.
+    fn allocate(&mut self, duid: &Duid, iaid: u32, hint: Option<Ipv6Addr>) -> Option<Ipv6Addr>;
+
+    /// Returns an address to the pool
+    fn release(&mut self, addr: Ipv6Addr);
+
+    /// Records that a client found the address to be in use by some other
+    /// node, so it shouldn't be handed out again.
+    fn decline(&mut self, addr: Ipv6Addr);
+
+    /// Returns true if the address belongs to the pool, and so is
+    /// appropriate for the link the server is serving.
+    fn contains(&self, addr: Ipv6Addr) -> bool;
+
+    /// Marks an address as in use, such as when bindings are restored after
+    /// a restart.  Returns false if the address couldn't be claimed.
+    fn claim(&mut self, addr: Ipv6Addr) -> bool;
+
+    /// Returns whether a Solicit carrying the Rapid Commit option should be
+    /// answered with a committed Reply, overriding the server's
+    /// configuration.  None defers to `ServerConfig::rapid_commit`.
+    fn rapid_commit(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Static configuration for a DHCPv6 server
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// The server's DUID, sent in the Server Identifier option
+    pub duid: Duid,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub t1: u32,
+    pub t2: u32,
+    /// The value of the Preference option sent in Advertises, if any
+    pub preference: Option<u8>,
+    /// Whether a Solicit carrying the Rapid Commit option may be answered
+    /// directly with a Reply, unless the allocator has a policy of its own
+    pub rapid_commit: bool,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub domain_list: Vec<String>,
+}
+
+impl ServerConfig {
+    pub fn new(duid: Duid) -> Self {
+        ServerConfig {
+            duid,
+            preferred_lifetime: 3600,
+            valid_lifetime: 7200,
+            t1: 1800,
+            t2: 2880,
+            preference: None,
+            rapid_commit: false,
+            dns_servers: Vec::new(),
+            domain_list: Vec::new(),
+        }
+    }
+}
+
+/// An address assigned to one of a client's IAs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+    pub addr: Ipv6Addr,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    /// When the address is returned to the pool
+    pub expires: SystemTime,
+    /// False if the address has only been offered in an Advertise
+    pub committed: bool,
+}
+
+/// A change to the server's committed bindings.  These should be applied to
+/// a LeaseStore so the bindings survive a restart.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LeaseEvent {
+    /// A binding was committed, or its lifetimes were extended
+    Bound {
+        duid: Duid,
+        iaid: u32,
+        binding: Binding,
+    },
+    /// The client released the address
+    Released {
+        duid: Duid,
+        iaid: u32,
+        addr: Ipv6Addr,
+    },
+    /// The client found the address in use by some other node
+    Declined {
+        duid: Duid,
+        iaid: u32,
+        addr: Ipv6Addr,
+    },
+    /// The binding's valid lifetime ran out
+    Expired {
+        duid: Duid,
+        iaid: u32,
+        addr: Ipv6Addr,
+    },
+}
+
+/// A sans-IO DHCPv6 server, which assigns a single address to each IA_NA
+/// requested by a client.
+pub struct Server<A: AddressAllocator> {
+    config: ServerConfig,
+    allocator: A,
+    // Answers Information-requests, which need none of the state above
+    stateless: StatelessServer,
+    bindings: HashMap<(Duid, u32), Binding>,
+    events: Vec<LeaseEvent>,
+}
+
+fn status(code: StatusCode, msg: &str) -> Dhcpv6Option {
+    Dhcpv6Option::StatusCode(StatusCodeOption {
+        code,
+        msg: msg.as_bytes().to_vec(),
+    })
+}
+
+// Returns the addresses the client included in an IA_NA
+fn ia_addrs(ia: &IaNaOption) -> Vec<Ipv6Addr> {
+    ia.options
+        .iter()
+        .filter_map(|o| match o {
+            Dhcpv6Option::IaAddr(a) => Some(a.addr),
+            _ => None,
+        })
+        .collect()
+}
+
+fn ia_nas(msg: &ClientMsg) -> Vec<&IaNaOption> {
+    msg.options
+        .iter()
+        .filter_map(|o| match o {
+            Dhcpv6Option::IaNa(ia) => Some(ia),
+            _ => None,
+        })
+        .collect()
+}
+
+impl<A: AddressAllocator> Server<A> {
+    pub fn new(config: ServerConfig, allocator: A) -> Self {
+        let mut stateless = StatelessServer::new(config.duid.clone());
+        if !config.dns_servers.is_empty() {
+            stateless.set_option(Dhcpv6Option::DnsServers(config.dns_servers.clone()));
+        }
+        if !config.domain_list.is_empty() {
+            stateless.set_option(Dhcpv6Option::DomainList(config.domain_list.clone()));
+        }
+        Server {
+            config,
+            allocator,
+            stateless,
+            bindings: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Returns the responder used for Information-requests.  It starts out
+    /// with the DNS servers and domain list from the configuration, and can
+    /// be given other options or a different refresh time.
+    pub fn stateless_mut(&mut self) -> &mut StatelessServer {
+        &mut self.stateless
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    /// Returns the address bound to the given IA, if any
+    pub fn binding(&self, duid: &Duid, iaid: u32) -> Option<&Binding> {
+        self.bindings.get(&(duid.clone(), iaid))
+    }
+
+    /// Returns the addresses of any bindings that have expired to the pool.
+    /// This is done automatically whenever a message is handled.
+    pub fn expire(&mut self, now: SystemTime) -> Vec<Ipv6Addr> {
+        let mut expired = Vec::new();
+        let allocator = &mut self.allocator;
+        let events = &mut self.events;
+        self.bindings.retain(|(duid, iaid), b| {
+            if b.expires > now {
+                return true;
+            }
+            allocator.release(b.addr);
+            expired.push(b.addr);
+            if b.committed {
+                events.push(LeaseEvent::Expired {
+                    duid: duid.clone(),
+                    iaid: *iaid,
+                    addr: b.addr,
+                });
+            }
+            false
+        });
+        expired
+    }
+
+    /// Returns the changes to the committed bindings since the last call
+    pub fn take_events(&mut self) -> Vec<LeaseEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Reloads the IA_NA bindings recorded in a lease store, claiming their
+    /// addresses from the allocator.  Returns the number of bindings
+    /// restored.
+    pub fn restore<S: LeaseStore + ?Sized>(&mut self, now: SystemTime, store: &S) -> usize {
+        let mut restored = 0;
+        for (key, leases) in store.entries() {
+            if key.ia_type != IaType::Na {
+                continue;
+            }
+            let lease = match leases.first() {
+                Some(lease) if lease.expires > now => lease,
+                _ => continue,
+            };
+            if !self.allocator.claim(lease.addr) {
+                continue;
+            }
+            let binding = Binding {
+                addr: lease.addr,
+                preferred_lifetime: lease.preferred_lifetime,
+                valid_lifetime: lease.valid_lifetime,
+                expires: lease.expires,
+                committed: true,
+            };
+            self.bindings.insert((key.duid.clone(), key.iaid), binding);
+            restored += 1;
+        }
+        restored
+    }
+
+    /// Handles a message received by the server, returning the response to
+    /// be sent, if any.  A response to a relayed message is wrapped in the
+    /// matching Relay-reply messages.
+    pub fn handle(&mut self, now: SystemTime, msg: &Message) -> Option<Message> {
+        let reply = self.handle_client_msg(now, msg.client_msg())?;
+        Some(msg.reply(reply))
+    }
+
+    /// Handles a message received directly from a client
+    pub fn handle_client_msg(&mut self, now: SystemTime, msg: &ClientMsg) -> Option<ClientMsg> {
+        self.expire(now);
+        if !self.is_valid(msg) {
+            return None;
+        }
+
+        match msg.msg_type {
+            MsgType::Solicit => self.solicit(now, msg),
+            MsgType::Request => Some(self.request(now, msg)),
+            MsgType::Renew => Some(self.renew(now, msg)),
+            MsgType::Rebind => self.rebind(now, msg),
+            MsgType::Release => Some(self.release(msg)),
+            MsgType::Decline => Some(self.decline(msg)),
+            MsgType::Confirm => self.confirm(msg),
+            MsgType::InformationRequest => self.stateless.handle_client_msg(msg),
+            _ => None,
+        }
+    }
+
+    // Applies the validation rules in rfc8415, section 16
+    fn is_valid(&self, msg: &ClientMsg) -> bool {
+        let server_id = match msg.find_one_option(OPTION_SERVERID) {
+            Some(Dhcpv6Option::ServerId(id)) => Some(id),
+            Some(_) => return false,
+            None => None,
+        };
+        let ours = server_id == Some(&self.config.duid);
+        let has_client_id = msg.has_option(OPTION_CLIENTID);
+
+        match msg.msg_type {
+            MsgType::Solicit | MsgType::Rebind | MsgType::Confirm => {
+                has_client_id && server_id.is_none()
+            }
+            MsgType::Request | MsgType::Renew | MsgType::Release | MsgType::Decline => {
+                has_client_id && ours
+            }
+            MsgType::InformationRequest => server_id.is_none() || ours,
+            _ => false,
+        }
+    }
+
+    // Builds a response carrying the server's identity, the client's
+    // identity and any configuration options the client asked for.
+    fn response(&self, msg_type: MsgType, msg: &ClientMsg) -> ClientMsg {
+        let mut response = ClientMsg::new(msg_type, Some(msg.tx_id));
+        response
+            .options
+            .push(Dhcpv6Option::ServerId(self.config.duid.clone()));
+        if let Some(client_id) = msg.find_one_option(OPTION_CLIENTID) {
+            response.options.push(client_id.clone());
+        }
+
+        let requested = match msg.find_one_option(OPTION_ORO) {
+            Some(Dhcpv6Option::Oro(codes)) => codes.clone(),
+            _ => Vec::new(),
+        };
+        if requested.contains(&OPTION_DNS_SERVERS) && !self.config.dns_servers.is_empty() {
+            response
+                .options
+                .push(Dhcpv6Option::DnsServers(self.config.dns_servers.clone()));
+        }
+        if requested.contains(&OPTION_DOMAIN_LIST) && !self.config.domain_list.is_empty() {
+            response
+                .options
+                .push(Dhcpv6Option::DomainList(self.config.domain_list.clone()));
+        }
+        response
+    }
+
+    // Builds an IA_NA describing a binding, with the configured timers
+    fn ia_option(&self, iaid: u32, binding: &Binding) -> Dhcpv6Option {
+        Dhcpv6Option::IaNa(self.ia_na(iaid, binding))
+    }
+
+    fn ia_na(&self, iaid: u32, binding: &Binding) -> IaNaOption {
+        let mut ia = IaNaOption::new(iaid);
+        ia.t1 = self.config.t1;
+        ia.t2 = self.config.t2;
+        let mut addr = IaAddrOption::new(binding.addr);
+        addr.preferred_lifetime = binding.preferred_lifetime;
+        addr.valid_lifetime = binding.valid_lifetime;
+        ia.options.push(Dhcpv6Option::IaAddr(addr));
+        ia
+    }
+
+    fn ia_status(iaid: u32, code: StatusCode, msg: &str) -> Dhcpv6Option {
+        let mut ia = IaNaOption::new(iaid);
+        ia.options.push(status(code, msg));
+        Dhcpv6Option::IaNa(ia)
+    }
+
+    // Creates or refreshes the binding for an IA, returning None if there
+    // are no addresses left to give out.  Offering an address never takes
+    // back a committed binding, and a binding's expiry only ever moves later.
+    fn bind(
+        &mut self,
+        now: SystemTime,
+        duid: &Duid,
+        ia: &IaNaOption,
+        commit: bool,
+    ) -> Option<Binding> {
+        let key = (duid.clone(), ia.iaid);
+        let existing = self.bindings.get(&key).cloned();
+        let addr = match &existing {
+            Some(b) if b.committed && !commit => return existing,
+            Some(b) => b.addr,
+            None => {
+                let hint = ia_addrs(ia).into_iter().next();
+                self.allocator.allocate(duid, ia.iaid, hint)?
+            }
+        };
+        Some(self.record(now, duid, ia.iaid, addr, existing.as_ref(), commit))
+    }
+
+    // Records a binding of the address, which the allocator has already
+    // handed out, to the IA.
+    fn record(
+        &mut self,
+        now: SystemTime,
+        duid: &Duid,
+        iaid: u32,
+        addr: Ipv6Addr,
+        existing: Option<&Binding>,
+        commit: bool,
+    ) -> Binding {
+        let lifetime = if commit {
+            self.config.valid_lifetime
+        } else {
+            OFFER_LIFETIME
+        };
+        let mut expires = now + Duration::from_secs(lifetime as u64);
+        if let Some(b) = existing {
+            expires = expires.max(b.expires);
+        }
+        let binding = Binding {
+            addr,
+            preferred_lifetime: self.config.preferred_lifetime,
+            valid_lifetime: self.config.valid_lifetime,
+            expires,
+            committed: commit,
+        };
+        self.bindings.insert((duid.clone(), iaid), binding.clone());
+        if commit {
+            self.events.push(LeaseEvent::Bound {
+                duid: duid.clone(),
+                iaid,
+                binding: binding.clone(),
+            });
+        }
+        binding
+    }
+
+    // Assigns addresses to each of the client's IA_NAs, as for a Request.
+    // Returns the response, and whether any addresses were assigned.
+    fn assign(
+        &mut self,
+        now: SystemTime,
+        msg_type: MsgType,
+        msg: &ClientMsg,
+        commit: bool,
+    ) -> (ClientMsg, bool) {
+        let duid = match msg.find_one_option(OPTION_CLIENTID) {
+            Some(Dhcpv6Option::ClientId(duid)) => duid.clone(),
+            _ => unreachable!("validated messages have a client ID"),
+        };
+        let mut response = self.response(msg_type, msg);
+        let mut assigned = false;
+        for ia in ia_nas(msg) {
+            let opt = match self.bind(now, &duid, ia, commit) {
+                Some(binding) => {
+                    assigned = true;
+                    self.ia_option(ia.iaid, &binding)
+                }
+                None => Self::ia_status(ia.iaid, StatusCode::NoAddrsAvail, "no addresses"),
+            };
+            response.options.push(opt);
+        }
+        (response, assigned)
+    }
+
+    fn solicit(&mut self, now: SystemTime, msg: &ClientMsg) -> Option<ClientMsg> {
+        let policy = self
+            .allocator
+            .rapid_commit()
+            .unwrap_or(self.config.rapid_commit);
+        if policy && msg.has_option(OPTION_RAPID_COMMIT) {
+            // If nothing can be committed, fall back to advertising, so the
+            // client hears why.
+            let (mut reply, assigned) = self.assign(now, MsgType::Reply, msg, true);
+            if assigned {
+                reply.options.push(Dhcpv6Option::RapidCommit);
+                return Some(reply);
+            }
+        }
+
+        let (mut advertise, assigned) = self.assign(now, MsgType::Advertise, msg, false);
+        if !assigned {
+            // There's nothing to offer, so send only a status code
+            advertise.options.retain(|o| u16::from(o) != OPTION_IA_NA);
+            advertise
+                .options
+                .push(status(StatusCode::NoAddrsAvail, "no addresses available"));
+        }
+        if let Some(preference) = self.config.preference {
+            advertise.options.push(Dhcpv6Option::Preference(preference));
+        }
+        Some(advertise)
+    }
+
+    fn request(&mut self, now: SystemTime, msg: &ClientMsg) -> ClientMsg {
+        self.assign(now, MsgType::Reply, msg, true).0
+    }
+
+    // Extends the lifetimes of an existing committed binding.  An address
+    // which has only been offered in an Advertise isn't bound yet.
+    fn extend(&mut self, now: SystemTime, duid: &Duid, ia: &IaNaOption) -> Option<Dhcpv6Option> {
+        let key = (duid.clone(), ia.iaid);
+        if !self.bindings.get(&key).is_some_and(|b| b.committed) {
+            return None;
+        }
+        let binding = self.bind(now, duid, ia, true)?;
+        let mut opt = self.ia_option(ia.iaid, &binding);
+
+        // Any other addresses the client thinks it has are no longer valid
+        if let Dhcpv6Option::IaNa(reply_ia) = &mut opt {
+            for addr in ia_addrs(ia).into_iter().filter(|a| *a != binding.addr) {
+                let mut stale = IaAddrOption::new(addr);
+                stale.preferred_lifetime = 0;
+                stale.valid_lifetime = 0;
+                reply_ia.options.push(Dhcpv6Option::IaAddr(stale));
+            }
+        }
+        Some(opt)
+    }
+
+    fn renew(&mut self, now: SystemTime, msg: &ClientMsg) -> ClientMsg {
+        let duid = self.client_id(msg);
+        let mut reply = self.response(MsgType::Reply, msg);
+        for ia in ia_nas(msg) {
+            let opt = self
+                .extend(now, &duid, ia)
+                .unwrap_or_else(|| Self::ia_status(ia.iaid, StatusCode::NoBinding, "no binding"));
+            reply.options.push(opt);
+        }
+        reply
+    }
+
+    fn rebind(&mut self, now: SystemTime, msg: &ClientMsg) -> Option<ClientMsg> {
+        let duid = self.client_id(msg);
+        let mut reply = self.response(MsgType::Reply, msg);
+        let mut any = false;
+        for ia in ia_nas(msg) {
+            if let Some(opt) = self.extend(now, &duid, ia) {
+                reply.options.push(opt);
+                any = true;
+                continue;
+            }
+
+            // We have no record of the IA.  If the client's first address
+            // is free for it to have, take over the binding.  Any address
+            // not taken over, the client is told to stop using.
+            let addrs = ia_addrs(ia);
+            if addrs.is_empty() {
+                continue;
+            }
+            let mut opt = if self.allocator.claim(addrs[0]) {
+                let binding = self.record(now, &duid, ia.iaid, addrs[0], None, true);
+                self.ia_na(ia.iaid, &binding)
+            } else {
+                IaNaOption::new(ia.iaid)
+            };
+            for addr in addrs {
+                if ia_addrs(&opt).contains(&addr) {
+                    continue;
+                }
+                let mut zeroed = IaAddrOption::new(addr);
+                zeroed.preferred_lifetime = 0;
+                zeroed.valid_lifetime = 0;
+                opt.options.push(Dhcpv6Option::IaAddr(zeroed));
+            }
+            reply.options.push(Dhcpv6Option::IaNa(opt));
+            any = true;
+        }
+
+        // With nothing to tell the client, the Rebind is discarded
+        if any {
+            Some(reply)
+        } else {
+            None
+        }
+    }
+
+    // Removes the bindings for the client's IA_NAs, passing each address to
+    // the allocator.
+    fn unbind(&mut self, msg: &ClientMsg, declined: bool) -> ClientMsg {
+        let duid = self.client_id(msg);
+        let mut reply = self.response(MsgType::Reply, msg);
+        for ia in ia_nas(msg) {
+            let key = (duid.clone(), ia.iaid);
+            let addrs = ia_addrs(ia);
+            match self.bindings.get(&key) {
+                Some(b) if addrs.contains(&b.addr) => {
+                    let (duid, iaid) = key.clone();
+                    let addr = self.bindings.remove(&key).unwrap().addr;
+                    if declined {
+                        self.allocator.decline(addr);
+                        self.events.push(LeaseEvent::Declined { duid, iaid, addr });
+                    } else {
+                        self.allocator.release(addr);
+                        self.events.push(LeaseEvent::Released { duid, iaid, addr });
+                    }
+                }
+                _ => reply.options.push(Self::ia_status(
+                    ia.iaid,
+                    StatusCode::NoBinding,
+                    "no binding",
+                )),
+            }
+        }
+        reply.options.push(status(StatusCode::Success, "done"));
+        reply
+    }
+
+    fn release(&mut self, msg: &ClientMsg) -> ClientMsg {
+        self.unbind(msg, false)
+    }
+
+    fn decline(&mut self, msg: &ClientMsg) -> ClientMsg {
+        self.unbind(msg, true)
+    }
+
+    fn confirm(&mut self, msg: &ClientMsg) -> Option<ClientMsg> {
+        let addrs: Vec<Ipv6Addr> = ia_nas(msg).into_iter().flat_map(ia_addrs).collect();
+
+        // Without any addresses, there's nothing to confirm
+        if addrs.is_empty() {
+            return None;
+        }
+        let mut reply = self.response(MsgType::Reply, msg);
+        if addrs.iter().all(|a| self.allocator.contains(*a)) {
+            reply
+                .options
+                .push(status(StatusCode::Success, "addresses are on link"));
+        } else {
+            reply
+                .options
+                .push(status(StatusCode::NotOnLink, "addresses are not on link"));
+        }
+        Some(reply)
+    }
+
+    fn client_id(&self, msg: &ClientMsg) -> Duid {
+        match msg.find_one_option(OPTION_CLIENTID) {
+            Some(Dhcpv6Option::ClientId(duid)) => duid.clone(),
+            _ => unreachable!("validated messages have a client ID"),
+        }
+    }
+}
+
+// Hands out the addresses in a fixed list, in order
+#[cfg(test)]
+#[derive(Default)]
+struct TestAllocator {
+    free: Vec<Ipv6Addr>,
+    declined: Vec<Ipv6Addr>,
+}
+
+#[cfg(test)]
+impl AddressAllocator for TestAllocator {
+    fn allocate(&mut self, _duid: &Duid, _iaid: u32, hint: Option<Ipv6Addr>) -> Option<Ipv6Addr> {
+        let idx = match hint.and_then(|h| self.free.iter().position(|a| *a == h)) {
+            Some(idx) => idx,
+            None if self.free.is_empty() => return None,
+            None => 0,
+        };
+        Some(self.free.remove(idx))
+    }
+
+    fn release(&mut self, addr: Ipv6Addr) {
+        self.free.push(addr);
+    }
+
+    fn decline(&mut self, addr: Ipv6Addr) {
+        self.declined.push(addr);
+    }
+
+    fn contains(&self, addr: Ipv6Addr) -> bool {
+        addr.segments()[0] == 0xfd00
+    }
+
+    fn claim(&mut self, addr: Ipv6Addr) -> bool {
+        match self.free.iter().position(|a| *a == addr) {
+            Some(idx) => {
+                self.free.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::test::{test_client_msg, test_duid};
+
+#[cfg(test)]
+fn test_server(addrs: &[&str]) -> Server<TestAllocator> {
+    let mut config = ServerConfig::new(test_duid(1));
+    config.dns_servers = vec!["fd00::53".parse().unwrap()];
+    let allocator = TestAllocator {
+        free: addrs.iter().map(|a| a.parse().unwrap()).collect(),
+        declined: Vec::new(),
+    };
+    Server::new(config, allocator)
+}
+
+#[cfg(test)]
+fn test_reply_addr(msg: &ClientMsg, iaid: u32) -> Option<IaAddrOption> {
+    msg.options.iter().find_map(|o| match o {
+        Dhcpv6Option::IaNa(ia) if ia.iaid == iaid => ia.options.iter().find_map(|o| match o {
+            Dhcpv6Option::IaAddr(a) => Some(a.clone()),
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+fn test_ia_status(msg: &ClientMsg, iaid: u32) -> Option<StatusCode> {
+    msg.options.iter().find_map(|o| match o {
+        Dhcpv6Option::IaNa(ia) if ia.iaid == iaid => ia.options.iter().find_map(|o| match o {
+            Dhcpv6Option::StatusCode(s) => Some(s.code),
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+#[test]
+fn test_server_assignment() {
+    let mut server = test_server(&["fd00::1", "fd00::2"]);
+    let server_id = server.config().duid.clone();
+    let now = SystemTime::now();
+
+    // The Advertise offers an address without committing to it, along with
+    // the configuration the client asked for
+    let mut solicit = test_client_msg(MsgType::Solicit, 2, None, &[1]);
+    solicit
+        .options
+        .push(Dhcpv6Option::Oro(vec![OPTION_DNS_SERVERS]));
+    let advertise = server.handle_client_msg(now, &solicit).unwrap();
+    assert_eq!(advertise.msg_type, MsgType::Advertise);
+    assert_eq!(advertise.tx_id, solicit.tx_id);
+    assert!(advertise.has_option(OPTION_DNS_SERVERS));
+    let offered = test_reply_addr(&advertise, 1).unwrap();
+    assert_eq!(offered.addr, "fd00::1".parse::<Ipv6Addr>().unwrap());
+    let client_id = match solicit.find_one_option(OPTION_CLIENTID) {
+        Some(Dhcpv6Option::ClientId(duid)) => duid.clone(),
+        _ => panic!(),
+    };
+    assert!(!server.binding(&client_id, 1).unwrap().committed);
+
+    // A Request commits the offered address
+    let mut request = test_client_msg(MsgType::Request, 2, Some(&server_id), &[1]);
+    request.options[2] = advertise.find_one_option(OPTION_IA_NA).unwrap().clone();
+    let reply = server.handle_client_msg(now, &request).unwrap();
+    assert_eq!(reply.msg_type, MsgType::Reply);
+    assert_eq!(test_reply_addr(&reply, 1).unwrap().addr, offered.addr);
+    assert!(server.binding(&client_id, 1).unwrap().committed);
+
+    // A Request for some other server is ignored
+    let other = test_duid(9);
+    let misdirected = test_client_msg(MsgType::Request, 2, Some(&other), &[1]);
+    assert!(server.handle_client_msg(now, &misdirected).is_none());
+
+    // Renew extends the binding, but an unknown IA gets NoBinding
+    let later = now + Duration::from_secs(1800);
+    let mut renew = test_client_msg(MsgType::Renew, 2, Some(&server_id), &[1, 2]);
+    renew.options[2] = reply.find_one_option(OPTION_IA_NA).unwrap().clone();
+    let reply = server.handle_client_msg(later, &renew).unwrap();
+    assert_eq!(test_reply_addr(&reply, 1).unwrap().addr, offered.addr);
+    assert_eq!(test_ia_status(&reply, 2), Some(StatusCode::NoBinding));
+    let binding = server.binding(&client_id, 1).unwrap();
+    assert_eq!(binding.expires, later + Duration::from_secs(7200));
+
+    // Release returns the address to the pool
+    let mut release = test_client_msg(MsgType::Release, 2, Some(&server_id), &[]);
+    let ia = reply.find_one_option(OPTION_IA_NA).unwrap().clone();
+    release.options.push(ia);
+    let reply = server.handle_client_msg(later, &release).unwrap();
+    assert_eq!(
+        reply.find_one_option(OPTION_STATUS_CODE),
+        Some(&status(StatusCode::Success, "done"))
+    );
+    assert!(server.binding(&client_id, 1).is_none());
+    assert!(server.allocator().free.contains(&offered.addr));
+}
+
+#[test]
+fn test_server_offer_doesnt_downgrade() {
+    let mut server = test_server(&["fd00::1", "fd00::2"]);
+    let server_id = server.config().duid.clone();
+    let now = SystemTime::now();
+    let solicit = test_client_msg(MsgType::Solicit, 2, None, &[1]);
+    let client_id = match solicit.find_one_option(OPTION_CLIENTID) {
+        Some(Dhcpv6Option::ClientId(duid)) => duid.clone(),
+        _ => panic!(),
+    };
+
+    // Renewing an address which was only offered gets NoBinding, and
+    // doesn't commit it
+    server.handle_client_msg(now, &solicit).unwrap();
+    let renew = test_client_msg(MsgType::Renew, 2, Some(&server_id), &[1]);
+    let reply = server.handle_client_msg(now, &renew).unwrap();
+    assert_eq!(test_ia_status(&reply, 1), Some(StatusCode::NoBinding));
+    assert!(!server.binding(&client_id, 1).unwrap().committed);
+
+    let request = test_client_msg(MsgType::Request, 2, Some(&server_id), &[1]);
+    server.handle_client_msg(now, &request).unwrap();
+    let bound = server.binding(&client_id, 1).unwrap().clone();
+    assert!(bound.committed);
+    assert_eq!(server.take_events().len(), 1);
+
+    // Soliciting again offers the same address, but leaves the binding alone
+    let later = now + Duration::from_secs(10);
+    let advertise = server.handle_client_msg(later, &solicit).unwrap();
+    assert_eq!(test_reply_addr(&advertise, 1).unwrap().addr, bound.addr);
+    assert_eq!(server.binding(&client_id, 1), Some(&bound));
+
+    // ... so it doesn't lapse with the offer
+    let lapsed = later + Duration::from_secs(OFFER_LIFETIME as u64);
+    assert!(server.expire(lapsed).is_empty());
+    assert!(server.take_events().is_empty());
+}
+
+#[test]
+fn test_server_exhaustion() {
+    let mut server = test_server(&["fd00::1"]);
+    let now = SystemTime::now();
+
+    // Only one of the two IAs can be given an address
+    let solicit = test_client_msg(MsgType::Solicit, 2, None, &[1, 2]);
+    let advertise = server.handle_client_msg(now, &solicit).unwrap();
+    assert!(test_reply_addr(&advertise, 1).is_some());
+    assert_eq!(
+        test_ia_status(&advertise, 2),
+        Some(StatusCode::NoAddrsAvail)
+    );
+
+    // With nothing left, the Advertise carries only a status code
+    let solicit = test_client_msg(MsgType::Solicit, 2, None, &[3]);
+    let advertise = server.handle_client_msg(now, &solicit).unwrap();
+    assert!(!advertise.has_option(OPTION_IA_NA));
+    assert_eq!(
+        advertise.find_one_option(OPTION_STATUS_CODE),
+        Some(&status(StatusCode::NoAddrsAvail, "no addresses available"))
+    );
+
+    // Once the offer lapses, the address can be offered again
+    let later = now + Duration::from_secs(OFFER_LIFETIME as u64);
+    assert_eq!(
+        server.expire(later),
+        vec!["fd00::1".parse::<Ipv6Addr>().unwrap()]
+    );
+    let advertise = server.handle_client_msg(later, &solicit).unwrap();
+    assert!(test_reply_addr(&advertise, 3).is_some());
+}
+
+#[test]
+fn test_server_rebind_confirm_decline() {
+    let mut server = test_server(&["fd00::1", "fd00::2"]);
+    let server_id = server.config().duid.clone();
+    let now = SystemTime::now();
+
+    // A Rebind for an address which doesn't belong here is zeroed
+    let mut rebind = test_client_msg(MsgType::Rebind, 2, None, &[]);
+    let mut ia = IaNaOption::new(1);
+    ia.options.push(Dhcpv6Option::IaAddr(IaAddrOption::new(
+        "fd01::1".parse().unwrap(),
+    )));
+    rebind.options.push(Dhcpv6Option::IaNa(ia));
+    let reply = server.handle_client_msg(now, &rebind).unwrap();
+    assert_eq!(test_reply_addr(&reply, 1).unwrap().valid_lifetime, 0);
+
+    // A Confirm of the same address says it's not on link
+    let mut confirm = rebind.clone();
+    confirm.msg_type = MsgType::Confirm;
+    let reply = server.handle_client_msg(now, &confirm).unwrap();
+    assert_eq!(
+        reply.find_one_option(OPTION_STATUS_CODE),
+        Some(&status(StatusCode::NotOnLink, "addresses are not on link"))
+    );
+
+    // A Rebind for an address on this link takes over the binding
+    let mut rebind = test_client_msg(MsgType::Rebind, 2, None, &[]);
+    let mut ia = IaNaOption::new(1);
+    ia.options.push(Dhcpv6Option::IaAddr(IaAddrOption::new(
+        "fd00::2".parse().unwrap(),
+    )));
+    rebind.options.push(Dhcpv6Option::IaNa(ia.clone()));
+    let reply = server.handle_client_msg(now, &rebind).unwrap();
+    let addr = test_reply_addr(&reply, 1).unwrap();
+    assert_eq!(addr.addr, "fd00::2".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(addr.valid_lifetime, 7200);
+
+    // A Decline takes the address out of service
+    let mut decline = test_client_msg(MsgType::Decline, 2, Some(&server_id), &[]);
+    decline.options.push(Dhcpv6Option::IaNa(ia));
+    server.handle_client_msg(now, &decline).unwrap();
+    assert_eq!(server.allocator().declined, vec![addr.addr]);
+    assert!(!server.allocator().free.contains(&addr.addr));
+}
+
+#[test]
+fn test_server_information_request() {
+    let mut server = test_server(&["fd00::1"]);
+    let now = SystemTime::now();
+
+    // Information-requests are answered like a stateless server would,
+    // including the refresh time, and bind nothing
+    let mut request = test_client_msg(MsgType::InformationRequest, 2, None, &[]);
+    request
+        .options
+        .push(Dhcpv6Option::Oro(vec![OPTION_DNS_SERVERS]));
+    let reply = server.handle_client_msg(now, &request).unwrap();
+    assert_eq!(reply.msg_type, MsgType::Reply);
+    assert!(reply.has_option(OPTION_DNS_SERVERS));
+    assert_eq!(
+        reply.find_one_option(OPTION_INFORMATION_REFRESH_TIME),
+        Some(&Dhcpv6Option::InformationRefreshTime(params::IRT_DEFAULT))
+    );
+    assert!(server
+        .allocator()
+        .free
+        .contains(&"fd00::1".parse().unwrap()));
+
+    server.stateless_mut().set_refresh_time(3600);
+    let reply = server.handle_client_msg(now, &request).unwrap();
+    assert_eq!(
+        reply.find_one_option(OPTION_INFORMATION_REFRESH_TIME),
+        Some(&Dhcpv6Option::InformationRefreshTime(3600))
+    );
+
+    // An Information-request with an IA is invalid
+    let request = test_client_msg(MsgType::InformationRequest, 2, None, &[1]);
+    assert!(server.handle_client_msg(now, &request).is_none());
+}
+
+#[test]
+fn test_server_relayed() {
+    let mut server = test_server(&["fd00::1"]);
+    let now = SystemTime::now();
+
+    let solicit = test_client_msg(MsgType::Solicit, 2, None, &[1]);
+    let mut relay = RelayMsg {
+        msg_type: MsgType::RelayForw,
+        hop_count: 0,
+        link_addr: "fd00::ff".parse().unwrap(),
+        peer_addr: "fe80::1".parse().unwrap(),
+        option: vec![Dhcpv6Option::InterfaceId(b"eth0".to_vec())],
+    };
+    relay
+        .option
+        .push(Dhcpv6Option::RelayMsg(solicit.encode().unwrap()));
+    let buf = relay.encode().unwrap();
+    let msg = Message::decode(&buf).unwrap();
+
+    let response = server.handle(now, &msg).unwrap();
+    let relays = response.relays();
+    assert_eq!(relays.len(), 1);
+    assert_eq!(relays[0].msg_type, MsgType::RelayRepl);
+    assert_eq!(relays[0].peer_addr, relay.peer_addr);
+    assert!(relays[0].has_option(OPTION_INTERFACE_ID));
+    assert_eq!(response.client_msg().msg_type, MsgType::Advertise);
+}
+
+#[test]
+fn test_server_rapid_commit() {
+    let mut server = test_server(&["fd00::1", "fd00::2"]);
+    let now = SystemTime::now();
+    let mut solicit = test_client_msg(MsgType::Solicit, 2, None, &[1]);
+    solicit.options.push(Dhcpv6Option::RapidCommit);
+
+    // Rapid commit is ignored unless the server is configured to allow it
+    let advertise = server.handle_client_msg(now, &solicit).unwrap();
+    assert_eq!(advertise.msg_type, MsgType::Advertise);
+    assert!(!advertise.has_option(OPTION_RAPID_COMMIT));
+
+    server.config.rapid_commit = true;
+    let reply = server.handle_client_msg(now, &solicit).unwrap();
+    assert_eq!(reply.msg_type, MsgType::Reply);
+    assert!(reply.has_option(OPTION_RAPID_COMMIT));
+    let addr = test_reply_addr(&reply, 1).unwrap();
+    assert_eq!(addr.addr, "fd00::1".parse::<Ipv6Addr>().unwrap());
+
+    // With nothing left to commit, the server advertises instead
+    let mut other = test_client_msg(MsgType::Solicit, 2, None, &[2]);
+    other.options.push(Dhcpv6Option::RapidCommit);
+    server.handle_client_msg(now, &other).unwrap();
+    let mut third = test_client_msg(MsgType::Solicit, 2, None, &[3]);
+    third.options.push(Dhcpv6Option::RapidCommit);
+    let advertise = server.handle_client_msg(now, &third).unwrap();
+    assert_eq!(advertise.msg_type, MsgType::Advertise);
+    assert!(!advertise.has_option(OPTION_RAPID_COMMIT));
+}
@@ -0,0 +1,556 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Stateless DHCPv6, as described in rfc8415, section 6.1.
+//!
+//! Clients that configure their addresses some other way, such as with
+//! SLAAC, can still use DHCPv6 to learn about DNS servers, search domains,
+//! NTP servers and the like.  They do this with an Information-request, which
+//! the responder answers from a fixed set of options without keeping any
+//! per-client state.  The client repeats the exchange whenever the
+//! Information Refresh Time it was given runs out.
+
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::client::{status_code, Output, Transmit};
+use crate::options::*;
+use crate::retransmit::Retransmitter;
+use crate::*;
+
+/// Options the stateless client always asks the server for
+const DEFAULT_ORO: [u16; 3] = [
+    OPTION_DNS_SERVERS,
+    OPTION_DOMAIN_LIST,
+    OPTION_INFORMATION_REFRESH_TIME,
+];
+
+/// A sans-IO server which only answers Information-request messages
+#[derive(Clone, Debug)]
+pub struct StatelessServer {
+    duid: Duid,
+    options: Vec<Dhcpv6Option>,
+    refresh_time: u32,
+}
+
+impl StatelessServer {
+    /// Returns a server which identifies itself with the given DUID and
+    /// tells clients to refresh their configuration once a day.
+    pub fn new(duid: Duid) -> Self {
+        StatelessServer {
+            duid,
+            options: Vec::new(),
+            refresh_time: params::IRT_DEFAULT,
+        }
+    }
+
+    pub fn duid(&self) -> &Duid {
+        &self.duid
+    }
+
+    /// Adds an option to those handed out to clients that ask for it,
+    /// replacing any existing option with the same code.  Options this crate
+    /// doesn't know about, such as NTP servers, can be supplied as
+    /// `Dhcpv6Option::Other`.
+    pub fn set_option(&mut self, opt: Dhcpv6Option) {
+        let code = u16::from(&opt);
+        self.options.retain(|o| u16::from(o) != code);
+        self.options.push(opt);
+    }
+
+    /// Removes the option with the given code, returning it if it was set
+    pub fn remove_option(&mut self, code: u16) -> Option<Dhcpv6Option> {
+        let idx = self.options.iter().position(|o| u16::from(o) == code)?;
+        Some(self.options.remove(idx))
+    }
+
+    pub fn options(&self) -> &[Dhcpv6Option] {
+        &self.options
+    }
+
+    /// Sets how many seconds clients should wait before asking again.  Values
+    /// below IRT_MINIMUM are raised to it, as rfc8415, section 21.23 requires.
+    pub fn set_refresh_time(&mut self, seconds: u32) {
+        self.refresh_time = seconds.max(params::IRT_MINIMUM);
+    }
+
+    pub fn refresh_time(&self) -> u32 {
+        self.refresh_time
+    }
+
+    /// Handles a message received by the server, returning the response to
+    /// be sent, if any.  A response to a relayed message is wrapped in the
+    /// matching Relay-reply messages.
+    pub fn handle(&self, msg: &Message) -> Option<Message> {
+        let reply = self.handle_client_msg(msg.client_msg())?;
+        Some(msg.reply(reply))
+    }
+
+    /// Handles a message received directly from a client.  Anything other
+    /// than a valid Information-request is ignored.
+    pub fn handle_client_msg(&self, msg: &ClientMsg) -> Option<ClientMsg> {
+        if !self.is_valid(msg) {
+            return None;
+        }
+
+        let mut reply = ClientMsg::new(MsgType::Reply, Some(msg.tx_id));
+        reply
+            .options
+            .push(Dhcpv6Option::ServerId(self.duid.clone()));
+        if let Some(client_id) = msg.find_one_option(OPTION_CLIENTID) {
+            reply.options.push(client_id.clone());
+        }
+
+        if let Some(Dhcpv6Option::Oro(codes)) = msg.find_one_option(OPTION_ORO) {
+            // The refresh time is always sent, so it's skipped here
+            for code in codes
+                .iter()
+                .filter(|c| **c != OPTION_INFORMATION_REFRESH_TIME)
+            {
+                if let Some(opt) = self.options.iter().find(|o| u16::from(*o) == *code) {
+                    reply.options.push(opt.clone());
+                }
+            }
+        }
+        reply
+            .options
+            .push(Dhcpv6Option::InformationRefreshTime(self.refresh_time));
+        Some(reply)
+    }
+
+    // Applies the validation rules in rfc8415, section 16.12
+    fn is_valid(&self, msg: &ClientMsg) -> bool {
+        if msg.msg_type != MsgType::InformationRequest {
+            return false;
+        }
+        match msg.find_one_option(OPTION_SERVERID) {
+            Some(Dhcpv6Option::ServerId(id)) if *id != self.duid => return false,
+            _ => {}
+        }
+        !msg.options.iter().any(|o| {
+            matches!(
+                o,
+                Dhcpv6Option::IaNa(_) | Dhcpv6Option::IaTa(_) | Dhcpv6Option::IaPd(_)
+            )
+        })
+    }
+}
+
+/// The configuration a stateless client has obtained from a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Information {
+    pub server_id: Duid,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub domain_list: Vec<String>,
+    /// Any other options the client asked for and the server supplied
+    pub options: Vec<Dhcpv6Option>,
+    /// How many seconds the information remains good for, or INFINITY if it
+    /// never needs refreshing
+    pub refresh_time: u32,
+    /// When the Reply carrying the information was received
+    pub acquired: Instant,
+}
+
+impl Information {
+    /// Returns when the client should ask for fresh information, if ever
+    pub fn refresh_at(&self) -> Option<Instant> {
+        lifetime_end(&self.acquired, self.refresh_time)
+    }
+}
+
+/// A sans-IO client which obtains configuration with Information-request
+/// messages, and refreshes it as the server directs.
+pub struct StatelessClient<R: Rng = StdRng> {
+    duid: Duid,
+    rng: R,
+    oro: Vec<u16>,
+    tx_id: u32,
+    retransmit: Option<Retransmitter>,
+    deadline: Option<Instant>,
+    info: Option<Information>,
+}
+
+impl StatelessClient<StdRng> {
+    /// Returns a client which identifies itself with the given DUID, and
+    /// asks for any options in `oro` in addition to the DNS servers, domain
+    /// list and refresh time.
+    pub fn new(duid: Duid, oro: Vec<u16>) -> Self {
+        StatelessClient::with_rng(duid, oro, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> StatelessClient<R> {
+    /// Returns a client like `new`, which draws its delays, retransmission
+    /// timeouts and transaction IDs from the supplied RNG.
+    pub fn with_rng(duid: Duid, oro: Vec<u16>, rng: R) -> Self {
+        StatelessClient {
+            duid,
+            rng,
+            oro,
+            tx_id: 0,
+            retransmit: None,
+            deadline: None,
+            info: None,
+        }
+    }
+
+    /// Returns the most recent information received, if any
+    pub fn information(&self) -> Option<&Information> {
+        self.info.as_ref()
+    }
+
+    /// Returns when handle_timeout() should next be called, if ever
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Starts the first exchange.  The first Information-request is delayed
+    /// by a random amount, as required by rfc8415, section 18.2.6.
+    pub fn start(&mut self, now: Instant) -> Output {
+        self.retransmit = None;
+        let max_delay = params::INF_MAX_DELAY as u64 * 1000;
+        let delay = self.rng.gen_range(0..=max_delay);
+        self.deadline = Some(now + Duration::from_millis(delay));
+        self.output(None)
+    }
+
+    fn output(&self, transmit: Option<ClientMsg>) -> Output {
+        Output {
+            transmit: transmit.map(|msg| Transmit {
+                msg,
+                dest: params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS,
+            }),
+            deadline: self.deadline,
+        }
+    }
+
+    fn build(&self, now: Instant) -> ClientMsg {
+        let mut msg = ClientMsg::new(MsgType::InformationRequest, Some(self.tx_id));
+        msg.options.push(Dhcpv6Option::ClientId(self.duid.clone()));
+
+        let mut oro = DEFAULT_ORO.to_vec();
+        oro.extend(self.oro.iter().filter(|c| !DEFAULT_ORO.contains(c)));
+        msg.options.push(Dhcpv6Option::Oro(oro));
+
+        // The elapsed time is measured in hundredths of a second
+        let started = self.retransmit.as_ref().and_then(|r| r.started());
+        let elapsed = now.saturating_duration_since(started.unwrap_or(now));
+        let elapsed = (elapsed.as_millis() / 10).min(u16::MAX as u128) as u16;
+        msg.options.push(Dhcpv6Option::ElapsedTime(elapsed));
+        msg
+    }
+
+    /// Handles the expiration of the deadline most recently returned by the
+    /// client.
+    pub fn handle_timeout(&mut self, now: Instant) -> Output {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return self.output(None),
+        }
+
+        let next = match self.retransmit.as_mut() {
+            // Information-request is retransmitted until a server answers
+            Some(retransmit) => retransmit.next(&now),
+            None => {
+                let rng = StdRng::seed_from_u64(self.rng.gen());
+                let mut retransmit =
+                    Retransmitter::with_rng(MsgType::InformationRequest, rng).unwrap();
+                self.tx_id = self.rng.gen::<u32>() & 0xffffff;
+                let deadline = retransmit.start(&now);
+                self.retransmit = Some(retransmit);
+                Some(deadline)
+            }
+        };
+        self.deadline = next;
+        match next {
+            Some(_) => self.output(Some(self.build(now))),
+            None => {
+                // Information-request has no MRC or MRD, so this shouldn't
+                // happen.  If it does, start over rather than give up.
+                self.start(now)
+            }
+        }
+    }
+
+    /// Handles a message received from a server.  Messages which aren't a
+    /// Reply to the current exchange are ignored.
+    pub fn handle_msg(&mut self, now: Instant, msg: &ClientMsg) -> Output {
+        if !self.is_response(msg) {
+            return self.output(None);
+        }
+        let server_id = match msg.find_one_option(OPTION_SERVERID) {
+            Some(Dhcpv6Option::ServerId(id)) => id.clone(),
+            _ => return self.output(None),
+        };
+        // A server which can't answer says so in a top-level Status Code.
+        // Its Reply is ignored, as process_reply() does, and the request
+        // retransmitted in the hope that another server can.
+        match status_code(&msg.options) {
+            None | Some(StatusCode::Success) => {}
+            Some(_) => return self.output(None),
+        }
+
+        // rfc8415, section 21.23: without the option the client uses
+        // IRT_DEFAULT, and anything shorter than IRT_MINIMUM is raised to it.
+        let refresh_time = match msg.find_one_option(OPTION_INFORMATION_REFRESH_TIME) {
+            Some(Dhcpv6Option::InformationRefreshTime(t)) => (*t).max(params::IRT_MINIMUM),
+            _ => params::IRT_DEFAULT,
+        };
+        let mut info = Information {
+            server_id,
+            dns_servers: Vec::new(),
+            domain_list: Vec::new(),
+            options: Vec::new(),
+            refresh_time,
+            acquired: now,
+        };
+        for opt in &msg.options {
+            match opt {
+                Dhcpv6Option::DnsServers(x) => info.dns_servers = x.clone(),
+                Dhcpv6Option::DomainList(x) => info.domain_list = x.clone(),
+                o if self.oro.contains(&u16::from(o)) => info.options.push(o.clone()),
+                _ => {}
+            }
+        }
+
+        self.deadline = info.refresh_at();
+        self.info = Some(info);
+        self.retransmit = None;
+        self.output(None)
+    }
+
+    // Returns true if the message is a Reply to our current exchange
+    fn is_response(&self, msg: &ClientMsg) -> bool {
+        if self.retransmit.is_none() || msg.msg_type != MsgType::Reply || msg.tx_id != self.tx_id {
+            return false;
+        }
+        matches!(
+            msg.find_one_option(OPTION_CLIENTID),
+            Some(Dhcpv6Option::ClientId(id)) if *id == self.duid
+        )
+    }
+}
+
+#[cfg(test)]
+use crate::test::test_duid;
+
+#[cfg(test)]
+fn test_responder() -> StatelessServer {
+    let mut server = StatelessServer::new(test_duid(1));
+    server.set_option(Dhcpv6Option::DnsServers(vec!["fd00::53".parse().unwrap()]));
+    server.set_option(Dhcpv6Option::DomainList(vec!["example.com".into()]));
+    server.set_option(Dhcpv6Option::Other(OtherOption {
+        code: 56,
+        len: 4,
+        data: vec![0, 1, 0, 0],
+    }));
+    server
+}
+
+#[test]
+fn test_stateless_reply() {
+    let server = test_responder();
+
+    let mut req = ClientMsg::new(MsgType::InformationRequest, Some(0x1234));
+    req.options.push(Dhcpv6Option::ClientId(test_duid(2)));
+    req.options.push(Dhcpv6Option::Oro(vec![
+        OPTION_DNS_SERVERS,
+        56,
+        OPTION_INFORMATION_REFRESH_TIME,
+    ]));
+    let reply = server.handle_client_msg(&req).unwrap();
+    assert_eq!(reply.msg_type, MsgType::Reply);
+    assert_eq!(reply.tx_id, 0x1234);
+    assert_eq!(
+        reply.options,
+        vec![
+            Dhcpv6Option::ServerId(test_duid(1)),
+            Dhcpv6Option::ClientId(test_duid(2)),
+            Dhcpv6Option::DnsServers(vec!["fd00::53".parse().unwrap()]),
+            server.options()[2].clone(),
+            Dhcpv6Option::InformationRefreshTime(params::IRT_DEFAULT),
+        ]
+    );
+
+    // Without an ORO, only the refresh time is handed out.  The Client ID is
+    // optional in an Information-request.
+    let mut server = server;
+    server.set_refresh_time(60);
+    assert_eq!(server.refresh_time(), params::IRT_MINIMUM);
+    let req = ClientMsg::new(MsgType::InformationRequest, Some(0x1234));
+    let reply = server.handle_client_msg(&req).unwrap();
+    assert_eq!(
+        reply.options,
+        vec![
+            Dhcpv6Option::ServerId(test_duid(1)),
+            Dhcpv6Option::InformationRefreshTime(params::IRT_MINIMUM),
+        ]
+    );
+}
+
+#[test]
+fn test_stateless_discard() {
+    let server = test_responder();
+
+    let mut req = ClientMsg::new(MsgType::InformationRequest, Some(1));
+    req.options.push(Dhcpv6Option::ServerId(test_duid(9)));
+    assert_eq!(server.handle_client_msg(&req), None);
+    req.options[0] = Dhcpv6Option::ServerId(test_duid(1));
+    assert!(server.handle_client_msg(&req).is_some());
+
+    req.options.push(Dhcpv6Option::IaNa(IaNaOption::new(1)));
+    assert_eq!(server.handle_client_msg(&req), None);
+
+    let mut solicit = ClientMsg::new(MsgType::Solicit, Some(1));
+    solicit.options.push(Dhcpv6Option::ClientId(test_duid(2)));
+    assert_eq!(server.handle_client_msg(&solicit), None);
+}
+
+#[test]
+fn test_stateless_relayed() {
+    let server = test_responder();
+
+    let mut req = ClientMsg::new(MsgType::InformationRequest, Some(0x42));
+    req.options
+        .push(Dhcpv6Option::Oro(vec![OPTION_DOMAIN_LIST]));
+    let relay = RelayMsg {
+        msg_type: MsgType::RelayForw,
+        hop_count: 0,
+        link_addr: "fd00:2::1".parse().unwrap(),
+        peer_addr: "fe80::1".parse().unwrap(),
+        option: vec![
+            Dhcpv6Option::InterfaceId(b"port3".to_vec()),
+            Dhcpv6Option::RelayMsg(req.encode().unwrap()),
+        ],
+    };
+    let msg = Message::decode(&relay.encode().unwrap()).unwrap();
+
+    let reply = server.handle(&msg).unwrap();
+    let relays = reply.relays();
+    assert_eq!(relays.len(), 1);
+    assert_eq!(relays[0].msg_type, MsgType::RelayRepl);
+    assert_eq!(relays[0].peer_addr, relay.peer_addr);
+    assert!(relays[0].has_option(OPTION_INTERFACE_ID));
+    assert_eq!(
+        reply.client_msg().find_one_option(OPTION_DOMAIN_LIST),
+        Some(&Dhcpv6Option::DomainList(vec!["example.com".into()]))
+    );
+}
+
+#[test]
+fn test_stateless_client() {
+    let server = test_responder();
+    let mut client = StatelessClient::new(test_duid(2), vec![56]);
+    let now = Instant::now();
+
+    let out = client.start(now);
+    assert_eq!(out.transmit, None);
+    let delay = out.deadline.unwrap() - now;
+    assert!(delay <= Duration::from_secs(params::INF_MAX_DELAY as u64));
+
+    let now = out.deadline.unwrap();
+    let out = client.handle_timeout(now);
+    let req = out.transmit.unwrap().msg;
+    assert_eq!(req.msg_type, MsgType::InformationRequest);
+    assert_eq!(
+        req.find_one_option(OPTION_ORO),
+        Some(&Dhcpv6Option::Oro(vec![
+            OPTION_DNS_SERVERS,
+            OPTION_DOMAIN_LIST,
+            OPTION_INFORMATION_REFRESH_TIME,
+            56
+        ]))
+    );
+
+    // No answer, so the request goes out again
+    let first = req;
+    let now = out.deadline.unwrap();
+    let out = client.handle_timeout(now);
+    let req = out.transmit.unwrap().msg;
+    assert_eq!(req.tx_id, first.tx_id);
+    assert!(out.deadline.unwrap() > now);
+
+    // A Reply to some other exchange is ignored
+    let mut reply = server.handle_client_msg(&req).unwrap();
+    reply.tx_id ^= 1;
+    assert_eq!(client.handle_msg(now, &reply).transmit, None);
+    assert_eq!(client.information(), None);
+
+    // So is one from a server that failed to answer
+    let mut reply = server.handle_client_msg(&req).unwrap();
+    reply
+        .options
+        .push(Dhcpv6Option::StatusCode(StatusCodeOption {
+            code: StatusCode::UnspecFail,
+            msg: Vec::new(),
+        }));
+    let out = client.handle_msg(now, &reply);
+    assert_eq!(out.transmit, None);
+    assert_eq!(out.deadline, client.poll_timeout());
+    assert_eq!(client.information(), None);
+
+    let reply = server.handle_client_msg(&req).unwrap();
+    let out = client.handle_msg(now, &reply);
+    let info = client.information().unwrap();
+    assert_eq!(info.server_id, test_duid(1));
+    assert_eq!(
+        info.dns_servers,
+        vec!["fd00::53".parse::<Ipv6Addr>().unwrap()]
+    );
+    assert_eq!(info.domain_list, vec!["example.com".to_string()]);
+    assert_eq!(info.options, vec![server.options()[2].clone()]);
+    assert_eq!(info.refresh_time, params::IRT_DEFAULT);
+    assert_eq!(
+        out.deadline,
+        Some(now + Duration::from_secs(params::IRT_DEFAULT as u64))
+    );
+
+    // Once the refresh time passes, the exchange starts over.  Only the
+    // first request is delayed.
+    let now = out.deadline.unwrap();
+    let out = client.handle_timeout(now);
+    assert_eq!(
+        out.transmit.unwrap().msg.msg_type,
+        MsgType::InformationRequest
+    );
+}
+
+#[test]
+fn test_stateless_client_refresh_time() {
+    let mut client = StatelessClient::new(test_duid(2), Vec::new());
+    let mut now = Instant::now();
+    let mut exchange = |client: &mut StatelessClient, refresh: Option<u32>| {
+        now = client.start(now).deadline.unwrap();
+        let req = client.handle_timeout(now).transmit.unwrap().msg;
+        let mut reply = ClientMsg::new(MsgType::Reply, Some(req.tx_id));
+        reply.options.push(Dhcpv6Option::ServerId(test_duid(1)));
+        reply.options.push(Dhcpv6Option::ClientId(test_duid(2)));
+        if let Some(t) = refresh {
+            reply.options.push(Dhcpv6Option::InformationRefreshTime(t));
+        }
+        client.handle_msg(now, &reply);
+        client.information().unwrap().refresh_time
+    };
+
+    assert_eq!(exchange(&mut client, None), params::IRT_DEFAULT);
+    assert_eq!(exchange(&mut client, Some(3600)), 3600);
+    assert_eq!(exchange(&mut client, Some(5)), params::IRT_MINIMUM);
+    assert_eq!(exchange(&mut client, Some(INFINITY)), INFINITY);
+    assert_eq!(client.information().unwrap().refresh_at(), None);
+    assert_eq!(client.poll_timeout(), None);
+}
+
+#[test]
+fn test_stateless_client_seeded() {
+    let t0 = Instant::now();
+    let run = |seed: u64| {
+        let rng = StdRng::seed_from_u64(seed);
+        let mut client = StatelessClient::with_rng(test_duid(2), Vec::new(), rng);
+        let deadline = client.start(t0).deadline.unwrap();
+        let out = client.handle_timeout(deadline);
+        (deadline, out.transmit.unwrap().msg.tx_id, out.deadline)
+    };
+    assert_eq!(run(1), run(1));
+    assert_ne!(run(1), run(2));
+}
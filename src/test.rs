@@ -29,6 +29,43 @@ fn decode_hex(ascii: &str) -> std::result::Result<Vec<u8>, String> {
     Ok(rval)
 }
 
+// Returns a DUID-LL which is unique to the given id, for use as a client or
+// server identifier
+#[cfg(test)]
+pub(crate) fn test_duid(id: u16) -> options::Duid {
+    let [hi, lo] = id.to_be_bytes();
+    options::Duid::Ll(options::DuidLL::new(1, &[0x02, 0x08, 0x20, 0x00, hi, lo]).unwrap())
+}
+
+#[cfg(test)]
+pub(crate) fn addr(s: &str) -> Ipv6Addr {
+    s.parse().unwrap()
+}
+
+// Returns a message from the client identified by test_duid(client), with an
+// empty IA_NA for each IAID.  A message meant for a particular server carries
+// its Server Identifier.
+#[cfg(test)]
+pub(crate) fn test_client_msg(
+    msg_type: MsgType,
+    client: u16,
+    server: Option<&options::Duid>,
+    iaids: &[u32],
+) -> ClientMsg {
+    let mut msg = ClientMsg::new(msg_type, Some(0x4321));
+    msg.options
+        .push(options::Dhcpv6Option::ClientId(test_duid(client)));
+    if let Some(server) = server {
+        msg.options
+            .push(options::Dhcpv6Option::ServerId(server.clone()));
+    }
+    for iaid in iaids {
+        msg.options
+            .push(options::Dhcpv6Option::IaNa(options::IaNaOption::new(*iaid)));
+    }
+    msg
+}
+
 #[test]
 fn test_decode_hex() {
     assert_eq!(decode_hex("9").unwrap(), vec![0x9]);
@@ -282,3 +319,890 @@ fn test_advertise() {
     let encoded = ClientMsg::encode(&decoded).unwrap();
     assert_eq!(encoded, z);
 }
+
+#[test]
+fn test_relay_forward() {
+    let z = decode_hex(
+        "0c00 fd00 aabb ccdd 0024 0000 0000 0000
+         0001 fe80 0000 0000 0000 0208 20ff fe18
+         e7ea 0012 0004 6574 6830 0009 0040 01a3
+         1b8f 0001 000e 0001 0001 27f8 d12f 0208
+         2018 e7ea 0003 000c 0000 0002 0000 0000
+         0000 0000 0006 000c 0007 000c 0017 0018
+         001b 001d 000e 0000 0008 0002 0000",
+    )
+    .unwrap();
+
+    let solicit = decode_hex(
+        "01a3 1b8f 0001 000e 0001 0001 27f8 d12f
+	 0208 2018 e7ea 0003 000c 0000 0002 0000
+	 0000 0000 0000 0006 000c 0007 000c 0017
+	 0018 001b 001d 000e 0000 0008 0002 0000",
+    )
+    .unwrap();
+
+    let expected = RelayMsg {
+        msg_type: MsgType::RelayForw,
+        hop_count: 0,
+        link_addr: "fd00:aabb:ccdd:24::1".parse().unwrap(),
+        peer_addr: "fe80::208:20ff:fe18:e7ea".parse().unwrap(),
+        option: vec![
+            options::Dhcpv6Option::InterfaceId(b"eth0".to_vec()),
+            options::Dhcpv6Option::RelayMsg(solicit.clone()),
+        ],
+    };
+
+    let decoded = RelayMsg::decode(&z).unwrap();
+    assert_eq!(decoded, expected);
+
+    let encoded = RelayMsg::encode(&decoded).unwrap();
+    assert_eq!(encoded, z);
+
+    // A client message is not a relay message, and vice versa
+    assert_eq!(RelayMsg::decode(&solicit), Err(Error::UnknownMsgCode(1)));
+}
+
+#[test]
+fn test_relay_reply() {
+    let z = decode_hex(
+        "0d00 fd00 aabb ccdd 0024 0000 0000 0000
+         0001 fe80 0000 0000 0000 0208 20ff fe18
+         e7ea 0012 0004 6574 6830 0009 0030 0243
+         35e9 0003 0028 0000 0002 0000 0000 0000
+         0000 0005 0018 fd00 aabb ccdd 0024 0000
+         0000 0000 65b0 0000 08ca 0000 0e10",
+    )
+    .unwrap();
+
+    let decoded = RelayMsg::decode(&z).unwrap();
+    assert_eq!(decoded.msg_type, MsgType::RelayRepl);
+    assert_eq!(decoded.hop_count, 0);
+    assert_eq!(
+        decoded.find_one_option(options::OPTION_INTERFACE_ID),
+        Some(&options::Dhcpv6Option::InterfaceId(b"eth0".to_vec()))
+    );
+
+    let inner = match decoded.find_one_option(options::OPTION_RELAY_MSG) {
+        Some(options::Dhcpv6Option::RelayMsg(x)) => ClientMsg::decode(x).unwrap(),
+        _ => panic!("missing relay message option"),
+    };
+    assert_eq!(inner.msg_type, MsgType::Advertise);
+    assert_eq!(inner.tx_id, 0x4335e9);
+    assert!(inner.has_option(options::OPTION_IA_NA));
+
+    let encoded = RelayMsg::encode(&decoded).unwrap();
+    assert_eq!(encoded, z);
+}
+
+#[test]
+fn test_relay_chain() {
+    let z = decode_hex(
+        "0c00 fd00 aabb ccdd 0024 0000 0000 0000
+         0001 fe80 0000 0000 0000 0208 20ff fe18
+         e7ea 0012 0004 6574 6830 0009 0040 01a3
+         1b8f 0001 000e 0001 0001 27f8 d12f 0208
+         2018 e7ea 0003 000c 0000 0002 0000 0000
+         0000 0000 0006 000c 0007 000c 0017 0018
+         001b 001d 000e 0000 0008 0002 0000",
+    )
+    .unwrap();
+
+    let inner = match Message::decode(&z).unwrap() {
+        Message::Relayed(chain) => chain,
+        Message::Client(_) => panic!("relay layer not recognized"),
+    };
+    assert_eq!(inner.relays.len(), 1);
+    assert!(!inner.relays[0].has_option(options::OPTION_RELAY_MSG));
+    assert_eq!(inner.msg.msg_type, MsgType::Solicit);
+    assert_eq!(inner.msg.tx_id, 0xa31b8f);
+    assert_eq!(inner.encode().unwrap(), z);
+
+    // Wrap the chain in a second relay, and make sure both layers survive a
+    // round trip.
+    let outer = RelayMsg {
+        msg_type: MsgType::RelayForw,
+        hop_count: 1,
+        link_addr: Ipv6Addr::UNSPECIFIED,
+        peer_addr: "fd00:aabb:ccdd:24::1".parse().unwrap(),
+        option: vec![options::Dhcpv6Option::InterfaceId(b"uplink".to_vec())],
+    };
+    let mut relays = vec![outer];
+    relays.extend(inner.relays);
+    let mut payload_at = vec![1];
+    payload_at.extend(inner.payload_at);
+    let chain = RelayChain {
+        relays,
+        msg: inner.msg,
+        payload_at,
+    };
+    let msg = Message::Relayed(chain);
+    let encoded = msg.encode().unwrap();
+    let decoded = Message::decode(&encoded).unwrap();
+    assert_eq!(decoded, msg);
+    assert_eq!(decoded.relays().len(), 2);
+    assert_eq!(decoded.client_msg().msg_type, MsgType::Solicit);
+
+    // The reply should retrace the same path, echoing each Interface-ID
+    let reply = decoded.reply(ClientMsg::new(MsgType::Advertise, Some(0xa31b8f)));
+    let relays = reply.relays();
+    assert_eq!(relays.len(), 2);
+    for (fwd, repl) in decoded.relays().iter().zip(relays) {
+        assert_eq!(repl.msg_type, MsgType::RelayRepl);
+        assert_eq!(repl.hop_count, fwd.hop_count);
+        assert_eq!(repl.link_addr, fwd.link_addr);
+        assert_eq!(repl.peer_addr, fwd.peer_addr);
+        assert_eq!(
+            repl.find_one_option(options::OPTION_INTERFACE_ID),
+            fwd.find_one_option(options::OPTION_INTERFACE_ID)
+        );
+    }
+    let reencoded = reply.encode().unwrap();
+    assert_eq!(Message::decode(&reencoded).unwrap(), reply);
+    assert_eq!(reply.client_msg().msg_type, MsgType::Advertise);
+}
+
+#[test]
+fn test_relay_chain_option_order() {
+    // The Relay Message option comes before the Interface-ID here, and must
+    // stay there when the chain is re-encoded.
+    let solicit = ClientMsg::new(MsgType::Solicit, Some(0xa31b8f))
+        .encode()
+        .unwrap();
+    let relay = RelayMsg {
+        msg_type: MsgType::RelayForw,
+        hop_count: 0,
+        link_addr: "fd00:aabb:ccdd:24::1".parse().unwrap(),
+        peer_addr: "fe80::208:20ff:fe18:e7ea".parse().unwrap(),
+        option: vec![
+            options::Dhcpv6Option::RelayMsg(solicit),
+            options::Dhcpv6Option::InterfaceId(b"eth0".to_vec()),
+        ],
+    };
+    let z = relay.encode().unwrap();
+
+    let chain = match Message::decode(&z).unwrap() {
+        Message::Relayed(chain) => chain,
+        Message::Client(_) => panic!("relay layer not recognized"),
+    };
+    assert_eq!(chain.payload_at, vec![0]);
+    assert_eq!(
+        chain.relays[0].option,
+        vec![options::Dhcpv6Option::InterfaceId(b"eth0".to_vec())]
+    );
+    assert_eq!(chain.encode().unwrap(), z);
+
+    // Without a recorded position, the payload goes last
+    let appended = RelayChain {
+        relays: chain.relays,
+        msg: chain.msg,
+        payload_at: Vec::new(),
+    };
+    let decoded = RelayMsg::decode(&appended.encode().unwrap()).unwrap();
+    assert_eq!(u16::from(&decoded.option[1]), options::OPTION_RELAY_MSG);
+}
+
+#[test]
+fn test_relay_hop_limit() {
+    let mut relays = Vec::new();
+    for hop in 0..=params::HOP_COUNT_LIMIT {
+        relays.push(RelayMsg {
+            msg_type: MsgType::RelayForw,
+            hop_count: (params::HOP_COUNT_LIMIT - hop) as u8,
+            link_addr: Ipv6Addr::UNSPECIFIED,
+            peer_addr: Ipv6Addr::LOCALHOST,
+            option: Vec::new(),
+        });
+    }
+    let chain = RelayChain {
+        relays,
+        msg: ClientMsg::new(MsgType::Solicit, Some(1)),
+        payload_at: Vec::new(),
+    };
+    let encoded = chain.encode().unwrap();
+    assert_eq!(
+        Message::decode(&encoded),
+        Err(Error::Other("hop count limit exceeded".to_string()))
+    );
+
+    // Dropping the outermost relay brings the chain within the limit
+    let encoded = RelayChain {
+        relays: chain.relays.into_iter().skip(1).collect(),
+        msg: chain.msg,
+        payload_at: Vec::new(),
+    }
+    .encode()
+    .unwrap();
+    assert!(Message::decode(&encoded).is_ok());
+}
+
+#[test]
+fn test_auth() {
+    let z = decode_hex(
+        "0722 407a 0001 000e 0001 0001 2841 2860
+         0208 20b3 b93e 0002 000e 0001 0001 2841
+         2881 0208 20b3 b93e 000b 001c 0301 0000
+         0000 0000 0000 0101 0001 0203 0405 0607
+         0809 0a0b 0c0d 0e0f 000b 0013 0201 0000
+         0000 0000 0000 ffde adbe ef00 c0ff ee",
+    )
+    .unwrap();
+
+    let client_id = options::Duid::Llt(options::DuidLLT {
+        type_code: 1,
+        hw_type: 1,
+        time: 0x28412860,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+
+    let server_id = options::Duid::Llt(options::DuidLLT {
+        type_code: 1,
+        hw_type: 1,
+        time: 0x28412881,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+
+    let reconf_key = options::AuthOption {
+        protocol: options::AUTH_PROTOCOL_RECONFIGURE_KEY,
+        algorithm: options::AUTH_ALGORITHM_HMAC_MD5,
+        rdm: options::AUTH_RDM_MONOTONIC,
+        replay_detection: 1,
+        info: options::AuthInfo::ReconfKey([
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ]),
+    };
+
+    // The delayed authentication protocol isn't decoded, so its
+    // authentication information should be preserved as is.
+    let delayed = options::AuthOption {
+        protocol: options::AUTH_PROTOCOL_DELAYED,
+        algorithm: options::AUTH_ALGORITHM_HMAC_MD5,
+        rdm: options::AUTH_RDM_MONOTONIC,
+        replay_detection: 0xff,
+        info: options::AuthInfo::Other(vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0xc0, 0xff, 0xee]),
+    };
+
+    let expected = ClientMsg {
+        msg_type: MsgType::Reply,
+        tx_id: 0x22407a,
+        options: vec![
+            options::Dhcpv6Option::ClientId(client_id),
+            options::Dhcpv6Option::ServerId(server_id),
+            options::Dhcpv6Option::Auth(reconf_key),
+            options::Dhcpv6Option::Auth(delayed),
+        ],
+    };
+
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded, expected);
+
+    let encoded = ClientMsg::encode(&decoded).unwrap();
+    assert_eq!(encoded, z);
+}
+
+#[test]
+fn test_reconfigure_auth() {
+    // The digest in this Reconfigure was computed independently, with
+    // Python's hmac module over the packet with its digest zeroed.
+    let signed = decode_hex(
+        "0a00 beef 0002 000e 0001 0001 2841 2881
+         0208 20b3 b93e 0001 000e 0001 0001 2841
+         2860 0208 20b3 b93e 0013 0001 0500 0b00
+         1c03 0100 0102 0304 0506 0708 029c 0bbe
+         c815 86df 28ac 2fa6 5a16 e6b3 d8",
+    )
+    .unwrap();
+    let key = [0xaa; 16];
+    let replay = 0x0102030405060708;
+
+    let client_id = options::Duid::Llt(options::DuidLLT {
+        type_code: 1,
+        hw_type: 1,
+        time: 0x28412860,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+
+    let server_id = options::Duid::Llt(options::DuidLLT {
+        type_code: 1,
+        hw_type: 1,
+        time: 0x28412881,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+
+    // The server delivers the key in a Reply...
+    let mut reply = ClientMsg::new(MsgType::Reply, Some(0x22407a));
+    reply
+        .options
+        .push(options::Dhcpv6Option::ServerId(server_id.clone()));
+    reply.options.push(auth::reconfigure_key_option(&key, 1));
+    let reply = ClientMsg::decode(&reply.encode().unwrap()).unwrap();
+    let mut client_key = auth::ReconfigureKey::from_reply(&reply).unwrap().unwrap();
+    assert_eq!(client_key.key(), &key);
+    assert_eq!(client_key.last_replay(), Some(1));
+
+    // ...and later uses it to sign a Reconfigure
+    let mut reconf = ClientMsg::new(MsgType::Reconfigure, Some(0xbeef));
+    reconf
+        .options
+        .push(options::Dhcpv6Option::ServerId(server_id));
+    reconf
+        .options
+        .push(options::Dhcpv6Option::ClientId(client_id));
+    reconf
+        .options
+        .push(options::Dhcpv6Option::ReconfMsg(u8::from(MsgType::Renew)));
+    auth::sign_reconfigure(&mut reconf, &key, replay).unwrap();
+    assert_eq!(reconf.encode().unwrap(), signed);
+
+    let received = client_key.verify(&signed).unwrap();
+    assert_eq!(received, reconf);
+    assert_eq!(client_key.last_replay(), Some(replay));
+
+    // The same message can't be accepted twice
+    assert_eq!(
+        client_key.verify(&signed),
+        Err(Error::AuthFailed("replayed message".into()))
+    );
+
+    // Any tampering with the message should be detected
+    let mut tampered = received.clone();
+    tampered
+        .options
+        .retain(|o| u16::from(o) != options::OPTION_RECONF_MSG);
+    tampered
+        .options
+        .push(options::Dhcpv6Option::ReconfMsg(u8::from(MsgType::Rebind)));
+    let mut fresh_key = auth::ReconfigureKey::new(key);
+    assert_eq!(
+        fresh_key.verify(&tampered.encode().unwrap()),
+        Err(Error::AuthFailed("HMAC-MD5 digest mismatch".into()))
+    );
+    assert_eq!(fresh_key.verify(&signed), Ok(received));
+
+    // A different key should fail to verify the original message
+    let mut wrong_key = auth::ReconfigureKey::new([0xff; 16]);
+    assert_eq!(
+        wrong_key.verify(&signed),
+        Err(Error::AuthFailed("HMAC-MD5 digest mismatch".into()))
+    );
+
+    // This Reconfigure Message option carries a trailing byte, which is
+    // dropped when the message is decoded, so re-encoding the message would
+    // not reproduce the packet.  The digest covers the packet as sent, so it
+    // must still verify.  This digest was also computed with Python's hmac.
+    let padded = decode_hex(
+        "0a00 beef 0002 000e 0001 0001 2841 2881
+         0208 20b3 b93e 0001 000e 0001 0001 2841
+         2860 0208 20b3 b93e 0013 0002 05ff 000b
+         001c 0301 0001 0203 0405 0607 0802 ea1d
+         0b73 df6d 34ca cefe 2af5 9b9a ca47",
+    )
+    .unwrap();
+    assert_ne!(
+        ClientMsg::decode(&padded).unwrap().encode().unwrap(),
+        padded
+    );
+    let mut fresh_key = auth::ReconfigureKey::new(key);
+    assert!(fresh_key.verify(&padded).is_ok());
+}
+
+#[test]
+fn test_prefix_delegation() {
+    let z = decode_hex(
+        "07a0 b1c2 0001 000e 0001 0001 2841 2860
+         0208 20b3 b93e 0002 000e 0001 0001 2841
+         2881 0208 20b3 b93e 0019 0029 0000 0001
+         0000 0708 0000 0b40 001a 0019 0000 0e10
+         0000 1c20 38fd 00aa bbcc dd01 0000 0000
+         0000 0000 00",
+    )
+    .unwrap();
+
+    let client_id = options::Duid::Llt(options::DuidLLT {
+        type_code: 1,
+        hw_type: 1,
+        time: 0x28412860,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+
+    let server_id = options::Duid::Llt(options::DuidLLT {
+        type_code: 1,
+        hw_type: 1,
+        time: 0x28412881,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+
+    let prefix = options::IaPrefixOption {
+        preferred_lifetime: 3600,
+        valid_lifetime: 7200,
+        prefix_len: 56,
+        prefix: "fd00:aabb:ccdd:100::".parse().unwrap(),
+        options: Vec::new(),
+    };
+    let ia_pd = options::IaPdOption {
+        iaid: 1,
+        t1: 1800,
+        t2: 2880,
+        options: vec![options::Dhcpv6Option::IaPrefix(prefix)],
+    };
+
+    let expected = ClientMsg {
+        msg_type: MsgType::Reply,
+        tx_id: 0xa0b1c2,
+        options: vec![
+            options::Dhcpv6Option::ClientId(client_id),
+            options::Dhcpv6Option::ServerId(server_id),
+            options::Dhcpv6Option::IaPd(ia_pd.clone()),
+        ],
+    };
+
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded, expected);
+    assert_eq!(
+        decoded.find_one_option(options::OPTION_IA_PD),
+        Some(&options::Dhcpv6Option::IaPd(ia_pd))
+    );
+
+    let encoded = ClientMsg::encode(&decoded).unwrap();
+    assert_eq!(encoded, z);
+}
+
+#[test]
+fn test_information_refresh_time() {
+    let z = decode_hex(
+        "07a0 b1c2 0017 0010 fd00 0000 0000 0000
+         0000 0000 0000 0053 0020 0004 0000 0e10",
+    )
+    .unwrap();
+
+    let expected = ClientMsg {
+        msg_type: MsgType::Reply,
+        tx_id: 0xa0b1c2,
+        options: vec![
+            options::Dhcpv6Option::DnsServers(vec!["fd00::53".parse().unwrap()]),
+            options::Dhcpv6Option::InformationRefreshTime(3600),
+        ],
+    };
+
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded, expected);
+
+    let encoded = ClientMsg::encode(&decoded).unwrap();
+    assert_eq!(encoded, z);
+}
+
+#[test]
+fn test_status_codes() {
+    let z = decode_hex(
+        "07a0 b1c2 0019 001f 0000 0001 0000 0000
+         0000 0000 000d 000f 0006 6e6f 2070 7265
+         6669 7865 7321 2100 0d00 0403 e7ff 41",
+    )
+    .unwrap();
+
+    let no_prefix = options::StatusCodeOption {
+        code: StatusCode::NoPrefixAvail,
+        msg: b"no prefixes!!".to_vec(),
+    };
+    let ia_pd = options::IaPdOption {
+        iaid: 1,
+        t1: 0,
+        t2: 0,
+        options: vec![options::Dhcpv6Option::StatusCode(no_prefix)],
+    };
+
+    // An unassigned code, with a message that isn't valid UTF-8
+    let unknown = options::StatusCodeOption {
+        code: StatusCode::Unknown(999),
+        msg: vec![0xff, 0x41],
+    };
+
+    let expected = ClientMsg {
+        msg_type: MsgType::Reply,
+        tx_id: 0xa0b1c2,
+        options: vec![
+            options::Dhcpv6Option::IaPd(ia_pd),
+            options::Dhcpv6Option::StatusCode(unknown),
+        ],
+    };
+
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded, expected);
+    assert_eq!(
+        format!("{:?}", decoded.options[1]),
+        "StatusCode(code: Unknown(999)  msg: \u{fffd}A)"
+    );
+
+    let encoded = ClientMsg::encode(&decoded).unwrap();
+    assert_eq!(encoded, z);
+
+    for code in 0..=u16::from(StatusCode::ExcessiveTimeSkew) + 1 {
+        assert_eq!(u16::from(StatusCode::from_code(code)), code);
+    }
+
+    // TryFrom only accepts registered codes
+    assert_eq!(StatusCode::try_from(6), Ok(StatusCode::NoPrefixAvail));
+    assert_eq!(StatusCode::try_from(999), Err(()));
+
+    // A registered code is the same status however it was built
+    assert_eq!(StatusCode::Unknown(6), StatusCode::NoPrefixAvail);
+    assert_ne!(StatusCode::Unknown(6), StatusCode::Unknown(7));
+}
+
+#[test]
+fn test_msg_types() {
+    // A Leasequery, carrying only a Client Identifier
+    let z = decode_hex(
+        "0e12 3456 0001 000e 0001 0001 2841 2860
+         0208 20b3 b93e",
+    )
+    .unwrap();
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded.msg_type, MsgType::LeaseQuery);
+    assert_eq!(format!("{}", decoded), "leaseQuery  txid: 0x123456");
+    assert_eq!(ClientMsg::encode(&decoded).unwrap(), z);
+    assert!(retransmit_params(MsgType::LeaseQuery).is_some());
+
+    // An unassigned message type should survive a round trip
+    let z = decode_hex("c8ab cdef 0008 0002 0000").unwrap();
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded.msg_type, MsgType::Unknown(200));
+    assert_eq!(format!("{}", decoded), "unknown(200)  txid: 0xabcdef");
+    assert_eq!(ClientMsg::encode(&decoded).unwrap(), z);
+    assert!(retransmit_params(MsgType::Unknown(200)).is_none());
+
+    for code in 0..=u8::MAX {
+        assert_eq!(u8::from(MsgType::from_code(code)), code);
+    }
+
+    // TryFrom only accepts registered types
+    assert_eq!(MsgType::try_from(14), Ok(MsgType::LeaseQuery));
+    assert_eq!(MsgType::try_from(0), Err(()));
+    assert_eq!(MsgType::try_from(200), Err(()));
+
+    // A registered type is the same type however it was built
+    assert_eq!(MsgType::Unknown(1), MsgType::Solicit);
+    assert_ne!(MsgType::Unknown(200), MsgType::Unknown(201));
+}
+
+#[test]
+fn test_duid_types() {
+    // A Solicit from a client using a DUID-UUID, answered by a server with a
+    // DUID of an unassigned type.
+    let z = decode_hex(
+        "01a3 1b8f 0001 0012 0004 6ba7 b810 9dad
+         11d1 80b4 00c0 4fd4 30c8 0002 0005 1234
+         6162 63",
+    )
+    .unwrap();
+
+    let client_id = options::Duid::Uuid([
+        0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30,
+        0xc8,
+    ]);
+    let server_id = options::Duid::Unknown {
+        type_code: 0x1234,
+        data: b"abc".to_vec(),
+    };
+
+    let expected = ClientMsg {
+        msg_type: MsgType::Solicit,
+        tx_id: 0xa31b8f,
+        options: vec![
+            options::Dhcpv6Option::ClientId(client_id),
+            options::Dhcpv6Option::ServerId(server_id),
+        ],
+    };
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(decoded, expected);
+    assert_eq!(ClientMsg::encode(&decoded).unwrap(), z);
+
+    // A DUID-LL
+    let z = decode_hex("01a3 1b8f 0001 000a 0003 0001 0208 20b3 b93e").unwrap();
+    let client_id = options::Duid::Ll(options::DuidLL {
+        type_code: 3,
+        hw_type: 1,
+        link_layer: vec![0x02, 0x08, 0x20, 0xb3, 0xb9, 0x3e],
+    });
+    let decoded = ClientMsg::decode(&z).unwrap();
+    assert_eq!(
+        decoded.options,
+        vec![options::Dhcpv6Option::ClientId(client_id)]
+    );
+    assert_eq!(ClientMsg::encode(&decoded).unwrap(), z);
+
+    // ... and again with a DUID-UUID with a truncated UUID.  A malformed DUID
+    // of a known type is an error, not an opaque DUID.
+    let z = decode_hex(
+        "01a3 1b8f 0001 000a 0003 0001 0208 20b3
+         b93e 0002 0006 0004 6ba7 b810",
+    )
+    .unwrap();
+    assert_eq!(
+        ClientMsg::decode(&z),
+        Err(Error::BadOption("DUID-UUID must be 16 bytes".into()))
+    );
+
+    // A truncated DUID-LLT
+    assert_eq!(
+        options::Duid::from_bytes(&[0x00, 0x01, 0x00, 0x01, 0x28, 0x41]),
+        Err(Error::TooShort)
+    );
+
+    // A DUID may be no more than 128 bytes, whatever its type
+    let mut long = vec![0x12, 0x34];
+    long.extend_from_slice(&[0xab; 128]);
+    assert!(options::Duid::from_bytes(&long).is_ok());
+    long.push(0xab);
+    assert_eq!(
+        options::Duid::from_bytes(&long),
+        Err(Error::BadOption("DUID too long".into()))
+    );
+}
+
+#[test]
+fn test_borrowed_decode() {
+    let z = decode_hex(
+        "05db fac2 0001 000e 0001 0001 2841 2860
+	 0208 20b3 b93e 0002 000e 0001 0001 2841
+	 2881 0208 20b3 b93e 0003 0044 0000 0003
+	 0000 0000 0000 0000 0005 0018 fd00 aabb
+	 ccdd 0024 0000 0000 0000 7000 0000 004b
+	 0000 0078 0005 0018 fd00 aabb ccdd 0024
+	 0000 0000 0000 658e 0000 004b 0000 0078
+	 0006 000c 0007 000c 0017 0018 001b 001d
+	 0008 0002 0000",
+    )
+    .unwrap();
+
+    let msg = view::ClientMsgRef::decode(&z).unwrap();
+    assert_eq!(msg.msg_type, MsgType::Renew);
+    assert_eq!(msg.tx_id, 0xdbfac2);
+
+    let codes: Vec<u16> = msg.options().map(|o| o.code).collect();
+    assert_eq!(
+        codes,
+        vec![
+            options::OPTION_CLIENTID,
+            options::OPTION_SERVERID,
+            options::OPTION_IA_NA,
+            options::OPTION_ORO,
+            options::OPTION_ELAPSED_TIME
+        ]
+    );
+
+    // The option payloads should point into the original buffer
+    let ia_na = msg.find_one_option(options::OPTION_IA_NA).unwrap();
+    assert_eq!(ia_na.data.as_ptr(), z[44..].as_ptr());
+    let addrs: Vec<Ipv6Addr> = ia_na
+        .nested()
+        .unwrap()
+        .map(|o| match o.to_option().unwrap() {
+            options::Dhcpv6Option::IaAddr(addr) => addr.addr,
+            x => panic!("unexpected option: {:?}", x),
+        })
+        .collect();
+    let expected: Vec<Ipv6Addr> = vec![
+        "fd00:aabb:ccdd:24::7000".parse().unwrap(),
+        "fd00:aabb:ccdd:24::658e".parse().unwrap(),
+    ];
+    assert_eq!(addrs, expected);
+    assert!(msg
+        .find_one_option(options::OPTION_ORO)
+        .unwrap()
+        .nested()
+        .is_err());
+
+    assert_eq!(msg.to_client_msg().unwrap(), ClientMsg::decode(&z).unwrap());
+
+    // An option whose length runs past the end of the packet is caught up front
+    assert_eq!(
+        view::ClientMsgRef::decode(&z[..z.len() - 1]),
+        Err(Error::TooShort)
+    );
+}
+
+#[test]
+fn test_encode_in_place() {
+    let z = decode_hex(
+        "0243 35e9 0003 0028 0000 0002 0000 0000
+	 0000 0000 0005 0018 fd00 aabb ccdd 0024
+	 0000 0000 0000 6b10 0000 08ca 0000 0e10
+	 0001 000e 0001 0001 27f8 d12f 0208 2018
+	 e7ea 0002 000e 0001 0001 2841 2881 0208
+	 20b3 b93e 0017 0020 fd00 aabb ccdd 0024
+	 0000 0000 0000 0080 2001 4860 4860 0000
+	 0000 0000 0000 8888 0018 0015 0365 6e67
+	 0005 6f78 6964 6508 636f 6d70 7574 6572
+	 00",
+    )
+    .unwrap();
+
+    let msg = ClientMsg::decode(&z).unwrap();
+    assert_eq!(msg.encoded_len().unwrap(), z.len());
+
+    let mut out = vec![0xffu8; z.len()];
+    assert_eq!(msg.encode_into(&mut out).unwrap(), z.len());
+    assert_eq!(out, z);
+
+    let mut short = vec![0u8; z.len() - 1];
+    assert_eq!(
+        msg.encode_into(&mut short),
+        Err(Error::BufferTooSmall(z.len()))
+    );
+
+    let mut appended = vec![0xaa, 0xbb];
+    msg.encode_append(&mut appended).unwrap();
+    assert_eq!(&appended[..2], &[0xaa, 0xbb]);
+    assert_eq!(&appended[2..], &z[..]);
+
+    // The nested relay message lengths are backfilled, too
+    let relay = RelayMsg {
+        msg_type: MsgType::RelayRepl,
+        hop_count: 0,
+        link_addr: Ipv6Addr::UNSPECIFIED,
+        peer_addr: "fe80::208:20ff:fe18:e7ea".parse().unwrap(),
+        option: vec![options::Dhcpv6Option::InterfaceId(b"eth0".to_vec())],
+    };
+    let chain = Message::Relayed(RelayChain {
+        relays: vec![relay.clone(), relay],
+        msg,
+        payload_at: vec![1, 1],
+    });
+    let len = chain.encoded_len().unwrap();
+    let mut out = vec![0u8; 2048];
+    assert_eq!(chain.encode_into(&mut out).unwrap(), len);
+    assert_eq!(&out[..len], &chain.encode().unwrap()[..]);
+    assert_eq!(Message::decode(&out[..len]).unwrap(), chain);
+}
+
+#[test]
+fn test_strict_decode() {
+    use options::{Dhcpv6Option, IaAddrOption, IaNaOption, IaPdOption, IaPrefixOption};
+
+    let addr = |a: &str, preferred, valid| {
+        let mut addr = IaAddrOption::new(a.parse().unwrap());
+        addr.preferred_lifetime = preferred;
+        addr.valid_lifetime = valid;
+        Dhcpv6Option::IaAddr(addr)
+    };
+
+    // T1 > T2, so the whole IA is ignored
+    let mut bad_timers = IaNaOption::new(1);
+    bad_timers.t1 = 2000;
+    bad_timers.t2 = 1000;
+    bad_timers.options.push(addr("fd00::1", 3000, 4000));
+
+    // Timers left to the client are fine, but one address isn't
+    let mut good = IaNaOption::new(2);
+    good.t2 = 1000;
+    good.options.push(addr("fd00::2", 3000, 4000));
+    good.options.push(addr("fd00::3", 5000, 4000));
+
+    let mut pd = IaPdOption::new(3);
+    let mut prefix = IaPrefixOption::new("fd00:1::".parse().unwrap(), 64);
+    prefix.preferred_lifetime = 10;
+    prefix.valid_lifetime = 5;
+    pd.options.push(Dhcpv6Option::IaPrefix(prefix));
+
+    let mut msg = ClientMsg::new(MsgType::Reply, Some(1));
+    msg.options.push(Dhcpv6Option::IaNa(bad_timers.clone()));
+    msg.options.push(Dhcpv6Option::IaNa(good.clone()));
+    msg.options.push(Dhcpv6Option::IaPd(pd));
+    let encoded = msg.encode().unwrap();
+
+    // The lenient decode keeps everything
+    assert_eq!(ClientMsg::decode(&encoded).unwrap(), msg);
+
+    let strict = ClientMsg::decode_strict(&encoded).unwrap();
+    assert!(!bad_timers.is_valid());
+    good.options.pop();
+    assert_eq!(
+        strict.options,
+        vec![
+            Dhcpv6Option::IaNa(good.clone()),
+            Dhcpv6Option::IaPd(IaPdOption::new(3)),
+        ]
+    );
+    assert_eq!(good.addrs().count(), 1);
+}
+
+#[test]
+fn test_effective_timers() {
+    use options::{
+        default_timers, lifetime_end, IaAddrOption, IaNaOption, INFINITY, MIN_CLIENT_T1,
+    };
+    use std::time::{Duration, Instant};
+
+    assert_eq!(default_timers(1000, 2000), (500, 800));
+    assert_eq!(default_timers(INFINITY, INFINITY), (INFINITY, INFINITY));
+
+    // Deprecated addresses fall back to the valid lifetime, and short
+    // lifetimes don't make the client renew constantly
+    assert_eq!(default_timers(0, 2000), (1000, 1600));
+    assert_eq!(default_timers(0, INFINITY), (INFINITY, INFINITY));
+    assert_eq!(default_timers(100, 200), (MIN_CLIENT_T1, 80));
+
+    // However short the lifetime, both timers stay below it
+    assert_eq!(default_timers(50, 200), (40, 40));
+    assert_eq!(default_timers(0, 1), (0, 0));
+    for lifetime in 1..200 {
+        let (t1, t2) = default_timers(lifetime, lifetime);
+        assert!(t1 <= t2 && t2 < lifetime);
+    }
+
+    let now = Instant::now();
+    assert_eq!(lifetime_end(&now, 10), Some(now + Duration::from_secs(10)));
+    assert_eq!(lifetime_end(&now, INFINITY), None);
+
+    let mut ia = IaNaOption::new(1);
+    assert_eq!(ia.effective_timers(), (0, 0));
+    let mut short = IaAddrOption::new("fd00::1".parse().unwrap());
+    short.preferred_lifetime = 1000;
+    short.valid_lifetime = 2000;
+    let mut long = IaAddrOption::new("fd00::2".parse().unwrap());
+    long.preferred_lifetime = INFINITY;
+    long.valid_lifetime = INFINITY;
+    ia.options.push(options::Dhcpv6Option::IaAddr(long.clone()));
+    assert_eq!(ia.effective_timers(), (INFINITY, INFINITY));
+    assert_eq!(ia.renew_at(&now), None);
+    ia.options
+        .push(options::Dhcpv6Option::IaAddr(short.clone()));
+    assert_eq!(ia.effective_timers(), (500, 800));
+    assert_eq!(ia.rebind_at(&now), Some(now + Duration::from_secs(800)));
+
+    // Timers set by the server are used as they are
+    ia.t1 = 100;
+    ia.t2 = 200;
+    assert_eq!(ia.effective_timers(), (100, 200));
+
+    // If only one is set, the client chooses just the other
+    ia.t2 = 0;
+    assert_eq!(ia.effective_timers(), (100, 800));
+    ia.t1 = 0;
+    ia.t2 = 300;
+    assert_eq!(ia.effective_timers(), (300, 300));
+    assert_eq!(options::fill_timers(0, 0, (500, 800)), (500, 800));
+
+    // An IA holding only deprecated addresses is still renewed in due course
+    let mut deprecated = IaNaOption::new(2);
+    let mut addr = IaAddrOption::new("fd00::3".parse().unwrap());
+    addr.preferred_lifetime = 0;
+    addr.valid_lifetime = 2000;
+    deprecated.options.push(options::Dhcpv6Option::IaAddr(addr));
+    assert_eq!(deprecated.effective_timers(), (1000, 1600));
+    assert_eq!(
+        deprecated.renew_at(&now),
+        Some(now + Duration::from_secs(1000))
+    );
+
+    assert_eq!(
+        short.preferred_until(&now),
+        Some(now + Duration::from_secs(1000))
+    );
+    assert_eq!(
+        short.valid_until(&now),
+        Some(now + Duration::from_secs(2000))
+    );
+    assert_eq!(long.valid_until(&now), None);
+}
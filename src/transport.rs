@@ -0,0 +1,248 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Blocking UDP sockets for sending and receiving DHCPv6 messages.
+//!
+//! Clients listen on port 546 and send to port 547; servers and relays do the
+//! reverse.  Both ports may be overridden, which is mostly useful for testing
+//! without privileges.
+//!
+//! Messages a client sends directly to its servers have a link-local source
+//! address, so the interface they arrived on is recovered from the scope of
+//! that address.  A message from a relay agent arrives from a global address
+//! and is reported with an interface index of 0.
+
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6, UdpSocket};
+use std::time::Duration;
+
+use crate::*;
+
+// Large enough for any DHCPv6 message that fits in an unfragmented datagram
+// on any link we're likely to see.
+pub(crate) const RECV_BUFFER_SIZE: usize = 65536;
+
+/// A message read from a socket, along with where it came from
+#[derive(Debug, PartialEq)]
+pub struct Received<M> {
+    pub msg: M,
+    pub src: SocketAddrV6,
+    /// The index of the interface the message arrived on, or 0 if it
+    /// couldn't be determined
+    pub ifindex: u32,
+}
+
+pub(crate) fn io_error(e: io::Error) -> Error {
+    Error::Other(format!("socket error: {}", e))
+}
+
+pub(crate) fn bind(port: u16) -> Result<UdpSocket> {
+    UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0)).map_err(io_error)
+}
+
+// Link-local and multicast destinations are meaningless without an interface,
+// so fill one in if the caller didn't.
+pub(crate) fn scoped(dest: SocketAddrV6, ifindex: u32) -> SocketAddrV6 {
+    let ip = dest.ip();
+    let needs_scope = ip.is_multicast() || (ip.segments()[0] & 0xffc0) == 0xfe80;
+    if needs_scope && dest.scope_id() == 0 {
+        SocketAddrV6::new(*ip, dest.port(), dest.flowinfo(), ifindex)
+    } else {
+        dest
+    }
+}
+
+// Reads a single datagram and decodes it with the given function.  The
+// interface is taken from the scope of the source address, falling back on
+// the socket's own interface if it has only one.
+fn recv<M>(
+    socket: &UdpSocket,
+    default_ifindex: u32,
+    decode: impl FnOnce(&[u8]) -> Result<M>,
+) -> Result<Received<M>> {
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    let (len, src) = socket.recv_from(&mut buf).map_err(io_error)?;
+    let src = match src {
+        SocketAddr::V6(src) => src,
+        SocketAddr::V4(src) => return Err(Error::Other(format!("unexpected source: {}", src))),
+    };
+    let msg = decode(&buf[..len])?;
+    let ifindex = match src.scope_id() {
+        0 => default_ifindex,
+        scope => scope,
+    };
+    Ok(Received { msg, src, ifindex })
+}
+
+/// A socket on which a client exchanges messages with its servers
+#[derive(Debug)]
+pub struct ClientSocket {
+    socket: UdpSocket,
+    ifindex: u32,
+    server_port: u16,
+}
+
+impl ClientSocket {
+    /// Binds the client port, for sending on the given interface
+    pub fn new(ifindex: u32) -> Result<Self> {
+        ClientSocket::with_ports(ifindex, params::CLIENT_PORT, params::SERVER_PORT)
+    }
+
+    /// Binds `client_port` rather than the standard client port, and sends
+    /// to servers on `server_port`.  A client port of 0 binds an ephemeral
+    /// port.
+    pub fn with_ports(ifindex: u32, client_port: u16, server_port: u16) -> Result<Self> {
+        Ok(ClientSocket {
+            socket: bind(client_port)?,
+            ifindex,
+            server_port,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(io_error)
+    }
+
+    pub fn ifindex(&self) -> u32 {
+        self.ifindex
+    }
+
+    /// Sets how long `recv` blocks before giving up.  `None` blocks forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(timeout).map_err(io_error)
+    }
+
+    /// Sends a message to All_DHCP_Relay_Agents_and_Servers
+    pub fn send(&self, msg: &ClientMsg) -> Result<()> {
+        self.send_to(msg, params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS)
+    }
+
+    /// Sends a message to the server port of the given address.  This is
+    /// used when a server has told the client it may unicast.
+    pub fn send_to(&self, msg: &ClientMsg, dest: Ipv6Addr) -> Result<()> {
+        let dest = scoped(
+            SocketAddrV6::new(dest, self.server_port, 0, 0),
+            self.ifindex,
+        );
+        self.socket
+            .send_to(&msg.encode()?, dest)
+            .map_err(io_error)
+            .map(|_| ())
+    }
+
+    /// Blocks until a message arrives, returning it decoded
+    pub fn recv(&self) -> Result<Received<ClientMsg>> {
+        recv(&self.socket, self.ifindex, ClientMsg::decode)
+    }
+}
+
+/// A socket on which a server or relay exchanges messages with clients and
+/// other relays
+#[derive(Debug)]
+pub struct ServerSocket {
+    socket: UdpSocket,
+    interfaces: Vec<u32>,
+}
+
+impl ServerSocket {
+    /// Binds the server port and joins All_DHCP_Relay_Agents_and_Servers on
+    /// each of the given interfaces
+    pub fn new(interfaces: &[u32]) -> Result<Self> {
+        ServerSocket::with_port(interfaces, params::SERVER_PORT)
+    }
+
+    /// Binds `port` rather than the standard server port.  A port of 0 binds
+    /// an ephemeral port.
+    pub fn with_port(interfaces: &[u32], port: u16) -> Result<Self> {
+        let socket = bind(port)?;
+        for ifindex in interfaces {
+            socket
+                .join_multicast_v6(&params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS, *ifindex)
+                .map_err(io_error)?;
+        }
+        Ok(ServerSocket {
+            socket,
+            interfaces: interfaces.to_vec(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(io_error)
+    }
+
+    pub fn interfaces(&self) -> &[u32] {
+        &self.interfaces
+    }
+
+    /// Sets how long `recv` blocks before giving up.  `None` blocks forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(timeout).map_err(io_error)
+    }
+
+    /// Sends a message to the given destination, scoped to `ifindex` if the
+    /// destination is link-local or multicast
+    pub fn send_to(&self, msg: &Message, dest: SocketAddrV6, ifindex: u32) -> Result<()> {
+        self.socket
+            .send_to(&msg.encode()?, scoped(dest, ifindex))
+            .map_err(io_error)
+            .map(|_| ())
+    }
+
+    /// Sends a response back to wherever a message came from: directly to the
+    /// client, or to the relay that forwarded it.
+    pub fn reply(&self, to: &Received<Message>, msg: &Message) -> Result<()> {
+        self.send_to(msg, to.src, to.ifindex)
+    }
+
+    /// Blocks until a message arrives, returning it decoded, with any relay
+    /// layers intact
+    pub fn recv(&self) -> Result<Received<Message>> {
+        let default = match self.interfaces.as_slice() {
+            [ifindex] => *ifindex,
+            _ => 0,
+        };
+        recv(&self.socket, default, Message::decode)
+    }
+}
+
+#[test]
+fn test_transport_loopback() {
+    let timeout = Some(Duration::from_secs(5));
+    let server = ServerSocket::with_port(&[], 0).unwrap();
+    server.set_read_timeout(timeout).unwrap();
+    let server_port = server.local_addr().unwrap().port();
+
+    let client = ClientSocket::with_ports(0, 0, server_port).unwrap();
+    client.set_read_timeout(timeout).unwrap();
+    let client_port = client.local_addr().unwrap().port();
+
+    let mut solicit = ClientMsg::new(MsgType::Solicit, Some(0x010203));
+    solicit.options.push(options::Dhcpv6Option::ElapsedTime(0));
+    client.send_to(&solicit, Ipv6Addr::LOCALHOST).unwrap();
+
+    let rx = server.recv().unwrap();
+    assert_eq!(rx.msg, Message::Client(solicit));
+    assert_eq!(*rx.src.ip(), Ipv6Addr::LOCALHOST);
+    assert_eq!(rx.src.port(), client_port);
+    assert_eq!(rx.ifindex, 0);
+
+    let advertise = ClientMsg::new(MsgType::Advertise, Some(0x010203));
+    server
+        .reply(&rx, &Message::Client(advertise.clone()))
+        .unwrap();
+    let rx = client.recv().unwrap();
+    assert_eq!(rx.msg, advertise);
+    assert_eq!(rx.src.port(), server_port);
+}
+
+#[test]
+fn test_transport_scope() {
+    let ll = SocketAddrV6::new("fe80::1".parse().unwrap(), 547, 0, 0);
+    assert_eq!(scoped(ll, 3).scope_id(), 3);
+    let mcast = SocketAddrV6::new(params::ALL_DHCP_RELAY_AGENTS_AND_SERVERS, 547, 0, 0);
+    assert_eq!(scoped(mcast, 3).scope_id(), 3);
+    let global = SocketAddrV6::new("fd00::1".parse().unwrap(), 547, 0, 0);
+    assert_eq!(scoped(global, 3).scope_id(), 0);
+    // An explicit scope is left alone
+    let ll = SocketAddrV6::new("fe80::1".parse().unwrap(), 547, 0, 7);
+    assert_eq!(scoped(ll, 3).scope_id(), 7);
+}
@@ -0,0 +1,164 @@
+// Copyright 2021 Oxide Computer Company
+
+//! Borrowed views of DHCPv6 messages.
+//!
+//! These types refer directly to the packet buffer rather than copying its
+//! contents, so a message can be inspected without allocating.  Options are
+//! only decoded into their owned form on request.
+
+use crate::buffer::Buffer;
+use crate::options::*;
+use crate::*;
+
+// Returns the size of the fixed fields which precede the nested options within
+// an option of the given type, or None if the type can't contain options.
+fn nested_offset(code: u16) -> Option<usize> {
+    match code {
+        OPTION_IA_NA => Some(12),
+        OPTION_IA_TA => Some(4),
+        OPTION_IAADDR => Some(24),
+        OPTION_IA_PD => Some(12),
+        OPTION_IAPREFIX => Some(25),
+        _ => None,
+    }
+}
+
+// Walks a list of options, verifying that each option fits within the list
+fn validate(mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        let mut buf = Buffer::new_from_slice(data);
+        let _code = buf.get_16()?;
+        let len = buf.get_16()? as usize;
+        buf.get_slice(len)?;
+        data = &data[4 + len..];
+    }
+    Ok(())
+}
+
+/// A single option, borrowed from the packet buffer
+#[derive(Clone, Copy, PartialEq)]
+pub struct OptionRef<'a> {
+    pub code: u16,
+    pub data: &'a [u8],
+}
+
+impl fmt::Debug for OptionRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "code: {}  len: {}", self.code, self.data.len())
+    }
+}
+
+impl<'a> OptionRef<'a> {
+    /// Returns an iterator over the options nested within this one.  This is
+    /// only valid for the IA_NA, IA_TA, IAADDR, IA_PD and IAPREFIX options.
+    pub fn nested(&self) -> Result<OptionIter<'a>> {
+        let offset = nested_offset(self.code).ok_or_else(|| {
+            Error::BadOption(format!("option {} has no nested options", self.code))
+        })?;
+        if self.data.len() < offset {
+            return Err(Error::TooShort);
+        }
+        let data = &self.data[offset..];
+        validate(data)?;
+        Ok(OptionIter { data })
+    }
+
+    /// Find the first nested option of the given type
+    pub fn find_nested(&self, opt_type: u16) -> Result<Option<OptionRef<'a>>> {
+        Ok(self.nested()?.find(|o| o.code == opt_type))
+    }
+
+    /// Decodes the option into its owned form
+    pub fn to_option(&self) -> Result<Dhcpv6Option> {
+        parse_option_data(self.code, self.data)
+    }
+}
+
+/// An iterator over a list of options borrowed from the packet buffer
+#[derive(Clone)]
+pub struct OptionIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for OptionIter<'a> {
+    type Item = OptionRef<'a>;
+
+    fn next(&mut self) -> Option<OptionRef<'a>> {
+        // The list was validated when the iterator was created, so running
+        // out of data here just means we've reached the end.
+        let mut buf = Buffer::new_from_slice(self.data);
+        let code = buf.get_16().ok()?;
+        let len = buf.get_16().ok()? as usize;
+        let data = buf.get_slice(len).ok()?;
+        self.data = &self.data[4 + len..];
+        Some(OptionRef { code, data })
+    }
+}
+
+/// A client message whose options are borrowed from the packet buffer
+#[derive(Clone, Copy, PartialEq)]
+pub struct ClientMsgRef<'a> {
+    pub msg_type: MsgType,
+    pub tx_id: u32,
+    options: &'a [u8],
+}
+
+impl fmt::Debug for ClientMsgRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "type: {:?}  txid: 0x{:x}  options: {:?}",
+            self.msg_type,
+            self.tx_id,
+            self.options().collect::<Vec<OptionRef>>()
+        )
+    }
+}
+
+impl<'a> ClientMsgRef<'a> {
+    // Attempts to parse the header of the message in the provided buffer.
+    // The option list is checked for framing errors, but the options
+    // themselves are not decoded.
+    pub fn decode(buf: &'a [u8]) -> Result<ClientMsgRef<'a>> {
+        let mut buf = Buffer::new_from_slice(buf);
+
+        let msg_type = MsgType::from_code(buf.get_8()?);
+        let tx_id = buf.get_24()?;
+        let left = buf.left();
+        let options = buf.get_slice(left)?;
+        validate(options)?;
+        Ok(ClientMsgRef {
+            msg_type,
+            tx_id,
+            options,
+        })
+    }
+
+    /// Returns an iterator over the message's top-level options
+    pub fn options(&self) -> OptionIter<'a> {
+        OptionIter { data: self.options }
+    }
+
+    /// Find the first option of the given type in the message's option list
+    pub fn find_one_option(&self, opt_type: u16) -> Option<OptionRef<'a>> {
+        self.options().find(|o| o.code == opt_type)
+    }
+
+    /// Returns 'true' iff the message's option list contains an option of the given type
+    pub fn has_option(&self, opt_type: u16) -> bool {
+        self.find_one_option(opt_type).is_some()
+    }
+
+    /// Decodes the message and all of its options into an owned ClientMsg
+    pub fn to_client_msg(&self) -> Result<ClientMsg> {
+        let options = self
+            .options()
+            .map(|o| o.to_option())
+            .collect::<Result<Vec<Dhcpv6Option>>>()?;
+        Ok(ClientMsg {
+            msg_type: self.msg_type,
+            tx_id: self.tx_id,
+            options,
+        })
+    }
+}